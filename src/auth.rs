@@ -0,0 +1,1101 @@
+//! Authenticating that deliveries were sent by the configured provider.
+
+use std::{
+    borrow::Cow,
+    env,
+    io::Read,
+    process::{Command, Stdio},
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use flate2::read::GzDecoder;
+use hmac::{
+    digest::{Digest, MacError},
+    Mac,
+};
+use rocket::{
+    data::{Data, FromData, Outcome, ToByteUnit},
+    http::{HeaderMap, Status},
+    Request,
+};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    env_or,
+    memory::{BodyMemory, Reservation, MAX_TOTAL_BODY_STRING},
+    parse_pairs,
+    tenant::Tenant,
+    MalformedPair,
+};
+
+/// The string for the environment variable containing the secret.
+pub const WEBHOOK_STRING: &str = "WEBHOOK_SECRET";
+/// The string for the environment variable containing a command printing the secret, run in place
+/// of reading [`WEBHOOK_STRING`].
+pub const SECRET_COMMAND_STRING: &str = "WEBHOOK_SECRET_COMMAND";
+/// The string for the environment variable containing the seconds the secret printed by
+/// [`SECRET_COMMAND_STRING`] is reused for before the command is run again.
+pub const SECRET_COMMAND_TTL_STRING: &str = "WEBHOOK_SECRET_COMMAND_TTL_SECS";
+/// The string for the environment variable containing the seconds [`SECRET_COMMAND_STRING`] may
+/// run for before it is killed.
+pub const SECRET_COMMAND_TIMEOUT_STRING: &str = "WEBHOOK_SECRET_COMMAND_TIMEOUT_SECS";
+/// The string for the environment variable containing the path to a file of GitHub secrets that
+/// are being rotated through.
+pub const ROTATION_FILE_STRING: &str = "WEBHOOK_SECRET_ROTATION_FILE";
+/// The string for the environment variable containing the most seconds a client may take to send
+/// the payload.
+pub const MAX_REQUEST_STRING: &str = "WEBHOOK_MAX_REQUEST_SECS";
+/// The string for the environment variable selecting how repeated [`HEADER`]s are handled, either
+/// "first", "strict" or "all".
+pub const DUPLICATE_SIGNATURES_STRING: &str = "WEBHOOK_DUPLICATE_SIGNATURES";
+/// The string for the environment variable containing the template of the string GitHub-style
+/// signatures are made over, such as "{timestamp}.{body}".
+pub const SIGNED_STRING_TEMPLATE_STRING: &str = "WEBHOOK_SIGNED_STRING_TEMPLATE";
+/// The string for the environment variable containing the name of the header "{timestamp}" is
+/// filled in from.
+pub const TIMESTAMP_HEADER_STRING: &str = "WEBHOOK_TIMESTAMP_HEADER";
+/// The number of seconds a templated signature remains valid for after its timestamp.
+pub const TIMESTAMP_TOLERANCE_STRING: &str = "WEBHOOK_TIMESTAMP_TOLERANCE_SECS";
+/// The string for the environment variable enabling decompressing gzipped bodies sent without a
+/// "Content-Encoding" header.
+pub const SNIFF_GZIP_STRING: &str = "WEBHOOK_SNIFF_GZIP";
+/// The string for the environment variable selecting whether signatures of sniffed gzipped bodies
+/// are of the compressed bytes as sent, or of the decompressed payload when set to "0".
+pub const SIGN_COMPRESSED_STRING: &str = "WEBHOOK_SIGN_COMPRESSED";
+/// The string for the environment variable selecting the provider sending the webhooks.
+pub const PROVIDER_STRING: &str = "WEBHOOK_PROVIDER";
+/// The number of seconds a Stripe signature remains valid for after being sent.
+pub const STRIPE_TOLERANCE_STRING: &str = "WEBHOOK_STRIPE_TOLERANCE_SECS";
+/// The name of the header sent by GitHub generated from the secret and payload.
+pub const HEADER: &str = "X-Hub-Signature-256";
+/// The name of the header sent by GitLab containing the secret token.
+pub const GITLAB_TOKEN_HEADER: &str = "X-Gitlab-Token";
+/// The name of the header sent by Stripe containing the timestamped signatures.
+pub const STRIPE_SIGNATURE_HEADER: &str = "Stripe-Signature";
+
+/// The header "{timestamp}" is filled in from unless configured otherwise.
+const DEFAULT_TIMESTAMP_HEADER: &str = "X-Timestamp";
+
+/// The length of a signature in [`HEADER`], any longer signature cannot be valid.
+const SIGNATURE_LENGTH: usize = "sha256=".len() + 64;
+/// The bytes every gzip stream starts with.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// A scheme for checking that a delivery was sent by its provider.
+pub trait Authenticator: Send + Sync {
+    /// Check that the request & its body were sent by the provider.
+    fn authenticate(&self, request: &Request<'_>, body: &[u8]) -> Result<(), AuthError>;
+}
+
+/// The possible errors when authenticating a delivery.
+#[derive(thiserror::Error, Debug)]
+pub enum AuthError {
+    #[error("{0}")]
+    Provider(#[from] ProviderError),
+    #[error("The \"{0}\" header was not sent.")]
+    MissingHeader(&'static str),
+    #[error("The \"{0}\" header was sent more than once.")]
+    DuplicateHeader(&'static str),
+    #[error("Signature Error: {0}")]
+    Signature(#[from] SignatureError),
+    #[error("The received token does not match the secret.")]
+    TokenMismatch,
+    #[error("The received \"{STRIPE_SIGNATURE_HEADER}\" header is malformed.")]
+    MalformedStripeHeader,
+    #[error("The received signature was sent too long ago.")]
+    Expired,
+    #[error("The \"{0}\" header was not sent as a unix timestamp.")]
+    InvalidTimestamp(String),
+}
+
+/// The possible errors when selecting the provider from the environment.
+#[derive(thiserror::Error, Debug)]
+pub enum ProviderError {
+    #[error("Could not get secret key from environment: {0}")]
+    MissingSecret(#[from] env::VarError),
+    #[error("Unknown provider \"{0}\", expected \"github\", \"gitlab\" or \"stripe\".")]
+    Unknown(String),
+    #[error("Could not read the secret rotation file: {0}")]
+    RotationFile(#[from] std::io::Error),
+    #[error("The secret rotation file contains no secrets.")]
+    NoRotatedSecrets,
+    #[error("Could not get secret from {SECRET_COMMAND_STRING}: {0}")]
+    SecretCommand(String),
+    #[error("No secret is configured for tenant \"{0}\".")]
+    MissingTenantSecret(String),
+    #[error("Invalid tenant secrets: {0}")]
+    MalformedTenantSecrets(#[from] MalformedPair),
+}
+
+/// Selects the authenticator for the provider configured in the environment, defaulting to
+/// GitHub.
+///
+/// GitHub secrets are read from the rotation file instead when one is configured. It's read for
+/// every delivery, so rotations take effect without a restart.
+pub fn from_env() -> Result<Box<dyn Authenticator>, ProviderError> {
+    let provider = env::var(PROVIDER_STRING);
+    if let (Err(_) | Ok("github"), Ok(path)) = (provider.as_deref(), env::var(ROTATION_FILE_STRING))
+    {
+        return Ok(Box::new(RotatingHmac {
+            secrets: read_rotated_secrets(&path)?,
+        }));
+    }
+
+    let secret = secret()?;
+
+    match provider.as_deref() {
+        Err(_) | Ok("github") => Ok(Box::new(GitHubHmac { secret })),
+        Ok("gitlab") => Ok(Box::new(GitLabToken { token: secret })),
+        Ok("stripe") => Ok(Box::new(StripeSignature {
+            secret,
+            tolerance: Duration::from_secs(env_or(STRIPE_TOLERANCE_STRING, 300)),
+        })),
+        Ok(provider) => Err(ProviderError::Unknown(provider.to_owned())),
+    }
+}
+
+/// The secret deliveries are signed with, printed by [`SECRET_COMMAND_STRING`] if configured.
+///
+/// The printed secret is reused until [`SECRET_COMMAND_TTL_STRING`] passes, so the command isn't
+/// run for every delivery.
+pub fn secret() -> Result<String, ProviderError> {
+    let Ok(command) = env::var(SECRET_COMMAND_STRING) else {
+        return Ok(env::var(WEBHOOK_STRING)?);
+    };
+    let ttl = match crate::env_seconds(SECRET_COMMAND_TTL_STRING) {
+        Ok(ttl) => ttl.unwrap_or(DEFAULT_SECRET_TTL),
+        Err(err) => {
+            tracing::warn!("{err} Using the default");
+            DEFAULT_SECRET_TTL
+        }
+    };
+
+    if let Some(secret) = cached_secret(&command, ttl) {
+        return Ok(secret);
+    }
+
+    let timeout = match crate::env_seconds(SECRET_COMMAND_TIMEOUT_STRING) {
+        Ok(timeout) => timeout.unwrap_or(DEFAULT_SECRET_TIMEOUT),
+        Err(err) => {
+            tracing::warn!("{err} Using the default");
+            DEFAULT_SECRET_TIMEOUT
+        }
+    };
+    // Commands can take as long as a vault does to answer, which mustn't stall the executor
+    rocket::tokio::task::block_in_place(|| {
+        // Deliveries arriving mid refresh wait for its secret, rather than running the command too
+        let _refreshing = REFRESHING_SECRET
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(secret) = cached_secret(&command, ttl) {
+            return Ok(secret);
+        }
+
+        let secret = run_secret_command(&command, timeout)?;
+        *COMMAND_SECRET
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(CommandSecret {
+            command,
+            secret: secret.clone(),
+            fetched: Instant::now(),
+        });
+        Ok(secret)
+    })
+}
+
+/// The secret printed by the command, unless it was printed longer than the ttl ago.
+fn cached_secret(command: &str, ttl: Duration) -> Option<String> {
+    // Not held while the command runs, so a slow command doesn't hold up deliveries with a secret
+    COMMAND_SECRET
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+        .filter(|cached| cached.command == command && cached.fetched.elapsed() < ttl)
+        .map(|cached| cached.secret.clone())
+}
+
+/// Runs the secret command, returning the secret it printed.
+///
+/// Commands still running after the timeout are killed.
+fn run_secret_command(command: &str, timeout: Duration) -> Result<String, ProviderError> {
+    let failed = |err: std::io::Error| ProviderError::SecretCommand(err.to_string());
+    let mut child = Command::new("bash")
+        .args(["-c", command])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(failed)?;
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(failed)? {
+            break status;
+        }
+        if started.elapsed() > timeout {
+            // Already exited if this fails, which leaves nothing to kill
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(ProviderError::SecretCommand(format!(
+                "did not finish within {timeout:?}"
+            )));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    if !status.success() {
+        return Err(ProviderError::SecretCommand(format!(
+            "exited with {status}"
+        )));
+    }
+
+    let mut stdout = Vec::new();
+    if let Some(mut pipe) = child.stdout.take() {
+        pipe.read_to_end(&mut stdout).map_err(failed)?;
+    }
+    let secret =
+        String::from_utf8(stdout).map_err(|err| ProviderError::SecretCommand(err.to_string()))?;
+    let secret = secret.trim_end_matches(['\r', '\n']).to_owned();
+    if secret.is_empty() {
+        return Err(ProviderError::SecretCommand("printed nothing".to_owned()));
+    }
+    Ok(secret)
+}
+
+/// How long the secret printed by the command is reused for, unless configured otherwise.
+const DEFAULT_SECRET_TTL: Duration = Duration::from_secs(300);
+/// How long the secret command may run for, unless configured otherwise.
+const DEFAULT_SECRET_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The secret last printed by the secret command, if it has been run.
+static COMMAND_SECRET: Mutex<Option<CommandSecret>> = Mutex::new(None);
+/// Held while the secret command runs, so only one delivery refreshes the secret at a time.
+static REFRESHING_SECRET: Mutex<()> = Mutex::new(());
+
+/// A secret printed by the secret command.
+struct CommandSecret {
+    /// The command that printed the secret, so changes to it aren't served stale secrets.
+    command: String,
+    secret: String,
+    fetched: Instant,
+}
+
+/// Selects the authenticator for the tenant, which signs its deliveries via GitHub with its own
+/// secret.
+///
+/// Tenants never fall back to the global secret, so can't send deliveries as each other.
+pub fn tenant_authenticator(tenant: &str) -> Result<Box<dyn Authenticator>, ProviderError> {
+    let secrets = env::var(crate::tenant::TENANT_SECRETS_STRING).unwrap_or_default();
+    let secret = parse_pairs(&secrets)?
+        .into_iter()
+        .find(|(name, _)| *name == tenant)
+        .map(|(_, secret)| secret.to_owned())
+        .ok_or_else(|| ProviderError::MissingTenantSecret(tenant.to_owned()))?;
+    Ok(Box::new(GitHubHmac { secret }))
+}
+
+/// Check that the request & body were sent by the tenant's provider, or the configured provider
+/// for deliveries without a tenant.
+pub fn authenticate(
+    request: &Request<'_>,
+    tenant: Option<&Tenant>,
+    body: &[u8],
+) -> Result<(), AuthError> {
+    let authenticator = match tenant {
+        Some(tenant) => tenant_authenticator(&tenant.name),
+        None => from_env(),
+    }?;
+    authenticator.authenticate(request, body)
+}
+
+/// GitHub's HMAC signature of the payload, sent in [`HEADER`].
+pub struct GitHubHmac {
+    pub secret: String,
+}
+
+impl Authenticator for GitHubHmac {
+    fn authenticate(&self, request: &Request<'_>, body: &[u8]) -> Result<(), AuthError> {
+        let signed = signed_string(request, body)?;
+        for signature in github_signatures(request)? {
+            signature_matches(&self.secret, &signed, XHubSignature { signature })?;
+        }
+        Ok(())
+    }
+}
+
+/// The signatures in [`HEADER`] that must all be valid, according to how duplicated headers are
+/// configured to be handled.
+///
+/// Proxies may duplicate the header, in which case only the first is checked unless configured
+/// otherwise.
+fn github_signatures<'r>(request: &'r Request<'_>) -> Result<Vec<&'r str>, AuthError> {
+    let signatures: Vec<_> = request.headers().get(HEADER).collect();
+    if signatures.is_empty() {
+        return Err(AuthError::MissingHeader(HEADER));
+    }
+    handle_duplicates(signatures).ok_or(AuthError::DuplicateHeader(HEADER))
+}
+
+/// Narrows the signatures sent in a header down to those that must all be valid, or `None` if
+/// duplicated headers are configured to be rejected.
+fn handle_duplicates(mut signatures: Vec<&str>) -> Option<Vec<&str>> {
+    if signatures.len() > 1 {
+        match env::var(DUPLICATE_SIGNATURES_STRING).as_deref() {
+            Ok("strict") => return None,
+            Ok("all") => {}
+            Err(_) | Ok("first") => signatures.truncate(1),
+            Ok(mode) => {
+                tracing::warn!("Unknown duplicate signature handling \"{mode}\", using \"first\"");
+                signatures.truncate(1);
+            }
+        }
+    }
+    Some(signatures)
+}
+
+/// The string GitHub-style signatures are made over, which is the body unless a template is
+/// configured.
+///
+/// Templates are filled in with the body in "{body}" & the timestamp header in "{timestamp}",
+/// binding the signature to when it was sent. Timestamps outside of the tolerance are rejected so
+/// that old deliveries can't be replayed.
+fn signed_string<'b>(request: &Request<'_>, body: &'b [u8]) -> Result<Cow<'b, [u8]>, AuthError> {
+    let Ok(template) = env::var(SIGNED_STRING_TEMPLATE_STRING) else {
+        return Ok(Cow::Borrowed(body));
+    };
+
+    let mut signed = Vec::with_capacity(template.len() + body.len());
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find('{') {
+        signed.extend_from_slice(&rest.as_bytes()[..start]);
+        rest = &rest[start..];
+
+        if let Some(after) = rest.strip_prefix("{body}") {
+            signed.extend_from_slice(body);
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("{timestamp}") {
+            signed.extend_from_slice(request_timestamp(request)?.as_bytes());
+            rest = after;
+        } else {
+            // Anything else is signed as is
+            signed.push(b'{');
+            rest = &rest[1..];
+        }
+    }
+    signed.extend_from_slice(rest.as_bytes());
+
+    Ok(Cow::Owned(signed))
+}
+
+/// The timestamp the delivery was signed at, as long as it is within the tolerance.
+fn request_timestamp<'r>(request: &'r Request<'_>) -> Result<&'r str, AuthError> {
+    let header = env::var(TIMESTAMP_HEADER_STRING);
+    let header = header.as_deref().unwrap_or(DEFAULT_TIMESTAMP_HEADER);
+    let (Some(raw), Some(timestamp)) = request
+        .headers()
+        .get_one(header)
+        .map(|raw| (Some(raw), raw.trim().parse::<u64>().ok()))
+        .unwrap_or_default()
+    else {
+        return Err(AuthError::InvalidTimestamp(header.to_owned()));
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if now.abs_diff(timestamp) > env_or(TIMESTAMP_TOLERANCE_STRING, 300) {
+        return Err(AuthError::Expired);
+    }
+    Ok(raw)
+}
+
+/// Reads the secrets from the rotation file, one per line with the newest first.
+///
+/// Blank lines & lines starting with `#` are ignored.
+fn read_rotated_secrets(path: &str) -> Result<Vec<String>, ProviderError> {
+    let secrets: Vec<String> = std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect();
+
+    match secrets.is_empty() {
+        true => Err(ProviderError::NoRotatedSecrets),
+        false => Ok(secrets),
+    }
+}
+
+/// GitHub's HMAC signature of the payload, which may be signed by any of the secrets being
+/// rotated through.
+///
+/// This keeps deliveries signed with the previous secret valid while GitHub is switched over to
+/// the new one.
+pub struct RotatingHmac {
+    pub secrets: Vec<String>,
+}
+
+impl Authenticator for RotatingHmac {
+    fn authenticate(&self, request: &Request<'_>, body: &[u8]) -> Result<(), AuthError> {
+        let signed = signed_string(request, body)?;
+        for signature in github_signatures(request)? {
+            let mut result = Err(SignatureError::ValidationError(MacError));
+            for secret in &self.secrets {
+                result = signature_matches(secret, &signed, XHubSignature { signature });
+                if result.is_ok() {
+                    break;
+                }
+            }
+            result?;
+        }
+        Ok(())
+    }
+}
+
+/// GitLab's secret token, sent as is in [`GITLAB_TOKEN_HEADER`].
+pub struct GitLabToken {
+    pub token: String,
+}
+
+impl Authenticator for GitLabToken {
+    fn authenticate(&self, request: &Request<'_>, _body: &[u8]) -> Result<(), AuthError> {
+        let token = request
+            .headers()
+            .get_one(GITLAB_TOKEN_HEADER)
+            .ok_or(AuthError::MissingHeader(GITLAB_TOKEN_HEADER))?;
+
+        match bool::from(token.as_bytes().ct_eq(self.token.as_bytes())) {
+            true => Ok(()),
+            false => Err(AuthError::TokenMismatch),
+        }
+    }
+}
+
+/// Stripe's HMAC signature of the timestamp & payload, sent in [`STRIPE_SIGNATURE_HEADER`] as
+/// `t=<timestamp>,v1=<signature>`.
+pub struct StripeSignature {
+    pub secret: String,
+    pub tolerance: Duration,
+}
+
+impl Authenticator for StripeSignature {
+    fn authenticate(&self, request: &Request<'_>, body: &[u8]) -> Result<(), AuthError> {
+        let header = request
+            .headers()
+            .get_one(STRIPE_SIGNATURE_HEADER)
+            .ok_or(AuthError::MissingHeader(STRIPE_SIGNATURE_HEADER))?;
+
+        let mut timestamp = None;
+        let mut signatures = Vec::new();
+        for (key, value) in header.split(',').filter_map(|pair| pair.split_once('=')) {
+            match key.trim() {
+                "t" => timestamp = value.parse::<u64>().ok(),
+                "v1" => signatures.push(value),
+                // Older signature schemes are ignored
+                _ => {}
+            }
+        }
+        let timestamp = timestamp.ok_or(AuthError::MalformedStripeHeader)?;
+
+        // Stops old deliveries from being replayed
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if now.abs_diff(timestamp) > self.tolerance.as_secs() {
+            return Err(AuthError::Expired);
+        }
+
+        let mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(self.secret.as_bytes())
+            .unwrap()
+            .chain_update(format!("{timestamp}."))
+            .chain_update(body);
+
+        // Any of the signatures may match, as Stripe sends one per active secret
+        let matches = signatures.into_iter().any(|signature| {
+            hex::decode(signature).is_ok_and(|raw| mac.clone().verify_slice(&raw).is_ok())
+        });
+        match matches {
+            true => Ok(()),
+            false => Err(SignatureError::ValidationError(MacError).into()),
+        }
+    }
+}
+
+/// The body of a delivery, along with the outcome of authenticating it with the configured
+/// provider.
+///
+/// Failing to authenticate doesn't fail the guard, leaving the handler to decide when to reject
+/// the delivery.
+pub struct Authenticated {
+    pub body: Vec<u8>,
+    pub result: Result<(), AuthError>,
+    /// The body as it was sent, when it was decompressed but the compressed body is what's signed.
+    pub signed: Option<Vec<u8>>,
+    /// The memory set aside for the body, held for as long as the body is.
+    pub reservation: Option<Reservation>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for Authenticated {
+    type Error = std::io::Error;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> Outcome<'r, Self> {
+        let limit = request.limits().get("string").unwrap_or(32.kibibytes());
+
+        // Many large bodies arriving at once could otherwise exhaust memory between them
+        let reservation = match env::var(MAX_TOTAL_BODY_STRING).map(|max| max.parse::<u64>()) {
+            Err(_) => None,
+            Ok(Ok(max)) => {
+                let bytes = request
+                    .headers()
+                    .get_one("Content-Length")
+                    .and_then(|length| length.parse::<u64>().ok())
+                    .map_or(limit.as_u64(), |length| length.min(limit.as_u64()));
+                let memory = request
+                    .rocket()
+                    .state::<BodyMemory>()
+                    .expect("Body memory is managed");
+
+                match memory.reserve(bytes, max.kibibytes().as_u64()) {
+                    Some(reservation) => Some(reservation),
+                    None => {
+                        let err = std::io::Error::other("Too many bodies are being buffered");
+                        return Outcome::Error((Status::ServiceUnavailable, err));
+                    }
+                }
+            }
+            Ok(Err(err)) => {
+                tracing::warn!("Invalid value for {MAX_TOTAL_BODY_STRING}, ignoring it: {err}");
+                None
+            }
+        };
+        let read = data.open(limit).into_bytes();
+
+        // Clients that never finish sending would otherwise hold the connection open forever.
+        // Leaving the body unread makes the connection get closed after responding.
+        let read = match crate::env_seconds(MAX_REQUEST_STRING) {
+            Ok(Some(max)) => match rocket::tokio::time::timeout(max, read).await {
+                Ok(read) => read,
+                Err(_) => {
+                    let err = std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "Payload was not sent in time",
+                    );
+                    return Outcome::Error((Status::RequestTimeout, err));
+                }
+            },
+            Ok(None) => read.await,
+            Err(err) => {
+                tracing::warn!("{err} Ignoring it");
+                read.await
+            }
+        };
+
+        let body = match read {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            Ok(_) => {
+                let err = std::io::Error::other("Payload exceeds the size limit");
+                return Outcome::Error((Status::PayloadTooLarge, err));
+            }
+            Err(err) => return Outcome::Error((Status::BadRequest, err)),
+        };
+
+        // Some senders compress bodies without saying so, which scripts can't be expected to handle
+        let (body, compressed) = match crate::env_flag(SNIFF_GZIP_STRING)
+            && body.starts_with(&GZIP_MAGIC)
+        {
+            true => match gunzip(&body, limit.as_u64()) {
+                Ok(Some(decompressed)) => (decompressed, Some(body)),
+                Ok(None) => {
+                    let err = std::io::Error::other("Decompressed payload exceeds the size limit");
+                    return Outcome::Error((Status::PayloadTooLarge, err));
+                }
+                Err(err) => return Outcome::Error((Status::BadRequest, err)),
+            },
+            false => (body, None),
+        };
+        let signed = match env::var(SIGN_COMPRESSED_STRING).as_deref() {
+            Ok("0" | "false") => None,
+            _ => compressed,
+        };
+
+        let result = authenticate(
+            request,
+            crate::tenant::of(request),
+            signed.as_deref().unwrap_or(&body),
+        );
+
+        Outcome::Success(Self {
+            body,
+            result,
+            signed,
+            reservation,
+        })
+    }
+}
+
+/// Decompresses the gzipped body, or returns `None` if it decompresses to more than the limit.
+fn gunzip(body: &[u8], limit: u64) -> std::io::Result<Option<Vec<u8>>> {
+    let mut decompressed = Vec::new();
+    GzDecoder::new(body)
+        .take(limit + 1)
+        .read_to_end(&mut decompressed)?;
+    Ok((decompressed.len() as u64 <= limit).then_some(decompressed))
+}
+
+/// The possible errors when checking that the received signature is correct.
+#[derive(thiserror::Error, Debug)]
+pub enum SignatureError {
+    #[error("The received signature is {0} bytes long, far longer than any valid signature.")]
+    Oversized(usize),
+    #[error("The received signature contained non-ascii chars.")]
+    NotASCII,
+    #[error("The received signature is not valid hexadecimal: {0}")]
+    BadHex(#[from] hex::FromHexError),
+    #[error("Error when validating signature: {0}")]
+    ValidationError(#[from] MacError),
+    #[error("The signature is well formed but does not match, so the payload may have been altered in transit or signed with another secret.")]
+    Altered,
+}
+
+/// Check if the payload signature is generated from the given secret
+pub fn signature_matches(
+    secret: &str,
+    payload: impl AsRef<[u8]>,
+    signature: XHubSignature,
+) -> Result<(), SignatureError> {
+    let XHubSignature { signature } = signature;
+
+    // Not worth decoding, and likely sent to waste our time
+    if signature.len() > SIGNATURE_LENGTH {
+        return Err(SignatureError::Oversized(signature.len()));
+    }
+
+    // Remove the "sha256=" from start of signature
+    let hex_signature = signature
+        .split_at_checked(7)
+        .ok_or(SignatureError::NotASCII)?
+        .1;
+
+    // Using let binding to create longer lived value
+    let binding = hex::decode(hex_signature)?;
+    let raw_signature = binding.as_slice();
+
+    let payload = payload.as_ref();
+    let computed = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes())
+        .unwrap()
+        .chain_update(payload)
+        .finalize()
+        .into_bytes();
+    if raw_signature.len() != computed.len() {
+        return Err(MacError.into());
+    }
+    if bool::from(raw_signature.ct_eq(&computed)) {
+        return Ok(());
+    }
+
+    // Only a prefix of the computed signature is logged, so the logs can't be used to forge one
+    let computed = hex::encode(computed);
+    tracing::warn!(
+        "Signature mismatch over {} bytes with sha256 digest {}: received {hex_signature}, \
+         computed {}…",
+        payload.len(),
+        hex::encode(sha2::Sha256::digest(payload)),
+        &computed[..8]
+    );
+    Err(SignatureError::Altered)
+}
+
+/// The GitHub webhook payload signature
+pub struct XHubSignature<'a> {
+    pub signature: &'a str,
+}
+
+/// The possible errors when checking the additionally required signatures.
+#[derive(thiserror::Error, Debug)]
+pub enum MultiSignatureError {
+    #[error("Invalid required signatures: {0}")]
+    MalformedPair(#[from] MalformedPair),
+    #[error("The required signature header \"{0}\" was not sent.")]
+    MissingHeader(String),
+    #[error("The required signature header \"{0}\" was sent more than once.")]
+    DuplicateHeader(String),
+    #[error("The signature in \"{header}\" is invalid: {source}")]
+    Invalid {
+        header: String,
+        source: SignatureError,
+    },
+}
+
+/// Check that the payload signature in every header of the `header=secret` pairs is generated
+/// from its paired secret.
+///
+/// Duplicated headers are handled the same as for the primary signature, & the payload should be
+/// the same bytes the primary signature was checked against.
+pub fn all_signatures_match(
+    pairs: &str,
+    headers: &HeaderMap<'_>,
+    payload: &[u8],
+) -> Result<(), MultiSignatureError> {
+    for (header, secret) in parse_pairs(pairs)? {
+        let signatures: Vec<_> = headers.get(header).collect();
+        if signatures.is_empty() {
+            return Err(MultiSignatureError::MissingHeader(header.to_owned()));
+        }
+        let signatures = handle_duplicates(signatures)
+            .ok_or_else(|| MultiSignatureError::DuplicateHeader(header.to_owned()))?;
+
+        for signature in signatures {
+            signature_matches(secret, payload, XHubSignature { signature }).map_err(|source| {
+                MultiSignatureError::Invalid {
+                    header: header.to_owned(),
+                    source,
+                }
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::{
+        http::Header,
+        local::blocking::{Client, LocalRequest},
+    };
+
+    use super::*;
+
+    /// Authenticates the request & body with the authenticator.
+    fn authenticate(
+        authenticator: &dyn Authenticator,
+        request: LocalRequest,
+        body: &str,
+    ) -> Result<(), AuthError> {
+        authenticator.authenticate(request.inner(), body.as_bytes())
+    }
+
+    #[test]
+    fn signature_generation() {
+        signature_matches(
+            "It's a Secret to Everybody",
+            "Hello, World!",
+            XHubSignature {
+                signature:
+                    "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17",
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn altered_payload_diagnostics() {
+        let captured = crate::logging::tests::Captured::default();
+        let writer = captured.clone();
+        // Logged through the same subscriber the handler installs
+        let subscriber = temp_env::with_var_unset(crate::logging::BUFFER_DELIVERIES_STRING, || {
+            crate::logging::subscriber(move || writer.clone(), false)
+        });
+
+        let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+        let result = tracing::subscriber::with_default(subscriber, || {
+            signature_matches(
+                "It's a Secret to Everybody",
+                "Hello, World?",
+                XHubSignature { signature },
+            )
+        });
+        assert!(matches!(result, Err(SignatureError::Altered)));
+
+        let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("Signature mismatch over 13 bytes"));
+        assert!(logs.contains(&format!("received {}", &signature[7..])));
+        assert!(logs.contains(&format!(
+            "sha256 digest {}",
+            hex::encode(sha2::Sha256::digest("Hello, World?"))
+        )));
+
+        // Truncated signatures aren't mistaken for altered payloads
+        assert!(matches!(
+            signature_matches(
+                "It's a Secret to Everybody",
+                "Hello, World!",
+                XHubSignature {
+                    signature: &signature[..21]
+                },
+            ),
+            Err(SignatureError::ValidationError(_))
+        ));
+    }
+
+    #[test]
+    fn github_authenticator() {
+        let client = Client::untracked(rocket::build()).expect("valid rocket instance");
+        let authenticator = GitHubHmac {
+            secret: "It's a Secret to Everybody".to_owned(),
+        };
+        let signed = || {
+            client.post("/").header(Header::new(
+                HEADER,
+                "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17",
+            ))
+        };
+
+        // Signatures are over the body alone by default
+        temp_env::with_var_unset(SIGNED_STRING_TEMPLATE_STRING, || {
+            authenticate(&authenticator, signed(), "Hello, World!").expect("Valid signature");
+            assert!(matches!(
+                authenticate(&authenticator, signed(), "Goodbye, World!"),
+                Err(AuthError::Signature(_))
+            ));
+            assert!(matches!(
+                authenticate(&authenticator, client.post("/"), "Hello, World!"),
+                Err(AuthError::MissingHeader(HEADER))
+            ));
+        });
+    }
+
+    #[test]
+    fn rotating_secrets() {
+        let client = Client::untracked(rocket::build()).expect("valid rocket instance");
+        let temp_dir =
+            tempdir::TempDir::new("webhook_handler-temp").expect("Able to create temp dir");
+        let rotation_file = temp_dir.path().join("secrets");
+        let signed_with = |secret: &str| {
+            let signature = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes())
+                .unwrap()
+                .chain_update("{}")
+                .finalize()
+                .into_bytes();
+            client.post("/").header(Header::new(
+                HEADER,
+                format!("sha256={}", hex::encode(signature)),
+            ))
+        };
+        let authenticate_with = |secret: &str| {
+            from_env()
+                .expect("Valid provider")
+                .authenticate(signed_with(secret).inner(), b"{}")
+        };
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, None),
+                (PROVIDER_STRING, None),
+                (ROTATION_FILE_STRING, rotation_file.to_str()),
+            ],
+            || {
+                std::fs::write(&rotation_file, "first\n").unwrap();
+                authenticate_with("first").expect("Valid signature");
+                assert!(authenticate_with("second").is_err());
+
+                // Both secrets are valid while rotating
+                std::fs::write(&rotation_file, "# newest first\nsecond\nfirst\n").unwrap();
+                authenticate_with("second").expect("Valid signature");
+                authenticate_with("first").expect("Valid signature");
+
+                std::fs::write(&rotation_file, "second\n").unwrap();
+                assert!(matches!(
+                    authenticate_with("first"),
+                    Err(AuthError::Signature(_))
+                ));
+
+                std::fs::write(&rotation_file, "\n").unwrap();
+                assert!(matches!(from_env(), Err(ProviderError::NoRotatedSecrets)));
+            },
+        );
+    }
+
+    #[test]
+    fn duplicate_signatures() {
+        let client = Client::untracked(rocket::build()).expect("valid rocket instance");
+        let authenticator = GitHubHmac {
+            secret: "It's a Secret to Everybody".to_owned(),
+        };
+        let valid = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+        let duplicated = |second: &'static str| {
+            client
+                .post("/")
+                .header(Header::new(HEADER, valid))
+                .header(Header::new(HEADER, second))
+        };
+
+        temp_env::with_var(DUPLICATE_SIGNATURES_STRING, Some("strict"), || {
+            assert!(matches!(
+                authenticate(&authenticator, duplicated(valid), "Hello, World!"),
+                Err(AuthError::DuplicateHeader(HEADER))
+            ));
+        });
+
+        temp_env::with_var(DUPLICATE_SIGNATURES_STRING, Some("all"), || {
+            authenticate(&authenticator, duplicated(valid), "Hello, World!")
+                .expect("Valid signatures");
+            assert!(matches!(
+                authenticate(
+                    &authenticator,
+                    duplicated("sha256=0123acd"),
+                    "Hello, World!"
+                ),
+                Err(AuthError::Signature(_))
+            ));
+        });
+
+        // Only the first is checked by default
+        temp_env::with_var(DUPLICATE_SIGNATURES_STRING, None::<&str>, || {
+            authenticate(
+                &authenticator,
+                duplicated("sha256=0123acd"),
+                "Hello, World!",
+            )
+            .expect("Valid signature");
+        });
+    }
+
+    #[test]
+    fn signed_string_template() {
+        let client = Client::untracked(rocket::build()).expect("valid rocket instance");
+        let authenticator = GitHubHmac {
+            secret: "VerySecure".to_owned(),
+        };
+        let signed = |signed_string: &str, timestamp: Option<String>| {
+            let signature = hmac::Hmac::<sha2::Sha256>::new_from_slice(b"VerySecure")
+                .unwrap()
+                .chain_update(signed_string)
+                .finalize()
+                .into_bytes();
+            let request = client.post("/").header(Header::new(
+                HEADER,
+                format!("sha256={}", hex::encode(signature)),
+            ));
+            match timestamp {
+                Some(timestamp) => request.header(Header::new("X-Signed-At", timestamp)),
+                None => request,
+            }
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        temp_env::with_vars(
+            [
+                (SIGNED_STRING_TEMPLATE_STRING, Some("v0:{timestamp}.{body}")),
+                (TIMESTAMP_HEADER_STRING, Some("X-Signed-At")),
+                (TIMESTAMP_TOLERANCE_STRING, None),
+            ],
+            || {
+                let valid = || signed(&format!("v0:{now}.{{}}"), Some(now.to_string()));
+                authenticate(&authenticator, valid(), "{}").expect("Valid signature");
+                assert!(matches!(
+                    authenticate(&authenticator, valid(), "{\"tampered\": true}"),
+                    Err(AuthError::Signature(_))
+                ));
+
+                // The signature is bound to the timestamp it was sent with
+                let moved = signed(&format!("v0:{now}.{{}}"), Some((now + 1).to_string()));
+                assert!(matches!(
+                    authenticate(&authenticator, moved, "{}"),
+                    Err(AuthError::Signature(_))
+                ));
+
+                // Signing the body alone is no longer enough
+                assert!(matches!(
+                    authenticate(&authenticator, signed("{}", Some(now.to_string())), "{}"),
+                    Err(AuthError::Signature(_))
+                ));
+
+                let old = now - 600;
+                let replayed = signed(&format!("v0:{old}.{{}}"), Some(old.to_string()));
+                assert!(matches!(
+                    authenticate(&authenticator, replayed, "{}"),
+                    Err(AuthError::Expired)
+                ));
+                assert!(matches!(
+                    authenticate(&authenticator, signed("v0:.{}", None), "{}"),
+                    Err(AuthError::InvalidTimestamp(_))
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn gitlab_authenticator() {
+        let client = Client::untracked(rocket::build()).expect("valid rocket instance");
+        let authenticator = GitLabToken {
+            token: "VerySecure".to_owned(),
+        };
+        let with_token = |token: &'static str| {
+            client
+                .post("/")
+                .header(Header::new(GITLAB_TOKEN_HEADER, token))
+        };
+
+        authenticate(&authenticator, with_token("VerySecure"), "{}").expect("Valid token");
+        assert!(matches!(
+            authenticate(&authenticator, with_token("VeryInsecure"), "{}"),
+            Err(AuthError::TokenMismatch)
+        ));
+    }
+
+    #[test]
+    fn stripe_authenticator() {
+        let client = Client::untracked(rocket::build()).expect("valid rocket instance");
+        let authenticator = StripeSignature {
+            secret: "VerySecure".to_owned(),
+            tolerance: Duration::from_secs(300),
+        };
+        let signed = |timestamp: u64| {
+            let signature = hmac::Hmac::<sha2::Sha256>::new_from_slice(b"VerySecure")
+                .unwrap()
+                .chain_update(format!("{timestamp}.{{}}"))
+                .finalize()
+                .into_bytes();
+            client.post("/").header(Header::new(
+                STRIPE_SIGNATURE_HEADER,
+                format!("t={timestamp},v1={}", hex::encode(signature)),
+            ))
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        authenticate(&authenticator, signed(now), "{}").expect("Valid signature");
+        assert!(matches!(
+            authenticate(&authenticator, signed(now), "{\"tampered\": true}"),
+            Err(AuthError::Signature(_))
+        ));
+        assert!(matches!(
+            authenticate(&authenticator, signed(now - 600), "{}"),
+            Err(AuthError::Expired)
+        ));
+    }
+
+    #[test]
+    fn secret_refreshed_once() {
+        let temp_dir = tempdir::TempDir::new("webhook_handler-temp").unwrap();
+        let runs = temp_dir.path().join("runs.temp");
+        let command = format!("sleep 0.2; echo run >> {}; echo Refreshed", runs.display());
+
+        temp_env::with_vars(
+            [
+                (SECRET_COMMAND_STRING, Some(command.as_str())),
+                (SECRET_COMMAND_TTL_STRING, None),
+                (SECRET_COMMAND_TIMEOUT_STRING, None),
+            ],
+            || {
+                // Every delivery arriving before the secret is cached waits for the same run
+                std::thread::scope(|scope| {
+                    let deliveries: Vec<_> = (0..4).map(|_| scope.spawn(secret)).collect();
+                    for delivery in deliveries {
+                        assert_eq!(delivery.join().unwrap().unwrap(), "Refreshed");
+                    }
+                });
+            },
+        );
+
+        assert_eq!(std::fs::read_to_string(runs).unwrap(), "run\n");
+    }
+}
@@ -0,0 +1,108 @@
+//! Collecting bursts of deliveries into a single run of their script.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::script;
+
+/// The string for the environment variable containing the seconds deliveries are collected for
+/// before their script is run once for all of them.
+pub const BATCH_WINDOW_STRING: &str = "WEBHOOK_BATCH_WINDOW_SECS";
+/// The string for the environment variable containing the most deliveries collected into a batch,
+/// which is run as soon as it fills up.
+pub const BATCH_MAX_STRING: &str = "WEBHOOK_BATCH_MAX_SIZE";
+
+/// The batches still collecting deliveries, keyed by the script they are for.
+#[derive(Default)]
+pub struct Batches {
+    open: Arc<Mutex<HashMap<PathBuf, Batch>>>,
+    next_id: AtomicU64,
+}
+
+/// The payloads collected for a run of the script.
+struct Batch {
+    /// Tells the batch apart from later ones for the same script, once it has been run.
+    id: u64,
+    payloads: Vec<serde_json::Value>,
+}
+
+impl Batches {
+    /// Adds the payload to the open batch for the script, opening one to be run once the window
+    /// passes if there is none.
+    ///
+    /// Batches are run as soon as they hold `max` payloads.
+    pub fn add(
+        &self,
+        script: PathBuf,
+        payload: serde_json::Value,
+        window: Duration,
+        max: Option<usize>,
+    ) {
+        let mut open = self.open.lock().unwrap_or_else(PoisonError::into_inner);
+        let batch = open.entry(script.clone()).or_insert_with(|| {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            self.close_after(script.clone(), id, window);
+            Batch {
+                id,
+                payloads: Vec::new(),
+            }
+        });
+
+        batch.payloads.push(payload);
+        if max.is_some_and(|max| batch.payloads.len() >= max) {
+            if let Some(batch) = open.remove(&script) {
+                run(script, batch.payloads);
+            }
+        }
+    }
+
+    /// Runs the batch once the window passes, unless it already filled up.
+    fn close_after(&self, script: PathBuf, id: u64, window: Duration) {
+        let open = self.open.clone();
+        thread::spawn(move || {
+            thread::sleep(window);
+
+            let mut open = open.lock().unwrap_or_else(PoisonError::into_inner);
+            if open.get(&script).is_some_and(|batch| batch.id == id) {
+                if let Some(batch) = open.remove(&script) {
+                    run(script, batch.payloads);
+                }
+            }
+        });
+    }
+}
+
+/// Runs the script with the json array of the payloads on its stdin, logging how it finished.
+fn run(script: PathBuf, payloads: Vec<serde_json::Value>) {
+    tracing::info!(
+        "Running {} for a batch of {} deliveries",
+        script.display(),
+        payloads.len()
+    );
+
+    let input = serde_json::Value::Array(payloads).to_string();
+    let spawned = crate::script_command(&script)
+        .and_then(|command| script::spawn_with_input(command, input.into_bytes()));
+    let finished = match spawned {
+        Ok(finished) => finished,
+        Err(err) => {
+            tracing::error!("Could not execute bash script: {err}");
+            return;
+        }
+    };
+
+    thread::spawn(move || match finished.blocking_recv() {
+        Ok(Ok(status)) if status.success() => {}
+        Ok(Ok(status)) => tracing::error!("Bash script failed for a batch: {status}"),
+        Ok(Err(err)) => tracing::error!("Could not wait for bash script: {err}"),
+        Err(_) => tracing::error!("Bash script was never waited on"),
+    });
+}
@@ -0,0 +1,73 @@
+//! Limiting how many deliveries a single keep-alive connection may send.
+
+use std::{
+    collections::HashMap,
+    env,
+    net::SocketAddr,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{Header, Method},
+    Request, Response,
+};
+
+/// The string for the environment variable containing the most deliveries a connection may send
+/// before being closed.
+pub const MAX_DELIVERIES_STRING: &str = "WEBHOOK_MAX_DELIVERIES_PER_CONNECTION";
+
+/// Closes connections once they have sent the configured number of deliveries.
+///
+/// Each open connection has its own client address, so connections are told apart by it.
+#[derive(Default)]
+pub struct DeliveryCap {
+    deliveries: Mutex<HashMap<SocketAddr, (u32, Instant)>>,
+}
+
+#[rocket::async_trait]
+impl Fairing for DeliveryCap {
+    fn info(&self) -> Info {
+        Info {
+            name: "Delivery cap per connection",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let (Method::Post, Some(remote), Ok(max)) = (
+            request.method(),
+            request.remote(),
+            env::var(MAX_DELIVERIES_STRING).map(|max| max.parse::<u32>()),
+        ) else {
+            return;
+        };
+        let max = match max {
+            Ok(max) => max,
+            Err(err) => {
+                tracing::warn!("Invalid value for {MAX_DELIVERIES_STRING}, ignoring it: {err}");
+                return;
+            }
+        };
+
+        let idle = Duration::from_secs(request.rocket().config().keep_alive.into());
+        let mut deliveries = self
+            .deliveries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        // Forget connections that have since been closed for being idle
+        let now = Instant::now();
+        deliveries.retain(|_, (_, last_seen)| now.duration_since(*last_seen) <= idle);
+
+        let (count, last_seen) = deliveries.entry(remote).or_insert((0, now));
+        *count += 1;
+        *last_seen = now;
+
+        if *count >= max {
+            deliveries.remove(&remote);
+            response.set_header(Header::new("Connection", "close"));
+        }
+    }
+}
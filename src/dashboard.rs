@@ -0,0 +1,151 @@
+//! A minimal HTML page showing what the handler has been doing, for operators.
+
+use std::{
+    collections::VecDeque,
+    env,
+    fmt::Write,
+    sync::{atomic::Ordering, Mutex, PoisonError},
+    time::Duration,
+};
+
+use rocket::{
+    get,
+    http::Status,
+    request::{FromRequest, Outcome},
+    response::content::RawHtml,
+    Request, State,
+};
+use subtle::ConstantTimeEq;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::metrics::{DELIVERIES, SCRIPT_USAGE};
+
+/// The string for the environment variable containing the bearer token needed to view the
+/// dashboard.
+pub const ADMIN_TOKEN_STRING: &str = "WEBHOOK_ADMIN_TOKEN";
+
+/// The number of recent deliveries shown on the dashboard.
+const RECENT_DELIVERIES: usize = 50;
+
+/// A delivery as shown on the dashboard.
+struct DeliveryRecord {
+    received: OffsetDateTime,
+    event: Option<String>,
+    status: Status,
+    elapsed: Duration,
+}
+
+/// The most recent deliveries, newest first.
+#[derive(Default)]
+pub struct RecentDeliveries {
+    deliveries: Mutex<VecDeque<DeliveryRecord>>,
+}
+
+impl RecentDeliveries {
+    /// Records a delivery that has just been responded to, forgetting the oldest if there are too
+    /// many.
+    pub fn record(&self, event: Option<&str>, status: Status, elapsed: Duration) {
+        let mut deliveries = self
+            .deliveries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        deliveries.push_front(DeliveryRecord {
+            received: OffsetDateTime::now_utc(),
+            event: event.map(str::to_owned),
+            status,
+            elapsed,
+        });
+        deliveries.truncate(RECENT_DELIVERIES);
+    }
+}
+
+/// A request bearing the admin token.
+///
+/// The dashboard is hidden entirely unless a non-empty token is configured.
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'a> FromRequest<'a> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'_>) -> Outcome<Self, ()> {
+        // An empty token would otherwise let in requests sending no token at all
+        let Some(token) = env::var(ADMIN_TOKEN_STRING)
+            .ok()
+            .filter(|token| !token.is_empty())
+        else {
+            return Outcome::Forward(Status::NotFound);
+        };
+
+        let Some(sent) = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "))
+        else {
+            return Outcome::Error((Status::Unauthorized, ()));
+        };
+        match bool::from(sent.as_bytes().ct_eq(token.as_bytes())) {
+            true => Outcome::Success(Self),
+            false => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// The dashboard, showing the counters & the most recent deliveries.
+#[get("/dashboard")]
+pub fn dashboard(_admin: Admin, recent: &State<RecentDeliveries>) -> RawHtml<String> {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Webhook handler</title>\
+         <style>body{font-family:sans-serif}td,th{padding:0 1em;text-align:left}</style>\
+         </head><body>\n<h1>Webhook handler</h1>\n",
+    );
+
+    // Writing to a string never fails
+    let _ = write!(
+        html,
+        "<h2>Counters</h2>\n<table>\n<tr><th>Deliveries</th><td>{}</td></tr>\n\
+         <tr><th>Scripts finished</th><td>{}</td></tr>\n\
+         <tr><th>Script CPU seconds</th><td>{:.3}</td></tr>\n</table>\n",
+        DELIVERIES.load(Ordering::Relaxed),
+        SCRIPT_USAGE.finished(),
+        SCRIPT_USAGE.cpu().as_secs_f64(),
+    );
+
+    html.push_str(
+        "<h2>Recent deliveries</h2>\n<table>\n\
+         <tr><th>Received</th><th>Event</th><th>Status</th><th>Took</th></tr>\n",
+    );
+    let deliveries = recent
+        .deliveries
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    for delivery in deliveries.iter() {
+        let _ = writeln!(
+            html,
+            "<tr class=\"delivery\"><td>{}</td><td>{}</td><td>{}</td><td>{} ms</td></tr>",
+            delivery.received.format(&Rfc3339).unwrap_or_default(),
+            escape(delivery.event.as_deref().unwrap_or("-")),
+            delivery.status,
+            delivery.elapsed.as_millis(),
+        );
+    }
+    html.push_str("</table>\n</body></html>\n");
+
+    RawHtml(html)
+}
+
+/// Escapes text sent by clients so it can't inject markup into the page.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for char in text.chars() {
+        match char {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            char => escaped.push(char),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,187 @@
+//! Holding deliveries whose signature is sent in a separate request, until it arrives.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use rocket::{
+    http::{HeaderMap, Status},
+    request::{FromRequest, Outcome},
+    Request, State,
+};
+
+use crate::{
+    auth::{self, AuthError},
+    memory::Reservation,
+    tenant::Tenant,
+};
+
+/// The string for the environment variable containing the seconds a delivery is held for while
+/// waiting for its detached signature.
+pub const DETACHED_TIMEOUT_STRING: &str = "WEBHOOK_DETACHED_SIGNATURE_SECS";
+/// The name of the header containing the token shared by a delivery & its detached signature.
+pub const TOKEN_HEADER: &str = "X-Signature-Token";
+
+/// The most deliveries held at once, as any client can ask for deliveries to be held.
+const MAX_HELD: usize = 1000;
+/// The most bytes of bodies held at once.
+const MAX_HELD_BYTES: usize = 64 * 1024 * 1024;
+
+/// The possible reasons a delivery can't be held.
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum HoldError {
+    #[error("Signature token \"{0}\" is already in use")]
+    TokenInUse(String),
+    #[error("Too many deliveries are waiting for their signatures")]
+    Full,
+}
+
+/// A delivery waiting for its signature.
+pub struct Held {
+    pub body: Vec<u8>,
+    pub headers: HeaderMap<'static>,
+    /// The tenant the delivery was sent for, whose secret & script it is signed & run with.
+    pub tenant: Option<Tenant>,
+    expires: Instant,
+    /// The memory set aside for the body, held for as long as the body is.
+    _reservation: Option<Reservation>,
+}
+
+impl Held {
+    /// Whether the signature arrived too late for the delivery to be run.
+    pub fn expired(&self) -> bool {
+        Instant::now() > self.expires
+    }
+}
+
+/// The deliveries waiting for their signatures, keyed by their tokens.
+#[derive(Default)]
+pub struct PendingDeliveries {
+    pending: Mutex<HashMap<String, Held>>,
+}
+
+impl PendingDeliveries {
+    /// Holds the delivery until its signature arrives or the timeout passes.
+    ///
+    /// Deliveries aren't held if the token is already in use, or too many are already held.
+    pub fn hold(
+        &self,
+        token: &str,
+        body: Vec<u8>,
+        headers: &HeaderMap<'_>,
+        tenant: Option<&Tenant>,
+        reservation: Option<Reservation>,
+        timeout: Duration,
+    ) -> Result<(), HoldError> {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // Deliveries whose signature never arrived would otherwise pile up
+        pending.retain(|_, held| !held.expired());
+        if pending.contains_key(token) {
+            return Err(HoldError::TokenInUse(token.to_owned()));
+        }
+        let held_bytes: usize = pending.values().map(|held| held.body.len()).sum();
+        if pending.len() >= MAX_HELD || held_bytes + body.len() > MAX_HELD_BYTES {
+            return Err(HoldError::Full);
+        }
+
+        let mut owned = HeaderMap::new();
+        for header in headers.iter() {
+            owned.add_raw(header.name().as_str().to_owned(), header.value().to_owned());
+        }
+        pending.insert(
+            token.to_owned(),
+            Held {
+                body,
+                headers: owned,
+                tenant: tenant.cloned(),
+                expires: Instant::now() + timeout,
+                _reservation: reservation,
+            },
+        );
+        Ok(())
+    }
+
+    /// Takes the delivery held for the token, so each token can only be signed once.
+    pub fn take(&self, token: &str) -> Option<Held> {
+        self.pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(token)
+    }
+}
+
+/// A held delivery released by a request to sign it, along with whether that request signed it.
+pub struct Signed {
+    pub held: Held,
+    pub result: Result<(), AuthError>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Signed {
+    type Error = ();
+
+    /// Takes the delivery held for the token in the path, checking the signature sent with the
+    /// request in the same way as deliveries signed when they are sent.
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        let Some(Ok(token)) = request.param::<&str>(1) else {
+            return Outcome::Forward(Status::NotFound);
+        };
+        let pending = match request.guard::<&State<PendingDeliveries>>().await {
+            Outcome::Success(pending) => pending,
+            Outcome::Error((status, _)) | Outcome::Forward(status) => {
+                return Outcome::Error((status, ()))
+            }
+        };
+
+        let Some(held) = pending.take(token) else {
+            tracing::warn!("No delivery is held for signature token \"{token}\"");
+            return Outcome::Error((Status::NotFound, ()));
+        };
+        if held.expired() {
+            tracing::warn!("Signature for token \"{token}\" arrived too late");
+            return Outcome::Error((Status::Gone, ()));
+        }
+
+        let result = auth::authenticate(request, held.tenant.as_ref(), &held.body);
+        Outcome::Success(Self { held, result })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limited() {
+        let pending = PendingDeliveries::default();
+        let headers = HeaderMap::new();
+        let hold = |token: &str, bytes: usize| {
+            pending.hold(
+                token,
+                vec![0; bytes],
+                &headers,
+                None,
+                None,
+                Duration::from_secs(60),
+            )
+        };
+
+        assert_eq!(hold("first", 1), Ok(()));
+        assert_eq!(
+            hold("first", 1),
+            Err(HoldError::TokenInUse("first".to_owned()))
+        );
+        assert_eq!(hold("huge", MAX_HELD_BYTES), Err(HoldError::Full));
+        for token in 1..MAX_HELD {
+            assert_eq!(hold(&token.to_string(), 1), Ok(()));
+        }
+        assert_eq!(hold("last", 1), Err(HoldError::Full));
+
+        // Signing deliveries makes room for more
+        assert!(pending.take("first").is_some());
+        assert_eq!(hold("last", 1), Ok(()));
+    }
+}
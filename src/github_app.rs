@@ -0,0 +1,205 @@
+//! Minting installation access tokens for scripts acting as a GitHub App.
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    sync::{Mutex, PoisonError},
+};
+
+use jsonwebtoken::{Algorithm, EncodingKey};
+use time::{format_description::well_known::Rfc3339, Duration, OffsetDateTime};
+
+/// The string for the environment variable containing the id of the GitHub App.
+pub const APP_ID_STRING: &str = "WEBHOOK_APP_ID";
+/// The string for the environment variable containing the path to the App's private key.
+pub const APP_PRIVATE_KEY_STRING: &str = "WEBHOOK_APP_PRIVATE_KEY";
+/// The string for the environment variable containing the base url of the GitHub API.
+pub const API_URL_STRING: &str = "WEBHOOK_GITHUB_API_URL";
+/// The environment variable the installation token is passed to the script in.
+pub const TOKEN_ENV: &str = "GITHUB_TOKEN";
+
+/// The GitHub API used unless another is configured (e.g. for GitHub Enterprise).
+const DEFAULT_API_URL: &str = "https://api.github.com";
+/// How long before expiry a cached token is refreshed, so it is never handed out nearly dead.
+const REFRESH_MARGIN: Duration = Duration::seconds(60);
+
+/// The possible errors when minting an installation token.
+#[derive(thiserror::Error, Debug)]
+pub enum AppError {
+    #[error("Could not read the App private key: {0}")]
+    ReadKey(#[from] std::io::Error),
+    #[error("Invalid App private key or JWT: {0}")]
+    Jwt(#[from] jsonwebtoken::errors::Error),
+    #[error("Could not request an installation token: {0}")]
+    Request(#[from] Box<ureq::Error>),
+    #[error("Invalid installation token response: {0}")]
+    Response(String),
+}
+
+/// A GitHub App, able to authenticate as any of its installations.
+pub struct App {
+    id: String,
+    key: EncodingKey,
+    api_url: String,
+}
+
+impl App {
+    /// The App configured in the environment, if any.
+    pub fn from_env() -> Option<Result<Self, AppError>> {
+        let id = env::var(APP_ID_STRING).ok()?;
+        let key_path = env::var(APP_PRIVATE_KEY_STRING).ok()?;
+        let api_url = env::var(API_URL_STRING).unwrap_or_else(|_| DEFAULT_API_URL.to_owned());
+
+        Some(
+            fs::read(key_path)
+                .map_err(AppError::from)
+                .and_then(|pem| Ok(EncodingKey::from_rsa_pem(&pem)?))
+                .map(|key| Self { id, key, api_url }),
+        )
+    }
+
+    /// A short lived JWT authenticating as the App itself.
+    fn jwt(&self) -> Result<String, AppError> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        // Backdated to allow for clock drift, as recommended by GitHub
+        let claims = serde_json::json!({
+            "iat": now - 60,
+            "exp": now + 9 * 60,
+            "iss": self.id,
+        });
+
+        Ok(jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(Algorithm::RS256),
+            &claims,
+            &self.key,
+        )?)
+    }
+
+    /// Requests a new access token for the installation.
+    fn request_token(&self, installation: u64) -> Result<InstallationToken, AppError> {
+        let url = format!(
+            "{}/app/installations/{installation}/access_tokens",
+            self.api_url.trim_end_matches('/')
+        );
+        let response: serde_json::Value = ureq::post(&url)
+            .set("Authorization", &format!("Bearer {}", self.jwt()?))
+            .set("Accept", "application/vnd.github+json")
+            .call()
+            .map_err(Box::new)?
+            .into_json()
+            .map_err(|err| AppError::Response(err.to_string()))?;
+
+        let (Some(token), Some(expires_at)) =
+            (response["token"].as_str(), response["expires_at"].as_str())
+        else {
+            return Err(AppError::Response(
+                "missing \"token\" or \"expires_at\"".to_owned(),
+            ));
+        };
+
+        Ok(InstallationToken {
+            token: token.to_owned(),
+            expires_at: OffsetDateTime::parse(expires_at, &Rfc3339)
+                .map_err(|err| AppError::Response(err.to_string()))?,
+        })
+    }
+}
+
+/// An access token scoped to a single installation of the App.
+#[derive(Clone)]
+struct InstallationToken {
+    token: String,
+    expires_at: OffsetDateTime,
+}
+
+/// The installation tokens minted so far, reused until they are about to expire.
+#[derive(Default)]
+pub struct InstallationTokens {
+    cache: Mutex<HashMap<u64, InstallationToken>>,
+}
+
+impl InstallationTokens {
+    /// An access token for the installation, minting a new one if none is cached or the cached one
+    /// is about to expire.
+    ///
+    /// This blocks on the request to GitHub.
+    pub fn token(&self, app: &App, installation: u64) -> Result<String, AppError> {
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(cached) = cache.get(&installation) {
+            if cached.expires_at - REFRESH_MARGIN > OffsetDateTime::now_utc() {
+                return Ok(cached.token.clone());
+            }
+        }
+
+        let minted = app.request_token(installation)?;
+        cache.insert(installation, minted.clone());
+        Ok(minted.token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An App talking to the mock server.
+    fn app(server: &mockito::Server) -> App {
+        let pem = fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/test_data/app_key.pem"
+        ))
+        .expect("Able to read test key");
+        App {
+            id: "1234".to_owned(),
+            key: EncodingKey::from_rsa_pem(&pem).expect("Valid test key"),
+            api_url: server.url(),
+        }
+    }
+
+    /// Serves tokens for installation 42 that expire after the given duration.
+    fn mock_tokens(server: &mut mockito::Server, expires_in: Duration) -> mockito::Mock {
+        let expires_at = (OffsetDateTime::now_utc() + expires_in)
+            .format(&Rfc3339)
+            .unwrap();
+        server
+            .mock("POST", "/app/installations/42/access_tokens")
+            .match_header(
+                "Authorization",
+                mockito::Matcher::Regex("^Bearer .+".into()),
+            )
+            .with_status(201)
+            .with_body(
+                serde_json::json!({ "token": "ghs_test", "expires_at": expires_at }).to_string(),
+            )
+    }
+
+    #[test]
+    fn tokens_cached_until_expiry() {
+        let mut server = mockito::Server::new();
+        let mock = mock_tokens(&mut server, Duration::hours(1))
+            .expect(1)
+            .create();
+        let app = app(&server);
+        let tokens = InstallationTokens::default();
+
+        assert_eq!(tokens.token(&app, 42).unwrap(), "ghs_test");
+        assert_eq!(tokens.token(&app, 42).unwrap(), "ghs_test");
+        mock.assert();
+    }
+
+    #[test]
+    fn tokens_refreshed_near_expiry() {
+        let mut server = mockito::Server::new();
+        let mock = mock_tokens(&mut server, Duration::seconds(30))
+            .expect(2)
+            .create();
+        let app = app(&server);
+        let tokens = InstallationTokens::default();
+
+        tokens.token(&app, 42).unwrap();
+        tokens.token(&app, 42).unwrap();
+        mock.assert();
+
+        // Other installations are never handed the token
+        assert!(matches!(tokens.token(&app, 7), Err(AppError::Request(_))));
+    }
+}
@@ -0,0 +1,208 @@
+//! Recording deliveries in a local SQLite database, for durable & queryable history.
+
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    env,
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use rocket::{get, http::Status, serde::json::Json};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::dashboard::Admin;
+
+/// The string for the environment variable containing the path to the SQLite database deliveries
+/// are recorded in.
+pub const HISTORY_DB_STRING: &str = "WEBHOOK_HISTORY_DB";
+
+/// The connection to each database deliveries are recorded in, by its path.
+static CONNECTIONS: Mutex<BTreeMap<String, Connection>> = Mutex::new(BTreeMap::new());
+
+/// The number of deliveries served by `/history` unless asked for another number.
+const DEFAULT_LIMIT: u32 = 50;
+/// The most deliveries served by `/history` at once.
+const MAX_LIMIT: u32 = 500;
+
+/// A delivery as recorded in the history.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Delivery {
+    /// The id GitHub sent the delivery with, if any.
+    pub id: Option<String>,
+    pub event: Option<String>,
+    /// The full name of the repository the delivery was about, if known.
+    pub repo: Option<String>,
+    /// When the delivery was responded to, in RFC 3339 format.
+    pub time: String,
+    /// The status the delivery was responded to with.
+    pub outcome: u16,
+    pub duration_ms: i64,
+}
+
+impl Delivery {
+    /// A delivery of the payload that has just been responded to.
+    pub fn new(
+        id: Option<&str>,
+        event: Option<&str>,
+        payload: &[u8],
+        outcome: Status,
+        duration: Duration,
+    ) -> Self {
+        let repo = serde_json::from_slice::<serde_json::Value>(payload)
+            .ok()
+            .and_then(|payload| {
+                payload["repository"]["full_name"]
+                    .as_str()
+                    .map(str::to_owned)
+            });
+        Self {
+            id: id.map(str::to_owned),
+            event: event.map(str::to_owned),
+            repo,
+            time: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            outcome: outcome.code,
+            duration_ms: duration.as_millis() as i64,
+        }
+    }
+}
+
+/// Opens the database at the path, creating the table deliveries are recorded in if it's new.
+///
+/// Called at startup, so the first delivery isn't held up by it.
+pub fn init(path: &str) -> rusqlite::Result<()> {
+    with_connection(path, |_| Ok(()))
+}
+
+/// Runs the query over the connection to the database at the path, opening it the first time.
+fn with_connection<T>(
+    path: &str,
+    query: impl FnOnce(&Connection) -> rusqlite::Result<T>,
+) -> rusqlite::Result<T> {
+    let mut connections = CONNECTIONS.lock().unwrap_or_else(PoisonError::into_inner);
+    let connection = match connections.entry(path.to_owned()) {
+        Entry::Occupied(connection) => connection.into_mut(),
+        Entry::Vacant(entry) => entry.insert(open(path)?),
+    };
+    query(connection)
+}
+
+/// Opens the database, creating the table deliveries are recorded in if it's new.
+fn open(path: &str) -> rusqlite::Result<Connection> {
+    let connection = Connection::open(path)?;
+    // Other processes may be using the database too
+    connection.busy_timeout(Duration::from_secs(5))?;
+    connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS deliveries (
+            id TEXT,
+            event TEXT,
+            repo TEXT,
+            time TEXT NOT NULL,
+            outcome INTEGER NOT NULL,
+            duration_ms INTEGER NOT NULL
+        )",
+    )?;
+    Ok(connection)
+}
+
+/// Records the delivery in the database at the path.
+///
+/// This blocks on writing to the database, and on any other delivery being recorded in it.
+pub fn record(path: &str, delivery: &Delivery) {
+    let recorded = with_connection(path, |connection| {
+        connection.execute(
+            "INSERT INTO deliveries (id, event, repo, time, outcome, duration_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                delivery.id,
+                delivery.event,
+                delivery.repo,
+                delivery.time,
+                delivery.outcome,
+                delivery.duration_ms,
+            ],
+        )
+    });
+    if let Err(err) = recorded {
+        tracing::warn!("Could not record the delivery in the history: {err}");
+    }
+}
+
+/// The most recent deliveries, newest first.
+fn recent(path: &str, limit: u32) -> rusqlite::Result<Vec<Delivery>> {
+    with_connection(path, |connection| {
+        let mut query = connection.prepare(
+            "SELECT id, event, repo, time, outcome, duration_ms FROM deliveries
+             ORDER BY rowid DESC LIMIT ?1",
+        )?;
+        let deliveries = query.query_map([limit], |row| {
+            Ok(Delivery {
+                id: row.get(0)?,
+                event: row.get(1)?,
+                repo: row.get(2)?,
+                time: row.get(3)?,
+                outcome: row.get(4)?,
+                duration_ms: row.get(5)?,
+            })
+        })?;
+        deliveries.collect()
+    })
+}
+
+/// The most recent deliveries recorded in the history, newest first.
+///
+/// Only served when a database is configured.
+#[get("/history?<limit>")]
+pub fn history(_admin: Admin, limit: Option<u32>) -> Result<Option<Json<Vec<Delivery>>>, Status> {
+    let Ok(path) = env::var(HISTORY_DB_STRING) else {
+        return Ok(None);
+    };
+
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    match rocket::tokio::task::block_in_place(|| recent(&path, limit)) {
+        Ok(deliveries) => Ok(Some(Json(deliveries))),
+        Err(err) => {
+            tracing::error!("Could not read the history: {err}");
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorded_newest_first() {
+        let temp_dir = tempdir::TempDir::new("webhook_handler-temp").unwrap();
+        let path = temp_dir.path().join("history.db");
+        let path = path.to_str().expect("Valid Path");
+
+        let first = Delivery::new(
+            Some("1"),
+            Some("push"),
+            br#"{"repository": {"full_name": "octo/app"}}"#,
+            Status::Ok,
+            Duration::from_millis(12),
+        );
+        assert_eq!(first.repo.as_deref(), Some("octo/app"));
+        let second = Delivery::new(None, None, b"{", Status::Unauthorized, Duration::ZERO);
+        record(path, &first);
+        record(path, &second);
+
+        assert_eq!(recent(path, 10).unwrap(), [second.clone(), first]);
+        assert_eq!(recent(path, 1).unwrap(), [second]);
+    }
+
+    #[test]
+    fn connection_reused() {
+        // In-memory databases only last as long as their connection
+        init(":memory:").unwrap();
+        let delivery = Delivery::new(None, None, b"{}", Status::Ok, Duration::ZERO);
+        record(":memory:", &delivery);
+        assert_eq!(recent(":memory:", 10).unwrap(), [delivery]);
+    }
+}
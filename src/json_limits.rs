@@ -0,0 +1,94 @@
+//! Rejecting pathological json before it is parsed, so it can't exhaust memory or the stack.
+
+/// The string for the environment variable containing the deepest nesting of arrays & objects
+/// allowed in payloads.
+pub const MAX_DEPTH_STRING: &str = "WEBHOOK_JSON_MAX_DEPTH";
+/// The string for the environment variable containing the most array items & object members
+/// allowed in payloads, counted across every level of nesting.
+pub const MAX_ELEMENTS_STRING: &str = "WEBHOOK_JSON_MAX_ELEMENTS";
+
+/// The possible limits a payload can exceed.
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum LimitError {
+    #[error("The payload is nested more than {0} levels deep.")]
+    Depth(usize),
+    #[error("The payload has more than {0} elements.")]
+    Elements(usize),
+}
+
+/// Checks the json is within the limits, without parsing it.
+///
+/// Only the structure is scanned, so malformed json is left for the parser to reject.
+pub fn check(json: &[u8], max_depth: usize, max_elements: usize) -> Result<(), LimitError> {
+    // Whether each open array or object has had an element started since its last comma
+    let mut open: Vec<bool> = Vec::new();
+    let mut elements = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    let mut start_element = |open: &mut Vec<bool>| -> Result<(), LimitError> {
+        match open.last_mut() {
+            Some(started @ false) => {
+                *started = true;
+                elements += 1;
+                match elements > max_elements {
+                    true => Err(LimitError::Elements(max_elements)),
+                    false => Ok(()),
+                }
+            }
+            _ => Ok(()),
+        }
+    };
+
+    for &byte in json {
+        if in_string {
+            match (escaped, byte) {
+                (true, _) => escaped = false,
+                (false, b'\\') => escaped = true,
+                (false, b'"') => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match byte {
+            b'{' | b'[' => {
+                start_element(&mut open)?;
+                if open.len() >= max_depth {
+                    return Err(LimitError::Depth(max_depth));
+                }
+                open.push(false);
+            }
+            b'}' | b']' => {
+                open.pop();
+            }
+            b',' => {
+                if let Some(started) = open.last_mut() {
+                    *started = false;
+                }
+            }
+            b'"' => {
+                start_element(&mut open)?;
+                in_string = true;
+            }
+            // Values after an object's key belong to the same member
+            b':' | b' ' | b'\t' | b'\n' | b'\r' => {}
+            _ => start_element(&mut open)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_structure() {
+        let json = br#"{"a": [1, 2, {"b": "[not, nested]"}], "c\"": true}"#;
+        assert_eq!(check(json, 3, 6), Ok(()));
+        assert_eq!(check(json, 2, 6), Err(LimitError::Depth(2)));
+        assert_eq!(check(json, 3, 5), Err(LimitError::Elements(5)));
+        assert_eq!(check(b"[]", 1, 0), Ok(()));
+    }
+}
@@ -0,0 +1,84 @@
+//! Keeping hold of the most recent error logged by any part of the handler, for quick diagnosis.
+
+use std::{
+    fmt::Debug,
+    sync::{Mutex, PoisonError},
+};
+
+use rocket::{get, serde::json::Json};
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::dashboard::Admin;
+
+/// The most recent error logged, if any has been.
+static LAST_ERROR: Mutex<Option<LastError>> = Mutex::new(None);
+
+/// An error logged by the handler.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LastError {
+    /// When the error was logged, in RFC 3339 format.
+    pub time: String,
+    /// The module the error was logged from, such as "script".
+    pub subsystem: String,
+    pub message: String,
+}
+
+/// Records every error logged as the most recent one.
+pub struct Recorder;
+
+impl<S: Subscriber> Layer<S> for Recorder {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::ERROR {
+            return;
+        }
+
+        let mut message = MessageVisitor(String::new());
+        event.record(&mut message);
+        let module = event.metadata().module_path().unwrap_or_default();
+        let subsystem = match module.split_once("::") {
+            Some((_, subsystem)) => subsystem,
+            None => "handler",
+        };
+
+        *LAST_ERROR.lock().unwrap_or_else(PoisonError::into_inner) = Some(LastError {
+            time: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            subsystem: subsystem.to_owned(),
+            message: message.0,
+        });
+    }
+}
+
+/// Reads the message of an event.
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.0 = value.to_owned();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{value:?}");
+        }
+    }
+}
+
+/// The most recent error logged by the handler, if any has been.
+#[get("/last-error")]
+pub fn last_error(_admin: Admin) -> Option<Json<LastError>> {
+    LAST_ERROR
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+        .map(Json)
+}
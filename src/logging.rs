@@ -0,0 +1,378 @@
+//! Setting up where logs are written to.
+
+use std::{
+    env,
+    fmt::{Debug, Write as _},
+    io::Write as _,
+    sync::Arc,
+};
+
+use syslog_tracing::{Facility, Options, Syslog};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Subscriber,
+};
+use tracing_subscriber::{
+    filter::{DynFilterFn, LevelFilter},
+    fmt::MakeWriter,
+    layer::{Context, SubscriberExt},
+    registry::{LookupSpan, SpanRef},
+    util::SubscriberInitExt,
+    Layer,
+};
+
+/// The string for the environment variable selecting where logs are written to.
+pub const LOG_TARGET_STRING: &str = "WEBHOOK_LOG_TARGET";
+/// The string for the environment variable containing the syslog facility to log under.
+pub const SYSLOG_FACILITY_STRING: &str = "WEBHOOK_SYSLOG_FACILITY";
+/// The string for the environment variable enabling holding back the logs of each delivery unless
+/// it fails.
+pub const BUFFER_DELIVERIES_STRING: &str = "WEBHOOK_LOG_BUFFER_DELIVERIES";
+
+/// The name of the span covering the handling of a delivery.
+pub const DELIVERY_SPAN: &str = "delivery";
+/// The field recorded as `true` on the delivery span once the delivery has failed.
+pub const FAILED_FIELD: &str = "failed";
+
+/// Where logs are written to.
+#[derive(Debug)]
+pub enum LogTarget {
+    Stdout,
+    Syslog(Facility),
+}
+
+/// The possible errors when setting up logging.
+#[derive(thiserror::Error, Debug)]
+pub enum LoggingError {
+    #[error("Unknown log target \"{0}\", expected \"stdout\" or \"syslog\".")]
+    UnknownTarget(String),
+    #[error("Unknown syslog facility \"{0}\".")]
+    UnknownFacility(String),
+    #[error("A syslog logger has already been opened.")]
+    SyslogInUse,
+}
+
+/// Selects the log target from the names of the target & syslog facility.
+///
+/// Logs are written to stdout unless otherwise specified, and syslog defaults to the "daemon"
+/// facility.
+pub fn select(target: Option<&str>, facility: Option<&str>) -> Result<LogTarget, LoggingError> {
+    match target.map(str::to_lowercase).as_deref() {
+        None | Some("stdout") => Ok(LogTarget::Stdout),
+        Some("syslog") => Ok(LogTarget::Syslog(match facility {
+            Some(facility) => parse_facility(facility)?,
+            None => Facility::Daemon,
+        })),
+        Some(_) => Err(LoggingError::UnknownTarget(
+            target.unwrap_or_default().to_owned(),
+        )),
+    }
+}
+
+/// Parses the name of a syslog facility.
+fn parse_facility(name: &str) -> Result<Facility, LoggingError> {
+    Ok(match name.to_lowercase().as_str() {
+        "user" => Facility::User,
+        "mail" => Facility::Mail,
+        "daemon" => Facility::Daemon,
+        "auth" => Facility::Auth,
+        "lpr" => Facility::Lpr,
+        "news" => Facility::News,
+        "uucp" => Facility::Uucp,
+        "cron" => Facility::Cron,
+        "ftp" => Facility::Ftp,
+        "local0" => Facility::Local0,
+        "local1" => Facility::Local1,
+        "local2" => Facility::Local2,
+        "local3" => Facility::Local3,
+        "local4" => Facility::Local4,
+        "local5" => Facility::Local5,
+        "local6" => Facility::Local6,
+        "local7" => Facility::Local7,
+        _ => return Err(LoggingError::UnknownFacility(name.to_owned())),
+    })
+}
+
+/// Installs the global subscriber, writing to the target selected in the environment.
+///
+/// Rocket's own logs are forwarded to the same target.
+pub fn init() -> Result<(), LoggingError> {
+    let target = select(
+        env::var(LOG_TARGET_STRING).ok().as_deref(),
+        env::var(SYSLOG_FACILITY_STRING).ok().as_deref(),
+    )?;
+
+    match target {
+        LogTarget::Stdout => subscriber(std::io::stdout, false).init(),
+        LogTarget::Syslog(facility) => {
+            let syslog = Syslog::new(c"webhook_handler", Options::LOG_PID, facility)
+                .ok_or(LoggingError::SyslogInUse)?;
+            subscriber(syslog, true).init()
+        }
+    }
+
+    Ok(())
+}
+
+/// The subscriber writing to the writer, set up as configured in the environment.
+///
+/// Syslog records its own timestamps, so they are left out when writing to it.
+pub(crate) fn subscriber<W>(writer: W, syslog: bool) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    // Every subscriber keeps hold of the last error, so it can be checked without reading the logs
+    let recorder = crate::last_error::Recorder;
+    match (crate::env_flag(BUFFER_DELIVERIES_STRING), syslog) {
+        (true, syslog) => Box::new(buffered_subscriber(writer, syslog).with(recorder)),
+        (false, false) => Box::new(
+            tracing_subscriber::fmt()
+                .with_writer(writer)
+                .finish()
+                .with(recorder),
+        ),
+        (false, true) => Box::new(
+            tracing_subscriber::fmt()
+                .with_writer(writer)
+                .with_ansi(false)
+                .without_time()
+                .finish()
+                .with(recorder),
+        ),
+    }
+}
+
+/// A subscriber writing to the writer, except for the logs of successful deliveries.
+fn buffered_subscriber<W>(writer: W, syslog: bool) -> impl Subscriber + Send + Sync
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let writer = Arc::new(writer);
+    let shared = SharedWriter(writer.clone());
+    let fmt = match syslog {
+        true => tracing_subscriber::fmt::layer()
+            .with_writer(shared)
+            .with_ansi(false)
+            .without_time()
+            .boxed(),
+        false => tracing_subscriber::fmt::layer().with_writer(shared).boxed(),
+    };
+
+    // Delivery events are only written out by the buffer, once it has failed
+    let outside_deliveries = DynFilterFn::new(|metadata, context| {
+        let in_delivery = context
+            .lookup_current()
+            .is_some_and(|span| delivery_span(span).is_some());
+        !metadata.is_event() || !in_delivery
+    });
+
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(fmt.with_filter(outside_deliveries))
+        .with(DeliveryBuffer {
+            writer: SharedWriter(writer),
+            with_time: !syslog,
+        })
+}
+
+/// Lets a writer be shared between layers.
+struct SharedWriter<W>(Arc<W>);
+
+impl<'a, W: MakeWriter<'a>> MakeWriter<'a> for SharedWriter<W> {
+    type Writer = W::Writer;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.0.make_writer()
+    }
+}
+
+/// The span of the delivery the span is part of, if any.
+fn delivery_span<'a, S: LookupSpan<'a>>(span: SpanRef<'a, S>) -> Option<SpanRef<'a, S>> {
+    span.scope().find(|span| span.name() == DELIVERY_SPAN)
+}
+
+/// The logs of a delivery held back until it is known whether it failed.
+#[derive(Default)]
+struct DeliveryLogs {
+    lines: Vec<String>,
+    failed: bool,
+}
+
+/// Holds back the logs of each delivery, only writing them out if the delivery fails.
+struct DeliveryBuffer<W> {
+    writer: W,
+    with_time: bool,
+}
+
+impl<S, W> Layer<S> for DeliveryBuffer<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + 'static,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if let (DELIVERY_SPAN, Some(span)) = (attrs.metadata().name(), ctx.span(id)) {
+            span.extensions_mut().insert(DeliveryLogs::default());
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let mut failed = FailedVisitor(false);
+        values.record(&mut failed);
+
+        if let (true, Some(span)) = (failed.0, ctx.span(id)) {
+            if let Some(logs) = span.extensions_mut().get_mut::<DeliveryLogs>() {
+                logs.failed = true;
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.event_span(event).and_then(delivery_span) else {
+            return;
+        };
+
+        let metadata = event.metadata();
+        let mut line = String::new();
+        if self.with_time {
+            if let Ok(now) = time::OffsetDateTime::now_utc()
+                .format(&time::format_description::well_known::Rfc3339)
+            {
+                let _ = write!(line, "{now} ");
+            }
+        }
+        let _ = write!(line, "{:>5} {}:", metadata.level(), metadata.target());
+        event.record(&mut LineVisitor(&mut line));
+        line.push('\n');
+
+        let mut extensions = span.extensions_mut();
+        if let Some(logs) = extensions.get_mut::<DeliveryLogs>() {
+            logs.lines.push(line);
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(logs) = span.extensions_mut().remove::<DeliveryLogs>() else {
+            return;
+        };
+
+        if logs.failed {
+            for line in logs.lines {
+                // Nowhere left to report failing to log to
+                let _ = self.writer.make_writer().write_all(line.as_bytes());
+            }
+        }
+    }
+}
+
+/// Finds whether the failed field was recorded as `true`.
+struct FailedVisitor(bool);
+
+impl Visit for FailedVisitor {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == FAILED_FIELD {
+            self.0 = value;
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn Debug) {}
+}
+
+/// Appends the fields of an event to a log line.
+struct LineVisitor<'a>(&'a mut String);
+
+impl Visit for LineVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => {
+                let _ = write!(self.0, " {value}");
+            }
+            name => {
+                let _ = write!(self.0, " {name}={value:?}");
+            }
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        match field.name() {
+            "message" => {
+                let _ = write!(self.0, " {value:?}");
+            }
+            name => {
+                let _ = write!(self.0, " {name}={value:?}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A writer capturing everything written to it.
+    #[derive(Clone, Default)]
+    pub(crate) struct Captured(pub Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn delivery_buffering() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = buffered_subscriber(move || writer.clone(), true);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("Outside of any delivery");
+
+            let span = tracing::info_span!(DELIVERY_SPAN, failed = tracing::field::Empty);
+            span.in_scope(|| tracing::info!("Successful delivery"));
+            drop(span);
+
+            let span = tracing::info_span!(DELIVERY_SPAN, failed = tracing::field::Empty);
+            span.in_scope(|| tracing::warn!("Failed delivery"));
+            span.record(FAILED_FIELD, true);
+            drop(span);
+        });
+
+        let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("Outside of any delivery"));
+        assert!(!logs.contains("Successful delivery"));
+        assert!(logs.contains("Failed delivery"));
+    }
+
+    #[test]
+    fn target_selection() {
+        assert!(matches!(select(None, None), Ok(LogTarget::Stdout)));
+        assert!(matches!(
+            select(Some("stdout"), Some("local3")),
+            Ok(LogTarget::Stdout)
+        ));
+        assert!(matches!(
+            select(Some("SysLog"), None),
+            Ok(LogTarget::Syslog(Facility::Daemon))
+        ));
+        assert!(matches!(
+            select(Some("syslog"), Some("local3")),
+            Ok(LogTarget::Syslog(Facility::Local3))
+        ));
+        assert!(matches!(
+            select(Some("syslog"), Some("kernel")),
+            Err(LoggingError::UnknownFacility(_))
+        ));
+        assert!(matches!(
+            select(Some("journal"), None),
+            Err(LoggingError::UnknownTarget(_))
+        ));
+    }
+}
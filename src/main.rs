@@ -1,275 +1,6243 @@
-use std::{env, net::Ipv4Addr, path::PathBuf, process::Command};
+use std::{
+    env,
+    ffi::OsStr,
+    fmt::Display,
+    future::Future,
+    panic::AssertUnwindSafe,
+    path::PathBuf,
+    process::{Command, Stdio},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use hmac::{digest::MacError, Mac};
+use auth::{AuthError, Authenticated, MultiSignatureError, SignatureError};
+use batch::Batches;
+use dashboard::RecentDeliveries;
+use detached::PendingDeliveries;
+use github_app::InstallationTokens;
+use hmac::digest::Digest;
+use result_cache::{ResultCache, RESULT_CACHE_STRING};
 use rocket::{
+    config::CipherSuite,
     data::{Limits, ToByteUnit},
-    get,
-    http::Status,
-    launch, post,
+    error::ErrorKind,
+    fairing::AdHoc,
+    futures::FutureExt,
+    get, head,
+    http::{uri::Origin, ContentType, Header, HeaderMap, Method, Status},
+    mtls::Certificate,
+    post,
     request::{FromRequest, Outcome},
-    routes, Config, Request,
+    response::{self, Responder},
+    routes,
+    serde::json::Json,
+    uri, Build, Config, Ignite, Request, Response, Rocket, Route, Shutdown, State,
 };
+use script::{JobStatus, Jobs, RetryPolicy};
+use tenant::Tenant;
+use tracing::{error, info, info_span, warn, Instrument, Span};
+
+mod auth;
+mod batch;
+mod connections;
+mod dashboard;
+mod detached;
+mod github_app;
+mod history;
+mod json_limits;
+mod last_error;
+mod logging;
+mod memory;
+mod metrics;
+mod pty;
+mod release;
+mod result_cache;
+mod script;
+mod shell_pool;
+mod statsd;
+mod tenant;
 
-/// The string for the environment variable containing the secret.
-const WEBHOOK_STRING: &str = "WEBHOOK_SECRET";
-/// The name of the header sent by GitHub generated from the secret and payload.
-const HEADER: &str = "X-Hub-Signature-256";
+/// The name of the header sent by GitHub containing the event that triggered the webhook.
+const EVENT_HEADER: &str = "X-GitHub-Event";
+/// The name of the header sent by GitHub containing the unique id of the delivery.
+const DELIVERY_HEADER: &str = "X-GitHub-Delivery";
+/// Whether to reject deliveries sent without an [`EVENT_HEADER`].
+const REQUIRE_EVENT_HEADER_STRING: &str = "WEBHOOK_REQUIRE_EVENT_HEADER";
+/// Whether to reject deliveries whose [`DELIVERY_HEADER`] isn't a UUID.
+const VALIDATE_DELIVERY_UUID_STRING: &str = "WEBHOOK_VALIDATE_DELIVERY_UUID";
+/// The regex that the head commit message of a push must match for the script to be run.
+const COMMIT_MESSAGE_REGEX_STRING: &str = "WEBHOOK_COMMIT_MESSAGE_REGEX";
+/// The template for the directory the script is run from, filled in from the payload.
+const WORKDIR_TEMPLATE_STRING: &str = "WEBHOOK_WORKDIR_TEMPLATE";
+/// Comma separated `header=secret` pairs, each of which must also hold a valid signature.
+const REQUIRE_ALL_SIGS_STRING: &str = "WEBHOOK_REQUIRE_ALL_SIGS";
+/// The seconds to wait for the script to finish before responding with its status.
+const RESPONSE_BUDGET_STRING: &str = "WEBHOOK_RESPONSE_BUDGET_SECS";
+/// Whether to log the script's stderr as part of the delivery, with `key=value` lines as fields.
+const PARSE_STDERR_STRING: &str = "WEBHOOK_PARSE_SCRIPT_STDERR";
+/// The path to the bash script run after every run of the deploy script, however it finished.
+const CLEANUP_SCRIPT_STRING: &str = "WEBHOOK_CLEANUP_SCRIPT";
+/// The environment variable the cleanup script is passed how the deploy script finished in,
+/// either "success", "failure" or "error" if it couldn't be run.
+const OUTCOME_ENV: &str = "WEBHOOK_OUTCOME";
+/// The environment variable the cleanup script is passed the exit code of the deploy script in,
+/// which is empty if it didn't exit with one.
+const EXIT_CODE_ENV: &str = "WEBHOOK_EXIT_CODE";
+/// The most files each script, of whatever kind, may have open at once.
+const NOFILE_LIMIT_STRING: &str = "WEBHOOK_SCRIPT_NOFILE_LIMIT";
+/// The number of times to run the script again after it fails.
+const SCRIPT_RETRIES_STRING: &str = "WEBHOOK_SCRIPT_RETRIES";
+/// Comma separated exit codes that the script is retried on, instead of any failure.
+const RETRY_EXIT_CODES_STRING: &str = "WEBHOOK_RETRY_EXIT_CODES";
+/// Comma separated `event=seconds` pairs overriding the response budget for those events.
+const EVENT_BUDGETS_STRING: &str = "WEBHOOK_EVENT_RESPONSE_BUDGETS";
+/// The most seconds a delivery may ask for the response to be held in [`BUDGET_HEADER`].
+const MAX_BUDGET_STRING: &str = "WEBHOOK_MAX_RESPONSE_BUDGET_SECS";
+/// The name of the header a delivery may override the response budget with.
+const BUDGET_HEADER: &str = "X-Response-Budget";
+/// Whether to include the SHA-256 digest of the payload in successful responses.
+const PAYLOAD_DIGEST_STRING: &str = "WEBHOOK_EXPOSE_PAYLOAD_SHA256";
+/// The name of the header containing the SHA-256 digest of the processed payload.
+const PAYLOAD_DIGEST_HEADER: &str = "X-Payload-SHA256";
+/// Comma separated `branch=script` pairs, selecting the script to run for pushes to a branch.
+const BRANCH_SCRIPTS_STRING: &str = "WEBHOOK_BRANCH_SCRIPTS";
+/// The path to the script run for installation events, instead of the deploy script.
+const MANAGEMENT_SCRIPT_STRING: &str = "WEBHOOK_MANAGEMENT_SCRIPT";
+/// The environment variable the management script is passed the action of the event in.
+const ACTION_ENV: &str = "WEBHOOK_ACTION";
+/// The environment variable the management script is passed the newline separated names of the
+/// repos added to the installation in.
+const REPOS_ADDED_ENV: &str = "WEBHOOK_REPOS_ADDED";
+/// The environment variable the management script is passed the newline separated names of the
+/// repos removed from the installation in.
+const REPOS_REMOVED_ENV: &str = "WEBHOOK_REPOS_REMOVED";
+/// A `header=value` pair marking monitoring pings, which are answered without running the script.
+const HEALTH_PING_STRING: &str = "WEBHOOK_HEALTH_PING";
+/// The name of the environment variable to pass the script all of the request headers in, as a
+/// json object.
+const HEADERS_ENV_STRING: &str = "WEBHOOK_HEADERS_ENV";
+/// Whether to log the environment variables set for the script, with secrets redacted.
+const LOG_SCRIPT_ENV_STRING: &str = "WEBHOOK_LOG_SCRIPT_ENV";
+/// Whether to redact the credentials from the headers passed to the script.
+const REDACT_HEADERS_STRING: &str = "WEBHOOK_HEADERS_REDACT";
+/// The string for the environment variable containing the name of the environment the handler
+/// deploys to, which deliveries are tagged with.
+const ENVIRONMENT_STRING: &str = "WEBHOOK_ENVIRONMENT";
+/// The environment variable the script is passed [`ENVIRONMENT_STRING`] in.
+const ENVIRONMENT_ENV: &str = "DEPLOY_ENVIRONMENT";
+/// The name of the environment variable to pass the script the payload in, as json.
+const PAYLOAD_ENV_STRING: &str = "WEBHOOK_PAYLOAD_ENV";
+/// Comma separated `name=pointer` pairs building the object passed to the script in place of the
+/// payload, from the fields of the payload at the json pointers.
+const PAYLOAD_TRANSFORM_STRING: &str = "WEBHOOK_PAYLOAD_TRANSFORM";
+/// Comma separated json pointers to fields of the payload that are replaced with
+/// [`REDACTED_VALUE`] once the signature has been checked.
+const REDACT_FIELDS_STRING: &str = "WEBHOOK_REDACT_FIELDS";
+/// What redacted fields of the payload are replaced with.
+const REDACTED_VALUE: &str = "***";
+/// The headers carrying credentials, redacted from those passed to the script when asked to be.
+const SENSITIVE_HEADERS: [&str; 6] = [
+    auth::HEADER,
+    "X-Hub-Signature",
+    auth::GITLAB_TOKEN_HEADER,
+    auth::STRIPE_SIGNATURE_HEADER,
+    "Authorization",
+    "Cookie",
+];
+/// The path to the script run for workflow job & run events, instead of the deploy script.
+const WORKFLOW_SCRIPT_STRING: &str = "WEBHOOK_WORKFLOW_SCRIPT";
+/// The environment variable the workflow script is passed the name of the job or run in.
+const WORKFLOW_NAME_ENV: &str = "WEBHOOK_WORKFLOW_NAME";
+/// The environment variable the workflow script is passed the status of the job or run in.
+const WORKFLOW_STATUS_ENV: &str = "WEBHOOK_WORKFLOW_STATUS";
+/// The environment variable the workflow script is passed the conclusion of the job or run in,
+/// which is empty until it completes.
+const WORKFLOW_CONCLUSION_ENV: &str = "WEBHOOK_WORKFLOW_CONCLUSION";
+/// The path to the script run for branch & tag creations, instead of the deploy script.
+const CREATE_SCRIPT_STRING: &str = "WEBHOOK_CREATE_SCRIPT";
+/// The path to the script run for branch & tag deletions, instead of the deploy script.
+const DELETE_SCRIPT_STRING: &str = "WEBHOOK_DELETE_SCRIPT";
+/// The environment variable the create & delete scripts are passed the kind of ref in, either
+/// "branch" or "tag".
+const REF_TYPE_ENV: &str = "WEBHOOK_REF_TYPE";
+/// The environment variable the create & delete scripts are passed the name of the ref in.
+const REF_ENV: &str = "WEBHOOK_REF";
+/// The path to the script run for pull request comments & reviews matching
+/// [`COMMENT_COMMAND_REGEX_STRING`], instead of the deploy script.
+const COMMENT_SCRIPT_STRING: &str = "WEBHOOK_COMMENT_SCRIPT";
+/// The regex that pull request comments & reviews must match for the comment script to be run.
+const COMMENT_COMMAND_REGEX_STRING: &str = "WEBHOOK_COMMENT_COMMAND_REGEX";
+/// The environment variable the comment script is passed the command in, being the regex's first
+/// capture group or the whole match if it has none.
+const COMMAND_ENV: &str = "WEBHOOK_COMMAND";
+/// The environment variable the comment script is passed the number of the pull request in.
+const PR_NUMBER_ENV: &str = "WEBHOOK_PR_NUMBER";
+/// Comma separated `event=script` pairs of scripts run all at once for the event, instead of the
+/// deploy script. Events are listed once for each of their scripts.
+const PARALLEL_SCRIPTS_STRING: &str = "WEBHOOK_PARALLEL_SCRIPTS";
+/// Comma separated `event=script` pairs of scripts run one after another for the event, in the
+/// order they are listed, instead of the deploy script.
+const SEQUENTIAL_SCRIPTS_STRING: &str = "WEBHOOK_SEQUENTIAL_SCRIPTS";
+/// The number of seconds to wait between each of the sequential scripts.
+const SCRIPT_STEP_DELAY_STRING: &str = "WEBHOOK_SCRIPT_STEP_DELAY_SECS";
+/// Whether the sequential scripts `stop` at the first that fails, or `continue` on with the rest.
+const MULTISCRIPT_MODE_STRING: &str = "WEBHOOK_MULTISCRIPT_MODE";
+/// The prefix the User-Agent of deliveries must start with, such as "GitHub-Hookshot/".
+const REQUIRE_UA_PREFIX_STRING: &str = "WEBHOOK_REQUIRE_UA_PREFIX";
+/// Whether to skip HMAC validation for clients presenting a verified mTLS certificate.
+const NO_HMAC_WHEN_MTLS_STRING: &str = "WEBHOOK_NO_HMAC_WHEN_MTLS";
+/// Whether to echo the processed event & action in successful responses.
+const ECHO_EVENT_STRING: &str = "WEBHOOK_ECHO_EVENT";
+/// The name of the header echoing the GitHub event that was processed.
+const ECHOED_EVENT_HEADER: &str = "X-Webhook-Event";
+/// The name of the header echoing the action of the event that was processed.
+const ECHOED_ACTION_HEADER: &str = "X-Webhook-Action";
+/// The seconds an idle keep-alive connection is held open for before being closed.
+const IDLE_TIMEOUT_STRING: &str = "WEBHOOK_IDLE_TIMEOUT_SECS";
+/// The minimum TLS version to accept connections over, either "1.2" or "1.3".
+const MIN_TLS_VERSION_STRING: &str = "WEBHOOK_MIN_TLS_VERSION";
+/// The path to the bash script to run at launch, before the handler is ready for deliveries.
+const WARMUP_SCRIPT_STRING: &str = "WEBHOOK_WARMUP_SCRIPT";
+/// Whether to shut down if the warmup script fails, rather than staying unready.
+const WARMUP_EXIT_STRING: &str = "WEBHOOK_WARMUP_EXIT_ON_FAILURE";
+/// The number of deliveries that must succeed before the handler reports itself as ready.
+const READY_AFTER_SUCCESSES_STRING: &str = "WEBHOOK_READY_AFTER_SUCCESSES";
+/// The megabytes that must be free on [`FREE_SPACE_PATH_STRING`] for the handler to be ready.
+const MIN_FREE_MB_STRING: &str = "WEBHOOK_MIN_FREE_MB";
+/// The path whose filesystem must have [`MIN_FREE_MB_STRING`] free, defaulting to the working
+/// directory.
+const FREE_SPACE_PATH_STRING: &str = "WEBHOOK_FREE_SPACE_PATH";
+/// The seconds between each heartbeat logged to show the handler is still alive.
+const HEARTBEAT_STRING: &str = "WEBHOOK_HEARTBEAT_SECS";
+/// Whether to log the configuration set in the environment at startup, with secrets redacted.
+const LOG_CONFIG_STRING: &str = "WEBHOOK_LOG_CONFIG";
+/// The configuration holding secrets, redacted wherever the configuration is shown.
+const SENSITIVE_CONFIG: [&str; 4] = [
+    auth::WEBHOOK_STRING,
+    REQUIRE_ALL_SIGS_STRING,
+    dashboard::ADMIN_TOKEN_STRING,
+    release::ASSETS_TOKEN_STRING,
+];
+/// The number of times to attempt binding the port before giving up.
+const BIND_ATTEMPTS_STRING: &str = "WEBHOOK_BIND_ATTEMPTS";
+/// The delay in milliseconds between attempts to bind the port.
+const BIND_RETRY_DELAY_STRING: &str = "WEBHOOK_BIND_RETRY_DELAY_MS";
+/// Whether to hide the landing page, so the handler doesn't reveal what it is.
+const DISABLE_LANDING_STRING: &str = "WEBHOOK_DISABLE_LANDING";
+/// The status to answer HEAD requests on the webhook path with, such as those from health checkers.
+const HEAD_STATUS_STRING: &str = "WEBHOOK_HEAD_STATUS";
+/// Whether to also route deliveries sent to `/webhook` to the handler, ignoring case & trailing
+/// slashes.
+const LENIENT_PATH_STRING: &str = "WEBHOOK_LENIENT_PATH";
+/// The path commonly configured for the webhook instead of the root.
+const LENIENT_ALIAS: &str = "/webhook";
+/// Whether to gzip the responses of [`COMPRESSED_PATHS`] for clients accepting it.
+const COMPRESS_RESPONSES_STRING: &str = "WEBHOOK_COMPRESS_RESPONSES";
+/// The paths whose responses can be large enough to be worth compressing.
+const COMPRESSED_PATHS: [&str; 3] = ["/metrics", "/dashboard", "/debug/env"];
+/// Whether to reject deliveries that weren't sent over HTTPS.
+const REQUIRE_HTTPS_STRING: &str = "WEBHOOK_REQUIRE_HTTPS";
+/// Comma separated addresses of the proxies trusted to report the original protocol in
+/// [`FORWARDED_PROTO_HEADER`].
+const TRUSTED_PROXIES_STRING: &str = "WEBHOOK_TRUSTED_PROXIES";
+/// The name of the header proxies report the protocol the request was originally sent over in.
+const FORWARDED_PROTO_HEADER: &str = "X-Forwarded-Proto";
+/// The name of the header sent by clients wishing to switch protocols.
+const UPGRADE_HEADER: &str = "Upgrade";
 /// The path to the bash script to get executed on a valid post.
 const SCRIPT_STRING: &str = "WEBHOOK_SCRIPT";
+/// Whether to refuse to start when deliveries couldn't be handled with the configuration.
+const STRICT_STARTUP_STRING: &str = "WEBHOOK_STRICT_STARTUP";
+
+/// Rejects any request attempting to upgrade the connection (e.g. a WebSocket handshake).
+///
+/// This is ranked ahead of every other route by [`upgrade_routes`], so that upgrade attempts on any
+/// path get a clear answer rather than whatever the matching route would have returned.
+#[get("/<_..>")]
+fn reject_upgrade(_upgrade: UpgradeRequest) -> (Status, &'static str) {
+    (
+        Status::BadRequest,
+        "WebSockets are not supported, this server only speaks plain HTTP.",
+    )
+}
+
+/// Rejects deliveries attempting to upgrade the connection, before their script is run.
+#[post("/<_..>")]
+fn reject_upgrade_delivery(upgrade: UpgradeRequest) -> (Status, &'static str) {
+    reject_upgrade(upgrade)
+}
+
+/// Rejects health pings attempting to upgrade the connection.
+#[head("/<_..>")]
+fn reject_upgrade_ping(upgrade: UpgradeRequest) -> (Status, &'static str) {
+    reject_upgrade(upgrade)
+}
+
+/// The routes rejecting upgrade attempts, ranked ahead of every other route for every method the
+/// other routes accept.
+fn upgrade_routes() -> Vec<Route> {
+    let mut routes = routes![reject_upgrade, reject_upgrade_delivery, reject_upgrade_ping];
+    for route in &mut routes {
+        // Lower than any rank Rocket gives routes by default
+        route.rank = -20;
+    }
+    routes
+}
+
+/// The landing page, which 404s like any unknown path when disabled.
+#[get("/", rank = 2)]
+fn listen() -> Option<String> {
+    if env_flag(DISABLE_LANDING_STRING) {
+        return None;
+    }
+
+    Some(
+        "Urm, hi?\nHow did you get here?\nThis is an api for computers 'n' stuff, not for humans :P"
+            .to_owned(),
+    )
+}
+
+/// Answers health checkers probing the webhook path, without running anything.
+///
+/// Without a configured status HEAD requests are answered like GET requests.
+#[head("/")]
+fn head_ping(ping: HeadPing) -> Status {
+    ping.status
+}
+
+// Each argument is a guard, so splitting them up would only obscure what the route needs
+#[allow(clippy::too_many_arguments)]
+#[post("/", format = "json", data = "<delivery>")]
+async fn webhook_listen(
+    _https: OverHttps,
+    certificate: Option<Certificate<'_>>,
+    event: Option<GitHubEvent<'_>>,
+    headers: RequestHeaders<'_>,
+    jobs: &State<Jobs>,
+    tokens: &State<InstallationTokens>,
+    batches: &State<Batches>,
+    readiness: &State<Readiness>,
+    pending: &State<PendingDeliveries>,
+    results: &State<ResultCache<WebhookResponse>>,
+    recent: &State<RecentDeliveries>,
+    limits: &State<tenant::RateLimits>,
+    tenant: Option<&Tenant>,
+    delivery: Authenticated,
+) -> WebhookResponse {
+    metrics::DELIVERIES.fetch_add(1, Ordering::Relaxed);
+    if let Some(tenant) = tenant {
+        metrics::tenant_delivery(&tenant.name);
+    }
+
+    if !readiness.is_ready() {
+        warn!("Delivery received before the warmup script succeeded");
+        return Status::ServiceUnavailable.into();
+    }
+
+    // Only authenticated deliveries are counted, so forged ones can't use up a tenant's limit
+    let authenticated = delivery.result.is_ok() || certificate.is_some();
+    let limit = env::var(tenant::TENANT_RATE_LIMIT_STRING);
+    if let (Some(tenant), Ok(limit), true) = (tenant, limit, authenticated) {
+        match limit.parse::<f64>() {
+            Ok(per_minute) if per_minute > 0.0 => {
+                if !limits.take(&tenant.name, per_minute) {
+                    warn!(
+                        "Tenant \"{}\" is sending deliveries too quickly",
+                        tenant.name
+                    );
+                    return Status::TooManyRequests.into();
+                }
+            }
+            _ => warn!(
+                "Invalid value for {}, ignoring it",
+                tenant::TENANT_RATE_LIMIT_STRING
+            ),
+        }
+    }
+
+    // Monitoring pings only confirm the handler is up, so never go near the script
+    if let Ok(marker) = env::var(HEALTH_PING_STRING) {
+        match is_health_ping(&marker, headers.headers) {
+            Ok(true) => return Status::Ok.into(),
+            Ok(false) => {}
+            Err(err) => {
+                error!("Invalid health ping marker: {err}");
+                return Status::InternalServerError.into();
+            }
+        }
+    }
+
+    // Deliveries can't be routed by their event without one
+    if env_flag(REQUIRE_EVENT_HEADER_STRING) && event.is_none() {
+        warn!("Delivery was sent without the \"{EVENT_HEADER}\" header");
+        return Status::BadRequest.into();
+    }
+
+    // GitHub always sends a UUID, so anything else likely didn't come from it
+    if env_flag(VALIDATE_DELIVERY_UUID_STRING) {
+        let id = headers.headers.get_one(DELIVERY_HEADER);
+        if !id.is_some_and(is_uuid) {
+            warn!("Delivery id {id:?} is not a UUID");
+            return Status::BadRequest.into();
+        }
+    }
+
+    // Deliveries signed in a separate request are held until the signature arrives
+    if let (Some(token), Err(AuthError::MissingHeader(_))) = (
+        headers.headers.get_one(detached::TOKEN_HEADER),
+        &delivery.result,
+    ) {
+        match env_seconds(detached::DETACHED_TIMEOUT_STRING) {
+            Ok(Some(timeout)) => {
+                let Authenticated {
+                    body, reservation, ..
+                } = delivery;
+                match pending.hold(token, body, headers.headers, tenant, reservation, timeout) {
+                    Ok(()) => {}
+                    Err(err @ detached::HoldError::TokenInUse(_)) => {
+                        warn!("{err}");
+                        return Status::Conflict.into();
+                    }
+                    Err(err @ detached::HoldError::Full) => {
+                        warn!("{err}");
+                        return Status::ServiceUnavailable.into();
+                    }
+                }
+
+                info!("Holding delivery until its signature is sent");
+                return Status::Accepted.into();
+            }
+            Ok(None) => {}
+            Err(err) => {
+                error!("{err}");
+                return Status::InternalServerError.into();
+            }
+        }
+    }
+
+    let received = Instant::now();
+    let delivery_id = headers.headers.get_one(DELIVERY_HEADER);
+    let Authenticated {
+        body: user_input,
+        result,
+        signed,
+        // Keeps the memory set aside until the body is dropped along with it
+        reservation: _reservation,
+    } = delivery;
+
+    // Idempotent deploys needn't be run again for repeats of the same delivery
+    let cache_ttl = match env_seconds(RESULT_CACHE_STRING) {
+        Ok(ttl) => ttl,
+        Err(err) => {
+            warn!("{err} Ignoring it");
+            None
+        }
+    };
+
+    // Lets the logs of successful deliveries be held back
+    let span = delivery_span();
+    let event_name = event.map(|event| event.event);
+    let mut response = catch_panics(deliver(
+        certificate,
+        event,
+        headers,
+        jobs,
+        tokens,
+        batches,
+        tenant,
+        &user_input,
+        signed.as_deref().unwrap_or(&user_input),
+        result,
+        cache_ttl.map(|_| results.inner()),
+    ))
+    .instrument(span.clone())
+    .await;
+    if !response.status.class().is_success() {
+        span.record(logging::FAILED_FIELD, true);
+    }
+    record_delivery(
+        readiness,
+        recent,
+        delivery_id,
+        event_name,
+        &user_input,
+        &response,
+        received.elapsed(),
+    );
+    if !response.status.class().is_success() || response.cached {
+        return response;
+    }
+
+    // Let clients confirm exactly what was processed
+    if env_flag(PAYLOAD_DIGEST_STRING) {
+        let digest = sha2::Sha256::digest(&user_input);
+        response
+            .headers
+            .push(Header::new(PAYLOAD_DIGEST_HEADER, hex::encode(digest)));
+    }
+
+    // Let proxies route & log by what was processed
+    if let (Some(GitHubEvent { event }), true) = (event, env_flag(ECHO_EVENT_STRING)) {
+        response
+            .headers
+            .push(Header::new(ECHOED_EVENT_HEADER, event.to_owned()));
+
+        // Successful deliveries have already been validated as json
+        if let Ok(PayloadAction {
+            action: Some(action),
+        }) = serde_json::from_slice(&user_input)
+        {
+            response
+                .headers
+                .push(Header::new(ECHOED_ACTION_HEADER, action));
+        }
+    }
+
+    if let Some(ttl) = cache_ttl {
+        results.insert(
+            tenant.map(|tenant| tenant.name.as_str()),
+            event_name,
+            &user_input,
+            response.clone(),
+            ttl,
+        );
+    }
+    response
+}
+
+/// Supplies the signature of a held delivery, running it if the signature is valid.
+///
+/// Held deliveries are released by the first attempt to sign them, whether or not it succeeds.
+#[post("/sign/<_>")]
+async fn sign_delivery(
+    _https: OverHttps,
+    jobs: &State<Jobs>,
+    tokens: &State<InstallationTokens>,
+    batches: &State<Batches>,
+    readiness: &State<Readiness>,
+    recent: &State<RecentDeliveries>,
+    signed: detached::Signed,
+) -> WebhookResponse {
+    let detached::Signed { held, result } = signed;
+
+    let received = Instant::now();
+    let event = held
+        .headers
+        .get_one(EVENT_HEADER)
+        .map(|event| GitHubEvent { event });
+    let span = delivery_span();
+    let response = catch_panics(deliver(
+        None,
+        event,
+        RequestHeaders {
+            headers: &held.headers,
+        },
+        jobs,
+        tokens,
+        batches,
+        held.tenant.as_ref(),
+        &held.body,
+        &held.body,
+        result,
+        None,
+    ))
+    .instrument(span.clone())
+    .await;
+    if !response.status.class().is_success() {
+        span.record(logging::FAILED_FIELD, true);
+    }
+    record_delivery(
+        readiness,
+        recent,
+        held.headers.get_one(DELIVERY_HEADER),
+        event.map(|event| event.event),
+        &held.body,
+        &response,
+        received.elapsed(),
+    );
+    response
+}
+
+/// Records how a delivery was responded to, whichever way it was received.
+fn record_delivery(
+    readiness: &Readiness,
+    recent: &RecentDeliveries,
+    delivery_id: Option<&str>,
+    event: Option<&str>,
+    payload: &[u8],
+    response: &WebhookResponse,
+    elapsed: Duration,
+) {
+    statsd::record(response.status, elapsed);
+    recent.record(event, response.status, elapsed);
+    if let Ok(path) = env::var(history::HISTORY_DB_STRING) {
+        let delivered =
+            history::Delivery::new(delivery_id, event, payload, response.status, elapsed);
+        rocket::tokio::task::block_in_place(|| history::record(&path, &delivered));
+    }
+
+    // Repeats answered from the cache weren't handled again
+    if response.status.class().is_success() && !response.cached {
+        readiness.successes.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The action of the event, present for events with several kinds of activity.
+#[derive(serde::Deserialize)]
+struct PayloadAction {
+    action: Option<String>,
+}
+
+/// Whether the id is a UUID in its hyphenated hexadecimal form.
+fn is_uuid(id: &str) -> bool {
+    let groups: Vec<_> = id.split('-').collect();
+    groups.iter().map(|group| group.len()).eq([8, 4, 4, 4, 12])
+        && groups
+            .iter()
+            .all(|group| group.bytes().all(|byte| byte.is_ascii_hexdigit()))
+}
+
+/// The span the handling of a delivery is logged within, tagged with the configured environment.
+fn delivery_span() -> Span {
+    info_span!(
+        logging::DELIVERY_SPAN,
+        failed = tracing::field::Empty,
+        environment = env::var(ENVIRONMENT_STRING).ok()
+    )
+}
+
+/// Fails the delivery cleanly if handling it panics, logging what went wrong.
+///
+/// Shared state is never left poisoned by a panic, so later deliveries carry on as normal.
+async fn catch_panics(delivery: impl Future<Output = WebhookResponse>) -> WebhookResponse {
+    match AssertUnwindSafe(delivery).catch_unwind().await {
+        Ok(response) => response,
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown cause");
+            error!("Handling the delivery panicked: {message}");
+            Status::InternalServerError.into()
+        }
+    }
+}
+
+/// Validates the delivery & runs the script for it.
+// The delivery is handed over piece by piece, as it's checked before its body is parsed
+#[allow(clippy::too_many_arguments)]
+async fn deliver(
+    certificate: Option<Certificate<'_>>,
+    event: Option<GitHubEvent<'_>>,
+    headers: RequestHeaders<'_>,
+    jobs: &State<Jobs>,
+    tokens: &State<InstallationTokens>,
+    batches: &State<Batches>,
+    tenant: Option<&Tenant>,
+    user_input: &[u8],
+    // What the signatures were made over, which may be the body before it was decompressed
+    signed: &[u8],
+    authenticated: Result<(), AuthError>,
+    results: Option<&ResultCache<WebhookResponse>>,
+) -> WebhookResponse {
+    // Sheds obvious junk cheaply, though any client can claim to be anyone
+    if let Ok(prefix) = env::var(REQUIRE_UA_PREFIX_STRING) {
+        let user_agent = headers.headers.get_one("User-Agent").unwrap_or_default();
+        if !user_agent.starts_with(&prefix) {
+            warn!("User-Agent \"{user_agent}\" does not start with \"{prefix}\"");
+            return Status::Forbidden.into();
+        }
+    }
+
+    // Clients with a verified certificate are already authenticated, if trusted to be
+    let skip_hmac = certificate.is_some() && env_flag(NO_HMAC_WHEN_MTLS_STRING);
+    if let (Err(err @ (AuthError::MissingHeader(_) | AuthError::DuplicateHeader(_))), false) =
+        (&authenticated, skip_hmac)
+    {
+        warn!("{err}");
+        return Status::BadRequest.into();
+    }
+
+    // Get script path, which is only optional when other deliveries have their own scripts
+    let default_path = match tenant {
+        Some(tenant) => Ok(tenant.script.clone()),
+        None => env::var(SCRIPT_STRING).map(PathBuf::from),
+    };
+    // Tenants only ever run their own script, so the handler's own routing isn't applied to them
+    let global = |key: &str| match tenant {
+        Some(_) => None,
+        None => env::var(key).ok(),
+    };
+    let branch_scripts = global(BRANCH_SCRIPTS_STRING);
+    let management_script = global(MANAGEMENT_SCRIPT_STRING);
+    let workflow_script = global(WORKFLOW_SCRIPT_STRING);
+    let create_script = global(CREATE_SCRIPT_STRING);
+    let delete_script = global(DELETE_SCRIPT_STRING);
+    let comment_script = global(COMMENT_SCRIPT_STRING);
+    let parallel_scripts = global(PARALLEL_SCRIPTS_STRING);
+    let sequential_scripts = global(SEQUENTIAL_SCRIPTS_STRING);
+    if let (Err(err), None, None, None, None, None, None, None, None) = (
+        &default_path,
+        &branch_scripts,
+        &management_script,
+        &workflow_script,
+        &create_script,
+        &delete_script,
+        &comment_script,
+        &parallel_scripts,
+        &sequential_scripts,
+    ) {
+        error!("Could not get script path from environment: {err}");
+        return Status::InternalServerError.into();
+    }
+
+    if !skip_hmac {
+        // Check if the delivery was sent by the provider holding the secret
+        match authenticated {
+            Ok(()) => {}
+            Err(err @ AuthError::Provider(_)) => {
+                error!("{err}");
+                return Status::InternalServerError.into();
+            }
+            Err(err @ AuthError::Signature(SignatureError::Oversized(_))) => {
+                warn!("{err}");
+                metrics::abuse("oversized_signature");
+                statsd::abuse("oversized_signature");
+                return Status::BadRequest.into();
+            }
+            Err(err) => {
+                warn!("{err}");
+                return Status::Unauthorized.into();
+            }
+        }
+    }
+
+    // Check any additional signatures required from independent keys, which a certificate
+    // doesn't stand in for
+    if let Some(pairs) = global(REQUIRE_ALL_SIGS_STRING) {
+        match auth::all_signatures_match(&pairs, headers.headers, signed) {
+            Ok(()) => {}
+            Err(err @ MultiSignatureError::MalformedPair(_)) => {
+                error!("{err}");
+                return Status::InternalServerError.into();
+            }
+            Err(err @ MultiSignatureError::DuplicateHeader(_)) => {
+                warn!("{err}");
+                return Status::BadRequest.into();
+            }
+            Err(err) => {
+                warn!("Signature Error: {err}");
+                return Status::Unauthorized.into();
+            }
+        }
+    }
+
+    // Looked up only now, so repeats have to pass every check the original delivery did
+    if let Some(results) = results {
+        let cached = results.get(
+            tenant.map(|tenant| tenant.name.as_str()),
+            event.map(|event| event.event),
+            user_input,
+        );
+        if let Some(cached) = cached {
+            info!("Identical delivery was already handled, reusing its result");
+            return WebhookResponse {
+                cached: true,
+                ..cached
+            };
+        }
+    }
+
+    // Checked before parsing, as parsing is what pathological payloads would exhaust
+    let limits = json_limits::check(
+        user_input,
+        env_or(json_limits::MAX_DEPTH_STRING, usize::MAX),
+        env_or(json_limits::MAX_ELEMENTS_STRING, usize::MAX),
+    );
+    match limits {
+        Ok(()) => {}
+        Err(err @ json_limits::LimitError::Depth(_)) => {
+            warn!("{err}");
+            return Status::UnprocessableEntity.into();
+        }
+        Err(err @ json_limits::LimitError::Elements(_)) => {
+            warn!("{err}");
+            return Status::PayloadTooLarge.into();
+        }
+    }
+
+    let mut payload: serde_json::Value = match serde_json::from_slice(user_input) {
+        Ok(payload) => payload,
+        Err(err) => {
+            warn!("Could not parse payload: {err}");
+            return Status::BadRequest.into();
+        }
+    };
+
+    // Scrubbed only now, as the signature is over the payload as it was sent
+    if let Ok(pointers) = env::var(REDACT_FIELDS_STRING) {
+        redact_fields(&mut payload, &pointers);
+    }
+
+    // Installation events update the repos being managed rather than deploying anything
+    if let (Some(GitHubEvent { event }), Some(script)) = (event, &management_script) {
+        if let Some((added, removed)) = installation_changes(event, &payload) {
+            let mut command = match script_command(script) {
+                Ok(command) => command,
+                Err(err) => {
+                    error!("Could not set up management script: {err}");
+                    return Status::InternalServerError.into();
+                }
+            };
+            command
+                .env(ACTION_ENV, payload["action"].as_str().unwrap_or_default())
+                .env(REPOS_ADDED_ENV, added.join("\n"))
+                .env(REPOS_REMOVED_ENV, removed.join("\n"));
+            return spawn_event_script(command, "management");
+        }
+    }
+
+    // Workflow events trigger follow-up actions rather than deploying anything
+    if let (
+        Some(GitHubEvent {
+            event: event @ ("workflow_job" | "workflow_run"),
+        }),
+        Some(script),
+    ) = (event, &workflow_script)
+    {
+        let workflow = &payload[event];
+        let field = |value: &serde_json::Value| value.as_str().unwrap_or_default().to_owned();
+
+        let mut command = match script_command(script) {
+            Ok(command) => command,
+            Err(err) => {
+                error!("Could not set up workflow script: {err}");
+                return Status::InternalServerError.into();
+            }
+        };
+        command
+            .env(ACTION_ENV, field(&payload["action"]))
+            .env(WORKFLOW_NAME_ENV, field(&workflow["name"]))
+            .env(WORKFLOW_STATUS_ENV, field(&workflow["status"]))
+            .env(WORKFLOW_CONCLUSION_ENV, field(&workflow["conclusion"]));
+        return spawn_event_script(command, "workflow");
+    }
+
+    // Created refs are provisioned & deleted refs torn down rather than deploying anything
+    let ref_script = match event {
+        Some(GitHubEvent { event: "create" }) => create_script.map(|script| (script, "create")),
+        Some(GitHubEvent { event: "delete" }) => delete_script.map(|script| (script, "delete")),
+        _ => None,
+    };
+    if let Some((script, kind)) = ref_script {
+        let field = |value: &serde_json::Value| value.as_str().unwrap_or_default().to_owned();
+
+        let mut command = match script_command(script) {
+            Ok(command) => command,
+            Err(err) => {
+                error!("Could not set up {kind} script: {err}");
+                return Status::InternalServerError.into();
+            }
+        };
+        command
+            .env(REF_TYPE_ENV, field(&payload["ref_type"]))
+            .env(REF_ENV, field(&payload["ref"]));
+        return spawn_event_script(command, kind);
+    }
+
+    // Commands left in pull request comments & reviews trigger actions rather than deploying
+    if let (Some(GitHubEvent { event }), Some(script)) = (event, &comment_script) {
+        if let Some((body, number)) = pull_request_comment(event, &payload) {
+            let Ok(pattern) = env::var(COMMENT_COMMAND_REGEX_STRING) else {
+                error!("{COMMENT_SCRIPT_STRING} is set without {COMMENT_COMMAND_REGEX_STRING}");
+                return Status::InternalServerError.into();
+            };
+            let pattern = match regex::Regex::new(&pattern) {
+                Ok(pattern) => pattern,
+                Err(err) => {
+                    error!("Invalid comment command regex: {err}");
+                    return Status::InternalServerError.into();
+                }
+            };
+
+            let Some(captures) = pattern.captures(body) else {
+                info!("Comment does not match the comment command regex, skipping");
+                return Status::Ok.into();
+            };
+            let command_text = captures
+                .get(1)
+                .or_else(|| captures.get(0))
+                .map_or("", |command| command.as_str());
+
+            let mut command = match script_command(script) {
+                Ok(command) => command,
+                Err(err) => {
+                    error!("Could not set up comment script: {err}");
+                    return Status::InternalServerError.into();
+                }
+            };
+            command
+                .env(COMMAND_ENV, command_text)
+                .env(PR_NUMBER_ENV, number.to_string());
+            return spawn_event_script(command, "comment");
+        }
+    }
+
+    // Only run scripts for pushes that opt in through their commit message
+    if let (Some(GitHubEvent { event: "push" }), Ok(pattern)) =
+        (event, env::var(COMMIT_MESSAGE_REGEX_STRING))
+    {
+        let pattern = match regex::Regex::new(&pattern) {
+            Ok(pattern) => pattern,
+            Err(err) => {
+                error!("Invalid commit message regex: {err}");
+                return Status::InternalServerError.into();
+            }
+        };
+
+        if !commit_message_matches(&pattern, &payload) {
+            info!("Head commit message does not match the commit message regex, skipping");
+            return Status::Ok.into();
+        }
+    }
+
+    // Some events trigger several independent actions, which needn't wait on each other
+    if let (Some(GitHubEvent { event }), Some(mapping)) = (event, &parallel_scripts) {
+        let scripts: Vec<_> = match parse_pairs(mapping) {
+            Ok(pairs) => pairs
+                .into_iter()
+                .filter(|(mapped, _)| *mapped == event)
+                .map(|(_, script)| PathBuf::from(script))
+                .collect(),
+            Err(err) => {
+                error!("Invalid parallel scripts: {err}");
+                return Status::InternalServerError.into();
+            }
+        };
+        if !scripts.is_empty() {
+            return run_parallel(scripts, &payload).await;
+        }
+    }
 
-#[get("/")]
-fn listen() -> String {
-    "Urm, hi?\nHow did you get here?\nThis is an api for computers 'n' stuff, not for humans :P"
-        .to_owned()
-}
-
-#[post("/", format = "json", data = "<user_input>")]
-fn webhook_listen(signature: XHubSignature, user_input: String) -> Status {
-    // Get script path
-    let path = env::var(SCRIPT_STRING).clone().map(|string_path| {
-        let mut path = PathBuf::new();
-        path.push(string_path);
-        path
-    });
-    let path = match path {
-        Ok(path) => path,
+    // Some events need several steps, each depending on the one before it
+    if let (Some(GitHubEvent { event }), Some(mapping)) = (event, &sequential_scripts) {
+        let scripts: Vec<_> = match parse_pairs(mapping) {
+            Ok(pairs) => pairs
+                .into_iter()
+                .filter(|(mapped, _)| *mapped == event)
+                .map(|(_, script)| PathBuf::from(script))
+                .collect(),
+            Err(err) => {
+                error!("Invalid sequential scripts: {err}");
+                return Status::InternalServerError.into();
+            }
+        };
+        let delay = match env_seconds(SCRIPT_STEP_DELAY_STRING) {
+            Ok(delay) => delay.unwrap_or_default(),
+            Err(err) => {
+                error!("{err}");
+                return Status::InternalServerError.into();
+            }
+        };
+        let keep_going = match env::var(MULTISCRIPT_MODE_STRING).as_deref() {
+            Ok("continue") => true,
+            Ok("stop") | Err(_) => false,
+            Ok(mode) => {
+                warn!("Unknown {MULTISCRIPT_MODE_STRING} \"{mode}\", stopping on failure");
+                false
+            }
+        };
+        if !scripts.is_empty() {
+            return run_sequential(scripts, &payload, delay, keep_going).await;
+        }
+    }
+
+    // Pushes run the script for their branch, falling back to the default script
+    let mapped_path = match (event, &branch_scripts) {
+        (Some(GitHubEvent { event: "push" }), Some(mapping)) => {
+            match branch_script(mapping, &payload) {
+                Ok(path) => path,
+                Err(err) => {
+                    error!("Invalid branch scripts: {err}");
+                    return Status::InternalServerError.into();
+                }
+            }
+        }
+        _ => None,
+    };
+    let path = match (mapped_path, default_path) {
+        (Some(path), _) | (None, Ok(path)) => path,
+        (None, Err(_)) => {
+            info!("No script for this delivery, skipping");
+            return Status::Ok.into();
+        }
+    };
+
+    // Bash would only report the script as unreadable, which hides the misconfiguration
+    if path.is_dir() {
+        error!("Script \"{}\" is a directory, not a script", path.display());
+        return WebhookResponse {
+            status: Status::InternalServerError,
+            body: Some(serde_json::json!({ "error": SCRIPT_IS_DIRECTORY }).to_string()),
+            headers: vec![ContentType::JSON.into()],
+            cached: false,
+        };
+    }
+
+    // Bursts of deliveries are run together once the window passes
+    let window = match tenant {
+        Some(_) => Ok(None),
+        None => env_seconds(batch::BATCH_WINDOW_STRING),
+    };
+    match window {
+        Ok(Some(window)) => {
+            let max = match env::var(batch::BATCH_MAX_STRING).map(|max| max.parse::<usize>()) {
+                Ok(Ok(max)) => Some(max),
+                Ok(Err(err)) => {
+                    error!("Invalid value for {}: {err}", batch::BATCH_MAX_STRING);
+                    return Status::InternalServerError.into();
+                }
+                Err(_) => None,
+            };
+
+            info!("Adding delivery to the batch for {}", path.display());
+            batches.add(path, payload, window, max);
+            return Status::Accepted.into();
+        }
+        Ok(None) => {}
+        Err(err) => {
+            error!("{err}");
+            return Status::InternalServerError.into();
+        }
+    }
+
+    let pool = shell_pool::from_env();
+    let open_file_limit = match open_file_limit() {
+        Ok(limit) => limit,
+        Err(err) => {
+            error!("{err}");
+            return Status::InternalServerError.into();
+        }
+    };
+    let command = match &pool {
+        // Pooled shells apply the open file limit within the script's subshell instead
+        Some(_) => Ok(shell_command(path)),
+        None => script_command(path),
+    };
+    let mut command = match command {
+        Ok(command) => command,
+        Err(err) => {
+            error!("Could not set up bash script: {err}");
+            return Status::InternalServerError.into();
+        }
+    };
+
+    // Run the script from within the directory templated from the payload
+    if let Ok(template) = env::var(WORKDIR_TEMPLATE_STRING) {
+        let workdir = match resolve_template(&template, &payload) {
+            Ok(workdir) => PathBuf::from(workdir),
+            Err(err) => {
+                warn!("Could not resolve working directory: {err}");
+                return Status::BadRequest.into();
+            }
+        };
+
+        if !workdir.is_dir() {
+            error!("Working directory \"{}\" does not exist", workdir.display());
+            return Status::InternalServerError.into();
+        }
+        command.current_dir(workdir);
+    }
+
+    if let Some(tenant) = tenant {
+        command.env(tenant::TENANT_ENV, &tenant.name);
+    }
+
+    if let Ok(name) = env::var(PAYLOAD_ENV_STRING) {
+        // Gives the script exactly the shape it expects, rather than all of the payload
+        let passed = match env::var(PAYLOAD_TRANSFORM_STRING) {
+            Ok(mapping) => match transform_payload(&payload, &mapping) {
+                Ok(transformed) => transformed.to_string(),
+                Err(err) => {
+                    error!("Invalid payload transform: {err}");
+                    return Status::InternalServerError.into();
+                }
+            },
+            Err(_) => payload.to_string(),
+        };
+        command.env(name, passed);
+    }
+
+    if let Ok(name) = env::var(HEADERS_ENV_STRING) {
+        let headers = headers_json(headers.headers, env_flag(REDACT_HEADERS_STRING));
+        command.env(name, headers.to_string());
+    }
+
+    // Deploys of releases need their assets, which are deleted along with deleted releases
+    if let (Some(GitHubEvent { event: "release" }), Ok(dir), false) = (
+        event,
+        env::var(release::ASSETS_DIR_STRING),
+        payload["action"] == "deleted",
+    ) {
+        let token = env::var(release::ASSETS_TOKEN_STRING).ok();
+        let downloaded = rocket::tokio::task::block_in_place(|| {
+            release::download_assets(&payload, dir.as_ref(), token.as_deref())
+        });
+        match downloaded {
+            Ok(assets) => {
+                command.env(release::ASSETS_ENV, assets);
+            }
+            Err(err) => {
+                error!("{err}");
+                return Status::InternalServerError.into();
+            }
+        }
+    }
+
+    // Let the script call the API as the App installation that sent the delivery
+    if let (Some(app), Some(installation)) = (
+        github_app::App::from_env(),
+        payload["installation"]["id"].as_u64(),
+    ) {
+        let token = app.and_then(|app| {
+            rocket::tokio::task::block_in_place(|| tokens.token(&app, installation))
+        });
+        match token {
+            Ok(token) => {
+                command.env(github_app::TOKEN_ENV, token);
+            }
+            Err(err) => {
+                error!("{err}");
+                return Status::InternalServerError.into();
+            }
+        }
+    }
+
+    // Some tools only show progress bars or colour when writing to a terminal
+    if env_flag(pty::USE_PTY_STRING) {
+        if let Err(err) = pty::attach(&mut command) {
+            error!("Could not open a pseudo-terminal for the script: {err}");
+            return Status::InternalServerError.into();
+        }
+    }
+
+    // Lets the script attach fields to the delivery's logs
+    if env_flag(PARSE_STDERR_STRING) {
+        command.stderr(Stdio::piped());
+    }
+
+    if env_flag(LOG_SCRIPT_ENV_STRING) {
+        for (key, value) in script_env(&command, headers.headers) {
+            info!("Script environment {key}={value}");
+        }
+    }
+
+    let retry_policy = match retry_policy() {
+        Ok(policy) => policy,
+        Err(err) => {
+            error!("Invalid {RETRY_EXIT_CODES_STRING}: {err}");
+            return Status::InternalServerError.into();
+        }
+    };
+
+    // Set up before the script is run, so that it is never left without its cleanup
+    let cleanup = match env::var(CLEANUP_SCRIPT_STRING).map(script_command) {
+        Ok(Ok(cleanup)) => Some(cleanup),
+        Ok(Err(err)) => {
+            error!("Could not set up cleanup script: {err}");
+            return Status::InternalServerError.into();
+        }
+        Err(_) => None,
+    };
+
+    // Execute script, in an already running shell if there's a pool of them
+    let spawned = match pool {
+        Some(pool) => match pool.spawn(command, open_file_limit, retry_policy) {
+            Ok(finished) => Ok(finished),
+            Err(err) => {
+                warn!("{err}");
+                return Status::ServiceUnavailable.into();
+            }
+        },
+        None => script::spawn_retrying(command, retry_policy),
+    };
+    let mut finished = match spawned {
+        Ok(finished) => finished,
+        Err(err) => {
+            error!("Could not execute bash script: {err}");
+            return Status::InternalServerError.into();
+        }
+    };
+
+    // Clean up after the script once it has finished, whatever the outcome
+    if let Some(mut cleanup) = cleanup {
+        finished = script::then_run(finished, move |result| {
+            let (outcome, code) = match result {
+                Ok(status) if status.success() => ("success", status.code()),
+                Ok(status) => ("failure", status.code()),
+                Err(_) => ("error", None),
+            };
+
+            cleanup.env(OUTCOME_ENV, outcome).env(
+                EXIT_CODE_ENV,
+                code.map(|code| code.to_string()).unwrap_or_default(),
+            );
+            cleanup
+        });
+    }
+
+    // Without a budget the script is left to run in the background
+    let budget = match response_budget(headers.headers) {
+        Ok(Some(budget)) => budget,
+        Ok(None) => return Status::Ok.into(),
+        Err(err) => {
+            error!("Invalid response budget: {err}");
+            return Status::InternalServerError.into();
+        }
+    };
+
+    match rocket::tokio::time::timeout(budget, &mut finished).await {
+        Ok(Ok(Ok(status))) if status.success() => Status::Ok.into(),
+        Ok(Ok(Ok(status))) => {
+            error!("Bash script failed: {status}");
+            Status::InternalServerError.into()
+        }
+        Ok(Ok(Err(err))) => {
+            error!("Could not wait for bash script: {err}");
+            Status::InternalServerError.into()
+        }
+        Ok(Err(_)) => Status::InternalServerError.into(),
+        // Respond before GitHub times out, letting the script carry on
+        Err(_) => {
+            let status_url = uri!(job_status(jobs.track(finished))).to_string();
+            WebhookResponse {
+                status: Status::Accepted,
+                body: Some(serde_json::json!({ "status_url": status_url }).to_string()),
+                headers: vec![
+                    ContentType::JSON.into(),
+                    Header::new("Location", status_url),
+                ],
+                cached: false,
+            }
+        }
+    }
+}
+
+/// When to run the script again after it fails, as configured in the environment.
+fn retry_policy() -> Result<RetryPolicy, std::num::ParseIntError> {
+    let exit_codes = match env::var(RETRY_EXIT_CODES_STRING) {
+        Ok(codes) => Some(
+            codes
+                .split(',')
+                .map(str::trim)
+                .filter(|code| !code.is_empty())
+                .map(str::parse)
+                .collect::<Result<_, _>>()?,
+        ),
+        Err(_) => None,
+    };
+
+    Ok(RetryPolicy {
+        retries: env_or(SCRIPT_RETRIES_STRING, 0),
+        exit_codes,
+    })
+}
+
+/// An environment variable that is not a valid number of seconds.
+#[derive(thiserror::Error, Debug)]
+#[error("\"{value}\" is not a valid number of seconds for {key}.")]
+struct InvalidSeconds {
+    key: &'static str,
+    value: String,
+}
+
+/// Parses a number of seconds, which may be fractional.
+fn parse_seconds(value: &str) -> Option<Duration> {
+    value
+        .trim()
+        .parse()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+}
+
+/// Reads the number of seconds from the environment variable, if set.
+fn env_seconds(key: &'static str) -> Result<Option<Duration>, InvalidSeconds> {
+    env::var(key)
+        .ok()
+        .map(|value| parse_seconds(&value).ok_or(InvalidSeconds { key, value }))
+        .transpose()
+}
+
+/// The possible errors when reading the response budget from the environment.
+#[derive(thiserror::Error, Debug)]
+enum BudgetError {
+    #[error("{0}")]
+    Seconds(#[from] InvalidSeconds),
+    #[error("Malformed {EVENT_BUDGETS_STRING}: {0}")]
+    EventBudget(#[from] MalformedPair),
+}
+
+/// How long to wait for the script before responding, if at all.
+///
+/// Deliveries may override the configured budget in [`BUDGET_HEADER`], but only once a
+/// maximum is configured to clamp it to. Otherwise the budget configured for the delivery's event
+/// is used, falling back to the global budget.
+fn response_budget(headers: &HeaderMap<'_>) -> Result<Option<Duration>, BudgetError> {
+    if let (Some(max), Some(requested)) = (
+        env_seconds(MAX_BUDGET_STRING)?,
+        headers.get_one(BUDGET_HEADER),
+    ) {
+        match parse_seconds(requested) {
+            Some(requested) => return Ok(Some(requested.min(max))),
+            None => warn!("Invalid {BUDGET_HEADER} \"{requested}\", ignoring it"),
+        }
+    }
+
+    if let (Ok(budgets), Some(event)) = (
+        env::var(EVENT_BUDGETS_STRING),
+        headers.get_one(EVENT_HEADER),
+    ) {
+        let budget = parse_pairs(&budgets)?
+            .into_iter()
+            .find(|(mapped, _)| *mapped == event);
+        if let Some((_, seconds)) = budget {
+            let budget = parse_seconds(seconds).ok_or_else(|| InvalidSeconds {
+                key: EVENT_BUDGETS_STRING,
+                value: seconds.to_owned(),
+            })?;
+            return Ok(Some(budget));
+        }
+    }
+
+    Ok(env_seconds(RESPONSE_BUDGET_STRING)?)
+}
+
+/// Rewrites deliveries sent to near misses of the webhook path to the root, when configured to.
+///
+/// Operators sometimes configure GitHub with `/webhook` or `/Webhook/` rather than `/`.
+fn lenient_path(request: &mut Request<'_>) {
+    if request.method() != Method::Post || !env_flag(LENIENT_PATH_STRING) {
+        return;
+    }
+
+    let path = request.uri().path().as_str().trim_end_matches('/');
+    if !path.is_empty() && !path.eq_ignore_ascii_case(LENIENT_ALIAS) {
+        return;
+    }
+    route_to_root(request);
+}
+
+/// Routes the request to the root, keeping its query.
+fn route_to_root(request: &mut Request<'_>) {
+    let uri = match request.uri().query() {
+        Some(query) => Origin::parse_owned(format!("/?{query}")).unwrap_or(Origin::ROOT),
+        None => Origin::ROOT,
+    };
+    request.set_uri(uri);
+}
+
+/// Gzips the response to requests for [`COMPRESSED_PATHS`] accepting it, when configured to.
+async fn compress_response(request: &Request<'_>, response: &mut Response<'_>) {
+    if !env_flag(COMPRESS_RESPONSES_STRING)
+        || !COMPRESSED_PATHS.contains(&request.uri().path().as_str())
+        || response.headers().contains("Content-Encoding")
+    {
+        return;
+    }
+
+    let accepts_gzip = request
+        .headers()
+        .get("Accept-Encoding")
+        .flat_map(|header| header.split(','))
+        .any(|encoding| {
+            let mut params = encoding.split(';').map(str::trim);
+            let refused = |param: &str| {
+                let quality = param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok());
+                quality == Some(0.0)
+            };
+            params
+                .next()
+                .is_some_and(|name| name.eq_ignore_ascii_case("gzip"))
+                && !params.any(refused)
+        });
+    if !accepts_gzip {
+        return;
+    }
+
+    let body = match response.body_mut().to_bytes().await {
+        Ok(body) => body,
+        Err(err) => {
+            warn!("Could not read the response to compress it: {err}");
+            response.set_status(Status::InternalServerError);
+            return;
+        }
+    };
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    let compressed = std::io::Write::write_all(&mut encoder, &body).and_then(|_| encoder.finish());
+    match compressed {
+        Ok(compressed) => {
+            response.set_raw_header("Content-Encoding", "gzip");
+            response.set_sized_body(compressed.len(), std::io::Cursor::new(compressed));
+        }
+        // Compressing in memory can't really fail, but the body must still be sent
         Err(err) => {
-            eprintln!("Could not get script path from environment: {err}");
-            return Status::InternalServerError;
+            warn!("Could not compress the response: {err}");
+            response.set_sized_body(body.len(), std::io::Cursor::new(body));
+        }
+    }
+    response.set_raw_header("Vary", "Accept-Encoding");
+}
+
+/// Whether the handler is ready for deliveries.
+///
+/// Deliveries are still accepted while waiting on [`READY_AFTER_SUCCESSES_STRING`], so that a
+/// canary can prove itself before taking all of the traffic.
+#[get("/ready")]
+fn ready(readiness: &State<Readiness>) -> Status {
+    let required = match env::var(READY_AFTER_SUCCESSES_STRING).map(|count| count.parse::<u64>()) {
+        Ok(Ok(required)) => required,
+        Ok(Err(err)) => {
+            warn!("Invalid value for {READY_AFTER_SUCCESSES_STRING}, ignoring it: {err}");
+            0
         }
+        Err(_) => 0,
     };
 
-    // Get secret
-    let secret = match env::var(WEBHOOK_STRING).clone() {
-        Ok(secret) => secret,
+    match readiness.is_ready()
+        && readiness.successes.load(Ordering::Relaxed) >= required
+        && enough_free_space()
+    {
+        true => Status::Ok,
+        false => Status::ServiceUnavailable,
+    }
+}
+
+/// Whether there is at least [`MIN_FREE_MB_STRING`] free for deploys to write to.
+fn enough_free_space() -> bool {
+    let Ok(minimum) = env::var(MIN_FREE_MB_STRING) else {
+        return true;
+    };
+    let minimum = match minimum.parse::<u64>() {
+        Ok(minimum) => minimum,
         Err(err) => {
-            eprintln!("Could not get secret key from environment: {err}");
-            return Status::InternalServerError;
+            warn!("Invalid value for {MIN_FREE_MB_STRING}, ignoring it: {err}");
+            return true;
         }
     };
 
-    // Check if sent signature was produced from matching secret
-    if let Err(err) = signature_matches(&secret, &user_input.to_string(), signature) {
-        eprintln!("Signature Error: {err}");
-        return Status::Unauthorized;
-    };
+    let path = env::var(FREE_SPACE_PATH_STRING).unwrap_or_else(|_| ".".to_owned());
+    match free_bytes(path.as_ref()) {
+        Ok(free) if free / (1024 * 1024) >= minimum => true,
+        Ok(free) => {
+            warn!(
+                "Only {} MB is free on \"{path}\", below {MIN_FREE_MB_STRING}",
+                free / (1024 * 1024)
+            );
+            false
+        }
+        Err(err) => {
+            error!("Could not check the free space on \"{path}\": {err}");
+            false
+        }
+    }
+}
+
+/// The bytes available to unprivileged users on the filesystem holding the path.
+#[cfg(unix)]
+fn free_bytes(path: &std::path::Path) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // Safety: the path is nul terminated & the stats are only read once statvfs succeeds
+    let stats = unsafe {
+        if libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        stats.assume_init()
+    };
+    #[allow(clippy::unnecessary_cast)] // The field types differ between platforms
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+/// Free space can only be checked on unix.
+#[cfg(not(unix))]
+fn free_bytes(_path: &std::path::Path) -> std::io::Result<u64> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Free space can only be checked on unix",
+    ))
+}
+
+/// Whether the warmup script has let the handler become ready for deliveries.
+#[derive(Clone)]
+struct Readiness {
+    ready: Arc<AtomicBool>,
+    failed: Arc<AtomicBool>,
+    /// The deliveries that have succeeded since launch.
+    successes: Arc<AtomicU64>,
+}
+
+impl Readiness {
+    /// Handlers with a warmup script start out unready.
+    fn new(warmup: bool) -> Self {
+        Self {
+            ready: Arc::new(AtomicBool::new(!warmup)),
+            failed: Arc::new(AtomicBool::new(false)),
+            successes: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+}
+
+/// Runs the warmup script, making the handler ready once it succeeds.
+async fn warmup(readiness: Readiness, shutdown: Shutdown) {
+    let Ok(path) = env::var(WARMUP_SCRIPT_STRING) else {
+        return;
+    };
+
+    let result = match script_command(path).and_then(script::spawn) {
+        Ok(finished) => finished
+            .await
+            .unwrap_or_else(|err| Err(std::io::Error::other(err))),
+        Err(err) => Err(err),
+    };
+
+    match result {
+        Ok(status) if status.success() => {
+            info!("Warmup script succeeded, ready for deliveries");
+            readiness.ready.store(true, Ordering::Relaxed);
+            return;
+        }
+        Ok(status) => error!("Warmup script failed: {status}"),
+        Err(err) => error!("Could not execute warmup script: {err}"),
+    }
+
+    readiness.failed.store(true, Ordering::Relaxed);
+    if env_flag(WARMUP_EXIT_STRING) {
+        shutdown.notify();
+    }
+}
+
+/// Logs a heartbeat & records it in the metrics every interval, until shut down.
+///
+/// This shows the handler is still alive even when no deliveries are being sent.
+async fn heartbeat(interval: Duration, shutdown: Shutdown) {
+    let started = Instant::now();
+    let mut beats = rocket::tokio::time::interval(interval);
+    // The first tick completes immediately
+    beats.tick().await;
+
+    loop {
+        rocket::tokio::select! {
+            _ = beats.tick() => {}
+            _ = shutdown.clone() => return,
+        }
+
+        info!(
+            uptime_secs = started.elapsed().as_secs(),
+            deliveries = metrics::DELIVERIES.load(Ordering::Relaxed),
+            "Heartbeat"
+        );
+        metrics::heartbeat();
+    }
+}
+
+/// The open file limit scripts are run with, if one is configured.
+fn open_file_limit() -> std::io::Result<Option<u64>> {
+    env::var(NOFILE_LIMIT_STRING)
+        .ok()
+        .map(|limit| {
+            limit.parse().map_err(|err| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid {NOFILE_LIMIT_STRING} \"{limit}\": {err}"),
+                )
+            })
+        })
+        .transpose()
+}
+
+/// A command running the script with bash, set up the same way whichever kind of script it is.
+fn script_command(script: impl AsRef<OsStr>) -> std::io::Result<Command> {
+    let mut command = shell_command(script);
+    // Stops a runaway script from exhausting the system's file descriptors
+    if let Some(limit) = open_file_limit()? {
+        script::limit_open_files(&mut command, limit)?;
+    }
+    Ok(command)
+}
+
+/// A command running the script with bash, without the limits applied by [`script_command`].
+///
+/// Every script is told which environment the handler is deploying to.
+///
+/// Only for pooled shells, which apply the limits within the script's subshell instead.
+fn shell_command(script: impl AsRef<OsStr>) -> Command {
+    let mut command = Command::new("bash");
+    command.arg(script);
+    if let Ok(environment) = env::var(ENVIRONMENT_STRING) {
+        command.env(ENVIRONMENT_ENV, environment);
+    }
+    command
+}
+
+/// Spawns a script handling an event other than a deploy, leaving it to run in the background.
+fn spawn_event_script(command: Command, kind: &str) -> WebhookResponse {
+    match script::spawn(command) {
+        Ok(_) => Status::Ok.into(),
+        Err(err) => {
+            error!("Could not execute {kind} script: {err}");
+            Status::InternalServerError.into()
+        }
+    }
+}
+
+/// Runs the scripts all at once with the payload on their stdin, succeeding only if every one of
+/// them does.
+async fn run_parallel(scripts: Vec<PathBuf>, payload: &serde_json::Value) -> WebhookResponse {
+    let input = payload.to_string();
+    let mut running = Vec::with_capacity(scripts.len());
+    let mut failed = false;
+    for script in scripts {
+        let spawned = script_command(&script)
+            .and_then(|command| script::spawn_with_input(command, input.clone().into_bytes()));
+        match spawned {
+            Ok(finished) => running.push((script, finished)),
+            Err(err) => {
+                error!("Could not execute {}: {err}", script.display());
+                failed = true;
+            }
+        }
+    }
+
+    for (script, finished) in running {
+        match finished.await {
+            Ok(Ok(status)) if status.success() => {}
+            Ok(Ok(status)) => {
+                error!("{} failed: {status}", script.display());
+                failed = true;
+            }
+            Ok(Err(err)) => {
+                error!("Could not wait for {}: {err}", script.display());
+                failed = true;
+            }
+            Err(_) => failed = true,
+        }
+    }
+
+    match failed {
+        true => Status::InternalServerError.into(),
+        false => Status::Ok.into(),
+    }
+}
+
+/// Runs the scripts one after another with the payload on their stdin, waiting for the delay
+/// between each of them.
+///
+/// Stops at the first script that fails, unless told to keep going. When it keeps going, the
+/// response lists the exit code of every script, and is a `207 Multi-Status` if any failed.
+async fn run_sequential(
+    scripts: Vec<PathBuf>,
+    payload: &serde_json::Value,
+    delay: Duration,
+    keep_going: bool,
+) -> WebhookResponse {
+    let input = payload.to_string();
+    let mut summary = Vec::with_capacity(scripts.len());
+    let mut failed = false;
+    for (step, script) in scripts.iter().enumerate() {
+        if step > 0 && !delay.is_zero() {
+            rocket::tokio::time::sleep(delay).await;
+        }
+
+        let spawned = script_command(script)
+            .and_then(|command| script::spawn_with_input(command, input.clone().into_bytes()));
+        // Scripts that couldn't be run, or were killed by a signal, have no exit code
+        let exit_code = match spawned {
+            Ok(finished) => match finished.await {
+                Ok(Ok(status)) if status.success() => status.code(),
+                Ok(Ok(status)) => {
+                    error!("{} failed: {status}", script.display());
+                    failed = true;
+                    status.code()
+                }
+                Ok(Err(err)) => {
+                    error!("Could not wait for {}: {err}", script.display());
+                    failed = true;
+                    None
+                }
+                Err(_) => {
+                    failed = true;
+                    None
+                }
+            },
+            Err(err) => {
+                error!("Could not execute {}: {err}", script.display());
+                failed = true;
+                None
+            }
+        };
+        summary.push(serde_json::json!({
+            "script": script.display().to_string(),
+            "exit_code": exit_code,
+        }));
+
+        if failed && !keep_going {
+            return Status::InternalServerError.into();
+        }
+    }
+
+    if !keep_going {
+        return Status::Ok.into();
+    }
+    WebhookResponse {
+        status: match failed {
+            true => Status::MultiStatus,
+            false => Status::Ok,
+        },
+        body: Some(serde_json::json!({ "scripts": summary }).to_string()),
+        headers: vec![ContentType::JSON.into()],
+        cached: false,
+    }
+}
+
+/// The body of a newly posted pull request comment or review, along with the number of the pull
+/// request.
+///
+/// Comments on issues that aren't pull requests, and edits to comments, are ignored.
+fn pull_request_comment<'a>(event: &str, payload: &'a serde_json::Value) -> Option<(&'a str, u64)> {
+    let (body, pull_request) = match (event, payload["action"].as_str()?) {
+        ("issue_comment", "created") if payload["issue"]["pull_request"].is_object() => {
+            (&payload["comment"]["body"], &payload["issue"])
+        }
+        ("pull_request_review", "submitted") => {
+            (&payload["review"]["body"], &payload["pull_request"])
+        }
+        _ => return None,
+    };
+    Some((
+        body.as_str().unwrap_or_default(),
+        pull_request["number"].as_u64()?,
+    ))
+}
+
+/// The status of a script that was still running when its delivery was responded to.
+#[get("/status/<id>")]
+fn job_status(id: u64, jobs: &State<Jobs>) -> Option<Json<JobStatus>> {
+    jobs.status(id).map(Json)
+}
+
+/// The error code responded with when the script to run is a directory.
+const SCRIPT_IS_DIRECTORY: &str = "script_is_directory";
+
+/// The response to a webhook delivery.
+#[derive(Clone)]
+struct WebhookResponse {
+    status: Status,
+    body: Option<String>,
+    headers: Vec<Header<'static>>,
+    /// Whether the response was reused from an identical delivery, rather than freshly handled.
+    cached: bool,
+}
+
+impl From<Status> for WebhookResponse {
+    fn from(status: Status) -> Self {
+        Self {
+            status,
+            body: None,
+            headers: Vec::new(),
+            cached: false,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for WebhookResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = match self.body {
+            Some(body) => Response::build_from(body.respond_to(request)?)
+                .status(self.status)
+                .finalize(),
+            // Lets error statuses be handled by the catchers
+            None => self.status.respond_to(request)?,
+        };
+
+        for header in self.headers {
+            response.set_header(header);
+        }
+        Ok(response)
+    }
+}
+
+/// Check if the head commit message of a push payload matches the pattern.
+///
+/// Payloads without a head commit message (e.g. branch deletions) never match.
+fn commit_message_matches(pattern: &regex::Regex, payload: &serde_json::Value) -> bool {
+    payload["head_commit"]["message"]
+        .as_str()
+        .is_some_and(|message| pattern.is_match(message))
+}
+
+/// Replaces the fields at each of the comma separated json pointers with [`REDACTED_VALUE`].
+///
+/// Fields missing from the payload are left missing.
+fn redact_fields(payload: &mut serde_json::Value, pointers: &str) {
+    for pointer in pointers.split(',').map(str::trim) {
+        if let Some(field) = payload.pointer_mut(pointer) {
+            *field = serde_json::Value::from(REDACTED_VALUE);
+        }
+    }
+}
+
+/// The object mapping each name to the field of the payload at its json pointer, or null if the
+/// payload has no such field.
+fn transform_payload(
+    payload: &serde_json::Value,
+    mapping: &str,
+) -> Result<serde_json::Value, MalformedPair> {
+    Ok(parse_pairs(mapping)?
+        .into_iter()
+        .map(|(name, pointer)| {
+            let field = payload.pointer(pointer).cloned();
+            (name.to_owned(), field.unwrap_or_default())
+        })
+        .collect())
+}
+
+/// The environment variables set for the script on top of those it inherits, with secrets
+/// redacted.
+///
+/// The headers passed to the script are always shown with their credentials redacted.
+fn script_env(command: &Command, headers: &HeaderMap<'_>) -> Vec<(String, String)> {
+    let headers_env = env::var(HEADERS_ENV_STRING).ok();
+    command
+        .get_envs()
+        .filter_map(|(key, value)| Some((key.to_string_lossy(), value?.to_string_lossy())))
+        .map(|(key, value)| {
+            let value = if key == github_app::TOKEN_ENV || key.contains("SECRET") {
+                "[redacted]".to_owned()
+            } else if headers_env.as_deref() == Some(&*key) {
+                headers_json(headers, true).to_string()
+            } else {
+                value.into_owned()
+            };
+            (key.into_owned(), value)
+        })
+        .collect()
+}
+
+/// The headers as a json object keyed by their lowercased names, joining repeated headers with
+/// commas.
+fn headers_json(headers: &HeaderMap<'_>, redact: bool) -> serde_json::Value {
+    let mut object = serde_json::Map::new();
+    for header in headers.iter() {
+        let name = header.name().as_str().to_lowercase();
+        let value = match redact
+            && SENSITIVE_HEADERS
+                .iter()
+                .any(|sensitive| sensitive.eq_ignore_ascii_case(&name))
+        {
+            true => "[redacted]",
+            false => header.value(),
+        };
+
+        match object.get_mut(&name) {
+            Some(serde_json::Value::String(joined)) => {
+                joined.push_str(", ");
+                joined.push_str(value);
+            }
+            _ => {
+                object.insert(name, value.into());
+            }
+        }
+    }
+
+    object.into()
+}
+
+/// Whether the request carries the exact `header=value` marker of a health ping.
+fn is_health_ping(marker: &str, headers: &HeaderMap<'_>) -> Result<bool, MalformedPair> {
+    let (name, value) = marker
+        .split_once('=')
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .filter(|(name, value)| !name.is_empty() && !value.is_empty())
+        .ok_or_else(|| MalformedPair(marker.to_owned()))?;
+
+    Ok(headers.get_one(name) == Some(value))
+}
+
+/// The names of the repos added to & removed from the App installation by the event, if it is an
+/// installation event.
+fn installation_changes<'a>(
+    event: &str,
+    payload: &'a serde_json::Value,
+) -> Option<(Vec<&'a str>, Vec<&'a str>)> {
+    let names = |repos: &'a serde_json::Value| -> Vec<&'a str> {
+        repos
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|repo| repo["full_name"].as_str())
+            .collect()
+    };
+
+    Some(match (event, payload["action"].as_str()) {
+        ("installation", Some("created")) => (names(&payload["repositories"]), Vec::new()),
+        ("installation", Some("deleted")) => (Vec::new(), names(&payload["repositories"])),
+        // Suspensions & permission changes leave the repos as they were
+        ("installation", _) => (Vec::new(), Vec::new()),
+        ("installation_repositories", _) => (
+            names(&payload["repositories_added"]),
+            names(&payload["repositories_removed"]),
+        ),
+        _ => return None,
+    })
+}
+
+/// An entry of a list that is not in the form `key=value`.
+#[derive(thiserror::Error, Debug)]
+#[error("\"{0}\" is not in the form \"key=value\".")]
+struct MalformedPair(String);
+
+/// Splits a comma separated list of `key=value` pairs.
+fn parse_pairs(list: &str) -> Result<Vec<(&str, &str)>, MalformedPair> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            pair.split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .ok_or_else(|| MalformedPair(pair.to_owned()))
+        })
+        .collect()
+}
+
+/// The script mapped to the branch that was pushed to, if any.
+///
+/// Only pushes to branches are mapped, so tag pushes never match.
+fn branch_script(
+    mapping: &str,
+    payload: &serde_json::Value,
+) -> Result<Option<PathBuf>, MalformedPair> {
+    let Some(branch) = payload["ref"]
+        .as_str()
+        .and_then(|git_ref| git_ref.strip_prefix("refs/heads/"))
+    else {
+        return Ok(None);
+    };
+
+    Ok(parse_pairs(mapping)?
+        .into_iter()
+        .find(|(mapped, _)| *mapped == branch)
+        .map(|(_, script)| PathBuf::from(script)))
+}
+
+/// The possible errors when filling a template in from the payload.
+#[derive(thiserror::Error, Debug)]
+enum TemplateError {
+    #[error("The template has an unterminated placeholder.")]
+    Unterminated,
+    #[error("The payload has no string or number at \"{0}\".")]
+    MissingField(String),
+}
+
+/// Replaces each `{dotted.path}` placeholder in the template with the value at that path in the
+/// payload.
+fn resolve_template(template: &str, payload: &serde_json::Value) -> Result<String, TemplateError> {
+    let mut resolved = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        resolved.push_str(&rest[..start]);
+        let end = start + rest[start..].find('}').ok_or(TemplateError::Unterminated)?;
+
+        let field = &rest[start + 1..end];
+        match field.split('.').fold(payload, |value, key| &value[key]) {
+            serde_json::Value::String(value) => resolved.push_str(value),
+            serde_json::Value::Number(value) => resolved.push_str(&value.to_string()),
+            _ => return Err(TemplateError::MissingField(field.to_owned())),
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    resolved.push_str(rest);
+    Ok(resolved)
+}
+
+/// The GitHub event that triggered the webhook.
+#[derive(Clone, Copy)]
+struct GitHubEvent<'a> {
+    event: &'a str,
+}
+
+#[rocket::async_trait]
+impl<'a> FromRequest<'a> for GitHubEvent<'a> {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'_>) -> Outcome<Self, ()> {
+        match request.headers().get_one(EVENT_HEADER) {
+            Some(event) => Outcome::Success(Self { event }),
+            None => Outcome::Forward(Status::BadRequest),
+        }
+    }
+}
+
+/// All of the headers sent with the request.
+struct RequestHeaders<'a> {
+    headers: &'a HeaderMap<'a>,
+}
+
+#[rocket::async_trait]
+impl<'a> FromRequest<'a> for RequestHeaders<'a> {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'_>) -> Outcome<Self, ()> {
+        Outcome::Success(Self {
+            headers: request.headers(),
+        })
+    }
+}
+
+/// The status configured for HEAD requests to the webhook path.
+struct HeadPing {
+    status: Status,
+}
+
+#[rocket::async_trait]
+impl<'a> FromRequest<'a> for HeadPing {
+    type Error = ();
+
+    async fn from_request(_request: &'a Request<'_>) -> Outcome<Self, ()> {
+        let Ok(status) = env::var(HEAD_STATUS_STRING) else {
+            return Outcome::Forward(Status::NotFound);
+        };
+
+        match status.parse().ok().and_then(Status::from_code) {
+            Some(status) if status.class().is_success() => Outcome::Success(Self { status }),
+            _ => {
+                warn!("Invalid {HEAD_STATUS_STRING} \"{status}\", expected a 2xx status");
+                Outcome::Forward(Status::NotFound)
+            }
+        }
+    }
+}
+
+/// A request sent over HTTPS, when required to be.
+///
+/// Requests are only known to be sent over HTTPS if TLS is enabled, or a trusted proxy reports
+/// that it received them over HTTPS. Others are rejected before their body is read.
+struct OverHttps;
+
+#[rocket::async_trait]
+impl<'a> FromRequest<'a> for OverHttps {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'_>) -> Outcome<Self, ()> {
+        if !env_flag(REQUIRE_HTTPS_STRING) || request.rocket().config().tls_enabled() {
+            return Outcome::Success(Self);
+        }
+
+        // Anyone can claim the request was sent over HTTPS, so only proxies are believed
+        let trusted = env::var(TRUSTED_PROXIES_STRING).unwrap_or_default();
+        let from_trusted = request.remote().is_some_and(|remote| {
+            trusted
+                .split(',')
+                .filter_map(|proxy| proxy.trim().parse::<std::net::IpAddr>().ok())
+                .any(|proxy| proxy == remote.ip())
+        });
+        let forwarded_https = request
+            .headers()
+            .get_one(FORWARDED_PROTO_HEADER)
+            .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"));
+
+        match from_trusted && forwarded_https {
+            true => Outcome::Success(Self),
+            false => {
+                warn!("Rejecting request that was not sent over HTTPS");
+                Outcome::Error((Status::BadRequest, ()))
+            }
+        }
+    }
+}
+
+/// A request asking for the connection to be upgraded to another protocol.
+struct UpgradeRequest;
+
+#[rocket::async_trait]
+impl<'a> FromRequest<'a> for UpgradeRequest {
+    type Error = ();
+
+    async fn from_request(request: &'a Request<'_>) -> Outcome<Self, ()> {
+        match request.headers().contains(UPGRADE_HEADER) {
+            true => Outcome::Success(Self),
+            false => Outcome::Forward(Status::NotFound),
+        }
+    }
+}
+
+/// Configuration that can't be applied to scripts run in the shell pool, if it is set alongside
+/// the pool.
+fn unpoolable_config() -> Option<&'static str> {
+    if env_or(shell_pool::POOL_SIZE_STRING, 0usize) == 0 {
+        return None;
+    }
+    [pty::USE_PTY_STRING, PARSE_STDERR_STRING]
+        .into_iter()
+        .find(|key| env_flag(key))
+}
+
+/// Reads & parses the given environment variable, falling back to the default if it is unset or
+/// invalid.
+fn env_or<T>(key: &str, default: T) -> T
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env::var(key) {
+        Ok(value) => match value.parse() {
+            Ok(value) => value,
+            Err(err) => {
+                warn!("Invalid value for {key}, using the default: {err}");
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+/// Whether the given environment variable is set to "1" or "true".
+fn env_flag(key: &str) -> bool {
+    env::var(key).is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+}
+
+/// Launches the rocket instance produced by `build`, rebuilding & retrying up to `attempts` times
+/// if the port could not be bound.
+///
+/// The port may still be held briefly by a terminating instance during restarts.
+async fn launch_with_retry<F>(
+    build: F,
+    attempts: u32,
+    delay: Duration,
+) -> Result<Rocket<Ignite>, rocket::Error>
+where
+    F: Fn() -> Rocket<Build>,
+{
+    let mut attempt = 1;
+    loop {
+        match build().launch().await {
+            Err(err) if matches!(err.kind(), ErrorKind::Bind(_)) && attempt < attempts => {
+                warn!("Could not bind port (attempt {attempt}/{attempts}): {err}");
+                rocket::tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[rocket::main]
+async fn main() {
+    if let Err(err) = logging::init() {
+        eprintln!("Could not set up logging: {err}");
+        std::process::exit(1);
+    }
+
+    if env_flag(LOG_CONFIG_STRING) {
+        log_config();
+    }
+
+    if env_flag(NO_HMAC_WHEN_MTLS_STRING) {
+        warn!("HMAC validation is DISABLED for clients presenting a verified certificate!");
+    }
+
+    let attempts = env_or(BIND_ATTEMPTS_STRING, 1);
+    let delay = Duration::from_millis(env_or(BIND_RETRY_DELAY_STRING, 1000));
+
+    match launch_with_retry(rocket, attempts, delay).await {
+        Ok(rocket) => {
+            // Only shut down early by a failed warmup script
+            if rocket
+                .state::<Readiness>()
+                .is_some_and(|readiness| readiness.failed.load(Ordering::Relaxed))
+                && env_flag(WARMUP_EXIT_STRING)
+            {
+                std::process::exit(1);
+            }
+        }
+        Err(err) => {
+            error!("{}", err.pretty_print());
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Why deliveries couldn't be handled with the configuration in the environment, if anything.
+fn missing_config() -> Option<String> {
+    if let Err(err) = auth::from_env() {
+        return Some(err.to_string());
+    }
+    if let Some(err) = script_is_directory() {
+        return Some(err);
+    }
+
+    let scripts = [
+        SCRIPT_STRING,
+        BRANCH_SCRIPTS_STRING,
+        MANAGEMENT_SCRIPT_STRING,
+        WORKFLOW_SCRIPT_STRING,
+        CREATE_SCRIPT_STRING,
+        DELETE_SCRIPT_STRING,
+        COMMENT_SCRIPT_STRING,
+        PARALLEL_SCRIPTS_STRING,
+        SEQUENTIAL_SCRIPTS_STRING,
+        tenant::TENANT_SCRIPTS_STRING,
+    ];
+    match scripts.iter().any(|key| env::var(key).is_ok()) {
+        true => None,
+        false => Some(format!(
+            "No script is configured in {}.",
+            scripts.join(", ")
+        )),
+    }
+}
+
+/// Why the configured script can't be run, if it is a directory.
+fn script_is_directory() -> Option<String> {
+    let script = env::var(SCRIPT_STRING).ok()?;
+    PathBuf::from(&script)
+        .is_dir()
+        .then(|| format!("{SCRIPT_STRING} \"{script}\" is a directory, not a script."))
+}
+
+/// Logs the configuration set in the environment, so operators can confirm what is in effect.
+fn log_config() {
+    for (key, value) in configuration() {
+        info!("Configured {key}={value}");
+    }
+}
+
+/// Lists the configuration set in the environment, for troubleshooting what is in effect.
+#[get("/debug/env")]
+fn debug_env(_admin: dashboard::Admin) -> String {
+    configuration()
+        .into_iter()
+        .map(|(key, value)| format!("{key}={value}\n"))
+        .collect()
+}
+
+/// The configuration set in the environment, sorted by key.
+///
+/// Anything configured in [`SENSITIVE_CONFIG`] is redacted, as are the values of unknown
+/// variables that mention a secret.
+fn configuration() -> Vec<(String, String)> {
+    let mut config: Vec<_> = env::vars()
+        .filter(|(key, _)| key.starts_with("WEBHOOK_") || key.starts_with("ROCKET_"))
+        .map(|(key, value)| {
+            let sensitive = SENSITIVE_CONFIG.contains(&key.as_str())
+                || (key.contains("SECRET")
+                    && ![
+                        auth::ROTATION_FILE_STRING,
+                        auth::SECRET_COMMAND_TTL_STRING,
+                        auth::SECRET_COMMAND_TIMEOUT_STRING,
+                    ]
+                    .contains(&key.as_str()));
+            match sensitive {
+                true => (key, "[redacted]".to_owned()),
+                false => (key, value),
+            }
+        })
+        .collect();
+    config.sort();
+    config
+}
+
+/// Builds the rocket instance with all of the routes mounted.
+fn rocket() -> Rocket<Build> {
+    // This way still allows for customistion via ENV.
+    let mut config = Config::figment().merge((
+        Config::LIMITS,
+        Limits::new().limit("string", 32.kibibytes()),
+    ));
+
+    // Stop idle connections from holding on to file descriptors
+    if let Ok(timeout) = env::var(IDLE_TIMEOUT_STRING) {
+        match timeout.parse::<u32>() {
+            Ok(timeout) => config = config.merge((Config::KEEP_ALIVE, timeout)),
+            Err(err) => warn!("Invalid value for {IDLE_TIMEOUT_STRING}, ignoring it: {err}"),
+        }
+    }
+
+    // Rustls negotiates the version from the available ciphers, so restricting them to TLS 1.3
+    // refuses anything older
+    if config.contains("tls.certs") {
+        match env::var(MIN_TLS_VERSION_STRING).as_deref() {
+            Err(_) | Ok("1.2") => {}
+            Ok("1.3") => config = config.merge(("tls.ciphers", CipherSuite::TLS_V13_SET)),
+            Ok(version) => warn!("Unknown minimum TLS version \"{version}\", ignoring it"),
+        }
+    }
+
+    rocket::build()
+        .configure(config)
+        .manage(Jobs::default())
+        .manage(InstallationTokens::default())
+        .manage(memory::BodyMemory::default())
+        .manage(PendingDeliveries::default())
+        .manage(RecentDeliveries::default())
+        .manage(Batches::default())
+        .manage(ResultCache::<WebhookResponse>::default())
+        .manage(tenant::RateLimits::default())
+        .manage(Readiness::new(env::var(WARMUP_SCRIPT_STRING).is_ok()))
+        .attach(AdHoc::try_on_ignite("Strict startup", |rocket| async {
+            // Fail fast rather than failing every delivery
+            match env_flag(STRICT_STARTUP_STRING)
+                .then(missing_config)
+                .flatten()
+            {
+                Some(err) => {
+                    error!("Refusing to start: {err}");
+                    Err(rocket)
+                }
+                None => Ok(rocket),
+            }
+        }))
+        .attach(AdHoc::try_on_ignite("Shell pool", |rocket| async {
+            // Pooled scripts share their shell's stdio, so these would silently not apply
+            match unpoolable_config() {
+                Some(key) => {
+                    error!(
+                        "Refusing to start: {key} can't be applied to scripts run by {}",
+                        shell_pool::POOL_SIZE_STRING
+                    );
+                    Err(rocket)
+                }
+                None => Ok(rocket),
+            }
+        }))
+        .attach(AdHoc::try_on_ignite("History", |rocket| async {
+            let Ok(path) = env::var(history::HISTORY_DB_STRING) else {
+                return Ok(rocket);
+            };
+            match history::init(&path) {
+                Ok(()) => Ok(rocket),
+                Err(err) => {
+                    error!("Refusing to start: could not open the history database: {err}");
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(connections::DeliveryCap::default())
+        .attach(AdHoc::on_request("Lenient path", |request, _| {
+            Box::pin(async move { lenient_path(request) })
+        }))
+        .attach(AdHoc::on_request("Tenants", |request, _| {
+            Box::pin(async move { tenant::route(request) })
+        }))
+        .attach(AdHoc::on_response("Compression", |request, response| {
+            Box::pin(compress_response(request, response))
+        }))
+        .attach(AdHoc::on_liftoff("Script check", |_| {
+            Box::pin(async {
+                // Strict startup refuses to start instead, but otherwise it'd only show up once
+                // deliveries start failing
+                if let Some(err) = script_is_directory() {
+                    error!("{err} Every delivery will fail until it is fixed.");
+                }
+            })
+        }))
+        .attach(AdHoc::on_liftoff("Warmup", |rocket| {
+            // Run in the background, serving readiness checks in the meantime
+            let readiness = rocket.state::<Readiness>().cloned();
+            let shutdown = rocket.shutdown();
+            Box::pin(async move {
+                if let Some(readiness) = readiness {
+                    rocket::tokio::spawn(warmup(readiness, shutdown));
+                }
+            })
+        }))
+        .attach(AdHoc::on_liftoff("Metrics state", |rocket| {
+            let shutdown = rocket.shutdown();
+            Box::pin(async move {
+                let Ok(path) = env::var(metrics::STATE_FILE_STRING).map(PathBuf::from) else {
+                    return;
+                };
+
+                // Only restored once launched, as building may be retried
+                match metrics::restore(&path, &metrics::counters()) {
+                    Ok(()) => {
+                        rocket::tokio::spawn(metrics::save_periodically(path, shutdown));
+                    }
+                    Err(err) => error!("Could not restore the metrics state: {err}"),
+                }
+            })
+        }))
+        .attach(AdHoc::on_shutdown("Metrics state", |_| {
+            Box::pin(async move {
+                if let Ok(path) = env::var(metrics::STATE_FILE_STRING) {
+                    if let Err(err) = metrics::save(path.as_ref(), &metrics::counters()) {
+                        error!("Could not save the metrics state: {err}");
+                    }
+                }
+            })
+        }))
+        .attach(AdHoc::on_liftoff("Heartbeat", |rocket| {
+            let shutdown = rocket.shutdown();
+            Box::pin(async move {
+                match env_seconds(HEARTBEAT_STRING) {
+                    Ok(Some(interval)) if !interval.is_zero() => {
+                        rocket::tokio::spawn(heartbeat(interval, shutdown));
+                    }
+                    Ok(_) => {}
+                    Err(err) => warn!("{err} Ignoring it"),
+                }
+            })
+        }))
+        .mount("/", upgrade_routes())
+        .mount(
+            "/",
+            routes![
+                listen,
+                head_ping,
+                webhook_listen,
+                sign_delivery,
+                ready,
+                job_status,
+                metrics::metrics,
+                dashboard::dashboard,
+                debug_env,
+                last_error::last_error,
+                history::history
+            ],
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::blocking::Client;
+
+    use super::*;
+    use crate::auth::{HEADER, WEBHOOK_STRING};
+    use hmac::Mac;
+
+    /// Signs the payload with the secret, formatted as GitHub would send it.
+    fn sign(secret: &str, payload: impl AsRef<[u8]>) -> String {
+        let signature = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes())
+            .unwrap()
+            .chain_update(payload)
+            .finalize()
+            .into_bytes();
+        format!("sha256={}", hex::encode(signature))
+    }
+
+    /// Writes a bash script running `body` from within a new temp dir.
+    fn write_script(body: &str) -> (tempdir::TempDir, PathBuf) {
+        let temp_dir =
+            tempdir::TempDir::new("webhook_handler-temp").expect("Able to create temp dir");
+
+        let mut path = temp_dir.path().to_path_buf();
+        path.push("test.sh");
+        std::fs::write(
+            &path,
+            format!(
+                // Switch to correct dir
+                "cd {}; {body}",
+                temp_dir.path().to_str().expect("Valid Path")
+            ),
+        )
+        .expect("Able to write test script");
+
+        (temp_dir, path)
+    }
+
+    /// Waits a short while for the file to be written to by a spawned script.
+    fn wait_for_file(path: &std::path::Path) -> bool {
+        for _ in 0..50 {
+            if path.metadata().is_ok_and(|metadata| metadata.len() > 0) {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    #[test]
+    fn no_env() {
+        temp_env::with_vars_unset([WEBHOOK_STRING, SCRIPT_STRING], || {
+            let client = Client::tracked(rocket()).expect("valid rocket instance");
+            let response = client
+                .post(uri!(webhook_listen))
+                .json(&"{}")
+                .header(Header::new(HEADER, "N/A"))
+                .dispatch();
+
+            assert_eq!(response.status(), Status::InternalServerError);
+        });
+    }
+
+    #[test]
+    fn no_signature() {
+        temp_env::with_vars(
+            [(WEBHOOK_STRING, None), (SCRIPT_STRING, Some("script.sh"))],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .json(&"{}")
+                    .header(Header::new(HEADER, "Not_A_Match"))
+                    .dispatch();
+
+                assert_eq!(response.status(), Status::InternalServerError);
+            },
+        );
+    }
+
+    #[test]
+    fn no_script() {
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("Very Secure!")),
+                (SCRIPT_STRING, None),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .json(&"{}")
+                    .header(Header::new(HEADER, "Not_A_Match"))
+                    .dispatch();
+
+                assert_eq!(response.status(), Status::InternalServerError);
+            },
+        );
+    }
+
+    #[test]
+    fn invalid_signature() {
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("Very Secure!")),
+                (SCRIPT_STRING, Some("script.sh")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .json(&"{}")
+                    .header(Header::new(HEADER, "sha256=0123acd"))
+                    .dispatch();
+
+                assert_eq!(response.status(), Status::Unauthorized);
+            },
+        );
+    }
+
+    #[test]
+    fn websocket_upgrade() {
+        let client = Client::tracked(rocket()).expect("valid rocket instance");
+        let response = client
+            .get(uri!(listen))
+            .header(Header::new("Connection", "Upgrade"))
+            .header(Header::new(UPGRADE_HEADER, "websocket"))
+            .dispatch();
+
+        assert_eq!(response.status(), Status::BadRequest);
+        assert!(response
+            .into_string()
+            .expect("Response has body")
+            .contains("WebSockets are not supported"));
+
+        // Normal requests are unaffected
+        temp_env::with_var(DISABLE_LANDING_STRING, None::<&str>, || {
+            let response = client.get(uri!(listen)).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+        });
+        let response = client
+            .get(uri!(ready))
+            .header(Header::new(UPGRADE_HEADER, "websocket"))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        let response = client
+            .head(uri!(head_ping))
+            .header(Header::new(UPGRADE_HEADER, "websocket"))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn websocket_upgrade_delivery() {
+        let (temp_dir, script) = write_script("echo ran > ran.temp");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |upgrade: bool| {
+                    let request = client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                        .body("{}");
+                    match upgrade {
+                        true => request.header(Header::new(UPGRADE_HEADER, "websocket")),
+                        false => request,
+                    }
+                    .dispatch()
+                    .status()
+                };
+
+                assert_eq!(deliver(true), Status::BadRequest);
+                std::thread::sleep(Duration::from_millis(100));
+                assert!(!temp_dir.path().join("ran.temp").exists());
+
+                assert_eq!(deliver(false), Status::Ok);
+                assert!(wait_for_file(&temp_dir.path().join("ran.temp")));
+            },
+        );
+    }
+
+    #[rocket::async_test]
+    async fn bind_retry() {
+        // Hold the port as a terminating instance would
+        let held = std::net::TcpListener::bind("127.0.0.1:0").expect("Able to bind port");
+        let port = held.local_addr().expect("Bound to address").port();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            drop(held);
+        });
+
+        let build = || {
+            let rocket = rocket();
+            let figment = rocket
+                .figment()
+                .clone()
+                .merge(("address", "127.0.0.1"))
+                .merge(("port", port));
+            rocket
+                .configure(figment)
+                .attach(rocket::fairing::AdHoc::on_liftoff("Shutdown", |rocket| {
+                    Box::pin(async move { rocket.shutdown().notify() })
+                }))
+        };
+
+        launch_with_retry(build, 20, Duration::from_millis(100))
+            .await
+            .expect("Eventually binds the port");
+    }
+
+    #[test]
+    fn valid_request() {
+        // Temp dir for bash script
+        let temp_dir =
+            tempdir::TempDir::new("webhook_handler-temp").expect("Able to create temp dir");
+
+        // Write script
+        let mut path = temp_dir.path().to_path_buf();
+        path.push("test.sh");
+        std::fs::write(
+            &path,
+            format!(
+                // Switch to correct dir
+                "cd {}; echo 'hi' > file.temp",
+                temp_dir.path().to_str().expect("Valid Path")
+            ),
+        )
+        .expect("Able to write test script");
+
+        // Valid signature with valid bash script
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(path.to_str().expect("Valid Path"))),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .json(&"{\"test\": 1}")
+                    .header(Header::new(
+                        HEADER,
+                        "sha256=f5cf34a2c036452fd80ced7508e5c231b1afa5c05713eaf87610499ee23f471a",
+                    ))
+                    .dispatch();
+
+                assert_eq!(response.status(), Status::Ok);
+            },
+        );
+
+        let mut path = temp_dir.path().to_path_buf();
+        path.push("file.temp");
+        // Check file exists, once the script has had a chance to run
+        assert!(wait_for_file(&path));
+        assert!(std::fs::exists(&path).expect("Exists"));
+
+        // Check correct content
+        let content = std::fs::read_to_string(path).expect("Valid file");
+        assert_eq!(content, "hi\n");
+    }
+
+    #[test]
+    fn commit_message_regex() {
+        let (temp_dir, script) = write_script("echo 'hi' >> file.temp");
+        let output = temp_dir.path().join("file.temp");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (COMMIT_MESSAGE_REGEX_STRING, Some(r"\[deploy\]")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let push = |message: &str| {
+                    let payload =
+                        serde_json::json!({ "head_commit": { "message": message } }).to_string();
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", &payload)))
+                        .header(Header::new(EVENT_HEADER, "push"))
+                        .body(payload)
+                        .dispatch()
+                        .status()
+                };
+
+                // Non-matching pushes are acknowledged without running the script
+                assert_eq!(push("Fix typo"), Status::Ok);
+                std::thread::sleep(Duration::from_millis(200));
+                assert!(!output.exists());
+
+                assert_eq!(push("Release v2 [deploy]"), Status::Ok);
+                assert!(wait_for_file(&output));
+            },
+        );
+    }
+
+    #[test]
+    fn commit_message_regex_multiple_scripts() {
+        let (temp_dir, script) = write_script("echo 'hi' >> file.temp");
+        let output = temp_dir.path().join("file.temp");
+        let mapping = format!("push={}", script.to_str().expect("Valid Path"));
+
+        for scripts in [PARALLEL_SCRIPTS_STRING, SEQUENTIAL_SCRIPTS_STRING] {
+            temp_env::with_vars(
+                [
+                    (WEBHOOK_STRING, Some("VerySecure")),
+                    (scripts, Some(mapping.as_str())),
+                    (COMMIT_MESSAGE_REGEX_STRING, Some(r"\[deploy\]")),
+                ],
+                || {
+                    let client = Client::tracked(rocket()).expect("valid rocket instance");
+                    let push = |message: &str| {
+                        let payload = serde_json::json!({ "head_commit": { "message": message } })
+                            .to_string();
+                        client
+                            .post(uri!(webhook_listen))
+                            .header(ContentType::JSON)
+                            .header(Header::new(HEADER, sign("VerySecure", &payload)))
+                            .header(Header::new(EVENT_HEADER, "push"))
+                            .body(payload)
+                            .dispatch()
+                            .status()
+                    };
+
+                    // These scripts are waited on, so there's no need to give them time to run
+                    assert_eq!(push("Fix typo"), Status::Ok);
+                    assert!(!output.exists(), "{scripts} ran");
+
+                    assert_eq!(push("Release v2 [deploy]"), Status::Ok);
+                    assert!(output.exists(), "{scripts} didn't run");
+                },
+            );
+            std::fs::remove_file(&output).unwrap();
+        }
+    }
+
+    #[test]
+    fn workdir_template() {
+        let (temp_dir, script) = write_script("");
+        let output = temp_dir.path().join("pwd.temp");
+        std::fs::write(&script, format!("pwd > {}", output.display()))
+            .expect("Able to write test script");
+
+        let checkout = temp_dir.path().join("tye-exe/website");
+        std::fs::create_dir_all(&checkout).expect("Able to create checkout dir");
+        let template = format!("{}/{{repository.full_name}}", temp_dir.path().display());
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (WORKDIR_TEMPLATE_STRING, Some(template.as_str())),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |repo: &str| {
+                    let payload =
+                        serde_json::json!({ "repository": { "full_name": repo } }).to_string();
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", &payload)))
+                        .body(payload)
+                        .dispatch()
+                        .status()
+                };
+
+                // Repos without a checkout are refused
+                assert_eq!(deliver("tye-exe/missing"), Status::InternalServerError);
+
+                assert_eq!(deliver("tye-exe/website"), Status::Ok);
+                assert!(wait_for_file(&output));
+            },
+        );
+
+        let pwd = std::fs::read_to_string(output).expect("Valid file");
+        assert_eq!(
+            pwd.trim(),
+            checkout
+                .canonicalize()
+                .expect("Valid Path")
+                .to_str()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn template_resolution() {
+        let payload = serde_json::json!({ "repository": { "name": "website", "id": 7 } });
+        assert_eq!(
+            resolve_template("/srv/{repository.name}-{repository.id}", &payload).unwrap(),
+            "/srv/website-7"
+        );
+        assert!(matches!(
+            resolve_template("/srv/{repository.owner}", &payload),
+            Err(TemplateError::MissingField(_))
+        ));
+        assert!(matches!(
+            resolve_template("/srv/{repository", &payload),
+            Err(TemplateError::Unterminated)
+        ));
+    }
+
+    #[test]
+    fn require_all_signatures() {
+        let (_temp_dir, script) = write_script("");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (
+                    REQUIRE_ALL_SIGS_STRING,
+                    Some("X-Second-Signature=AlsoSecure"),
+                ),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let payload = "{\"test\": 1}";
+                let deliver = |second_secret: &str| {
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", payload)))
+                        .header(Header::new(
+                            "X-Second-Signature",
+                            sign(second_secret, payload),
+                        ))
+                        .body(payload)
+                        .dispatch()
+                        .status()
+                };
+
+                assert_eq!(deliver("AlsoSecure"), Status::Ok);
+                assert_eq!(deliver("NotTheSecret"), Status::Unauthorized);
+
+                // Duplicated headers are handled as they are for the primary signature
+                let duplicated = |mode: &str| {
+                    temp_env::with_var(auth::DUPLICATE_SIGNATURES_STRING, Some(mode), || {
+                        client
+                            .post(uri!(webhook_listen))
+                            .header(ContentType::JSON)
+                            .header(Header::new(HEADER, sign("VerySecure", payload)))
+                            .header(Header::new(
+                                "X-Second-Signature",
+                                sign("AlsoSecure", payload),
+                            ))
+                            .header(Header::new(
+                                "X-Second-Signature",
+                                sign("NotTheSecret", payload),
+                            ))
+                            .body(payload)
+                            .dispatch()
+                            .status()
+                    })
+                };
+                assert_eq!(duplicated("first"), Status::Ok);
+                assert_eq!(duplicated("all"), Status::Unauthorized);
+                assert_eq!(duplicated("strict"), Status::BadRequest);
+            },
+        );
+    }
+
+    #[test]
+    fn require_all_signatures_compressed() {
+        use std::io::Write;
+
+        let (_temp_dir, script) = write_script("");
+        let payload = "{\"test\": 1}";
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(payload.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (
+                    REQUIRE_ALL_SIGS_STRING,
+                    Some("X-Second-Signature=AlsoSecure"),
+                ),
+                (auth::SNIFF_GZIP_STRING, Some("1")),
+                (auth::SIGN_COMPRESSED_STRING, None),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |second_signed: &[u8]| {
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", &compressed)))
+                        .header(Header::new(
+                            "X-Second-Signature",
+                            sign("AlsoSecure", second_signed),
+                        ))
+                        .body(&compressed)
+                        .dispatch()
+                        .status()
+                };
+
+                // Checked against the same bytes as the primary signature
+                assert_eq!(deliver(&compressed), Status::Ok);
+                assert_eq!(deliver(payload.as_bytes()), Status::Unauthorized);
+            },
+        );
+    }
+
+    #[test]
+    fn response_budget() {
+        let (temp_dir, script) = write_script("");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (RESPONSE_BUDGET_STRING, Some("0.5")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |script_body: &str| {
+                    std::fs::write(&script, script_body).expect("Able to write test script");
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                        .body("{}")
+                        .dispatch()
+                };
+
+                // Fast scripts report their result synchronously
+                assert_eq!(deliver("exit 0").status(), Status::Ok);
+                assert_eq!(deliver("exit 1").status(), Status::InternalServerError);
+
+                // Slow scripts are handed off once the budget runs out
+                let marker = temp_dir.path().join("done.temp");
+                let response = deliver(&format!("sleep 1; echo done > {}", marker.display()));
+                assert_eq!(response.status(), Status::Accepted);
+                let status_url = response
+                    .headers()
+                    .get_one("Location")
+                    .expect("Has status url")
+                    .to_owned();
+
+                let status = client.get(status_url.as_str()).dispatch();
+                assert_eq!(
+                    status.into_json::<serde_json::Value>().expect("Valid json")["status"],
+                    "running"
+                );
+
+                // The script carries on to completion
+                assert!(wait_for_file(&marker));
+                std::thread::sleep(Duration::from_millis(100));
+                let status = client.get(status_url.as_str()).dispatch();
+                assert_eq!(
+                    status.into_json::<serde_json::Value>().expect("Valid json")["status"],
+                    "finished"
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn payload_digest() {
+        let (_temp_dir, script) = write_script("");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (PAYLOAD_DIGEST_STRING, Some("1")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .header(ContentType::JSON)
+                    .header(Header::new(HEADER, sign("VerySecure", "Hello, World!")))
+                    .body("Hello, World!")
+                    .dispatch();
+
+                // Not valid json, so never processed
+                assert_eq!(response.status(), Status::BadRequest);
+                assert!(!response.headers().contains(PAYLOAD_DIGEST_HEADER));
+
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .header(ContentType::JSON)
+                    .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                    .body("{}")
+                    .dispatch();
+
+                assert_eq!(response.status(), Status::Ok);
+                assert_eq!(
+                    response.headers().get_one(PAYLOAD_DIGEST_HEADER),
+                    Some("44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a")
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn branch_scripts() {
+        let (temp_dir, prod) = write_script("echo 'prod' > prod.temp");
+        let staging = temp_dir.path().join("staging.sh");
+        std::fs::write(
+            &staging,
+            format!(
+                "cd {}; echo 'staging' > staging.temp",
+                temp_dir.path().display()
+            ),
+        )
+        .expect("Able to write test script");
+        let mapping = format!("main={},staging={}", prod.display(), staging.display());
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, None),
+                (BRANCH_SCRIPTS_STRING, Some(mapping.as_str())),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let push = |branch: &str| {
+                    let payload =
+                        serde_json::json!({ "ref": format!("refs/heads/{branch}") }).to_string();
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", &payload)))
+                        .header(Header::new(EVENT_HEADER, "push"))
+                        .body(payload)
+                        .dispatch()
+                        .status()
+                };
+
+                assert_eq!(push("staging"), Status::Ok);
+                assert!(wait_for_file(&temp_dir.path().join("staging.temp")));
+                assert!(!temp_dir.path().join("prod.temp").exists());
+
+                assert_eq!(push("main"), Status::Ok);
+                assert!(wait_for_file(&temp_dir.path().join("prod.temp")));
+
+                // Without a default script unmapped branches are skipped
+                assert_eq!(push("feature"), Status::Ok);
+            },
+        );
+    }
+
+    #[test]
+    fn no_hmac_when_mtls() {
+        let (_temp_dir, script) = write_script("");
+        let identity = rcgen::generate_simple_self_signed(vec!["github.com".to_owned()])
+            .expect("Able to generate certificate")
+            .cert
+            .pem();
+
+        let deliver = |client: &Client, identity: Option<&str>| {
+            let request = client
+                .post(uri!(webhook_listen))
+                .header(ContentType::JSON)
+                .body("{}");
+            match identity {
+                Some(identity) => request.identity(identity.as_bytes()),
+                None => request,
+            }
+            .dispatch()
+            .status()
+        };
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (NO_HMAC_WHEN_MTLS_STRING, Some("1")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                assert_eq!(deliver(&client, Some(&identity)), Status::Ok);
+                assert_eq!(deliver(&client, None), Status::BadRequest);
+            },
+        );
+
+        // Only the primary signature is skipped, not those from independent keys
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (NO_HMAC_WHEN_MTLS_STRING, Some("1")),
+                (REQUIRE_ALL_SIGS_STRING, Some("X-Other-Signature=Other")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                assert_eq!(deliver(&client, Some(&identity)), Status::Unauthorized);
+
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .header(ContentType::JSON)
+                    .header(Header::new("X-Other-Signature", sign("Other", "{}")))
+                    .identity(identity.as_bytes())
+                    .body("{}")
+                    .dispatch();
+                assert_eq!(response.status(), Status::Ok);
+            },
+        );
+
+        // HMAC is still required with a certificate unless explicitly skipped
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (NO_HMAC_WHEN_MTLS_STRING, None),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                assert_eq!(deliver(&client, Some(&identity)), Status::BadRequest);
+            },
+        );
+    }
+
+    #[test]
+    fn echo_event() {
+        let (_temp_dir, script) = write_script("");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (ECHO_EVENT_STRING, Some("1")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |event: &str, payload: &str| {
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", payload)))
+                        .header(Header::new(EVENT_HEADER, event.to_owned()))
+                        .body(payload)
+                        .dispatch()
+                };
+
+                let response = deliver("release", r#"{"action": "published"}"#);
+                assert_eq!(response.status(), Status::Ok);
+                assert_eq!(
+                    response.headers().get_one(ECHOED_EVENT_HEADER),
+                    Some("release")
+                );
+                assert_eq!(
+                    response.headers().get_one(ECHOED_ACTION_HEADER),
+                    Some("published")
+                );
+
+                // Events without activity types have no action
+                let response = deliver("push", "{}");
+                assert_eq!(
+                    response.headers().get_one(ECHOED_EVENT_HEADER),
+                    Some("push")
+                );
+                assert!(!response.headers().contains(ECHOED_ACTION_HEADER));
+            },
+        );
+    }
+
+    #[test]
+    fn management_script() {
+        let (temp_dir, deploy) = write_script("echo 'deploy' > deploy.temp");
+        let management = temp_dir.path().join("manage.sh");
+        let output = temp_dir.path().join("repos.temp");
+        std::fs::write(
+            &management,
+            format!(
+                "printf '%s|%s|%s' \"$WEBHOOK_ACTION\" \"$WEBHOOK_REPOS_ADDED\" \"$WEBHOOK_REPOS_REMOVED\" > {}",
+                output.display()
+            ),
+        )
+        .expect("Able to write test script");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(deploy.to_str().expect("Valid Path"))),
+                (
+                    MANAGEMENT_SCRIPT_STRING,
+                    Some(management.to_str().expect("Valid Path")),
+                ),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |event: &str, payload: serde_json::Value| {
+                    let _ = std::fs::remove_file(&output);
+                    let payload = payload.to_string();
+                    let status = client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", &payload)))
+                        .header(Header::new(EVENT_HEADER, event.to_owned()))
+                        .body(payload)
+                        .dispatch()
+                        .status();
+                    assert_eq!(status, Status::Ok);
+                    assert!(wait_for_file(&output));
+                    std::fs::read_to_string(&output).expect("Valid file")
+                };
+
+                let created = serde_json::json!({
+                    "action": "created",
+                    "installation": { "id": 1 },
+                    "repositories": [
+                        { "full_name": "tye-exe/website" },
+                        { "full_name": "tye-exe/blog" },
+                    ],
+                });
+                assert_eq!(
+                    deliver("installation", created),
+                    "created|tye-exe/website\ntye-exe/blog|"
+                );
+
+                let changed = serde_json::json!({
+                    "action": "removed",
+                    "installation": { "id": 1 },
+                    "repositories_added": [],
+                    "repositories_removed": [{ "full_name": "tye-exe/blog" }],
+                });
+                assert_eq!(
+                    deliver("installation_repositories", changed),
+                    "removed||tye-exe/blog"
+                );
+            },
+        );
+
+        // Deploy script never ran for the installation events
+        assert!(!temp_dir.path().join("deploy.temp").exists());
+    }
+
+    #[test]
+    fn min_tls_version() {
+        let ciphers = || {
+            rocket()
+                .figment()
+                .extract_inner::<Vec<CipherSuite>>("tls.ciphers")
+                .ok()
+        };
+
+        temp_env::with_vars(
+            [
+                ("ROCKET_TLS", Some("{certs=\"cert.pem\",key=\"key.pem\"}")),
+                (MIN_TLS_VERSION_STRING, Some("1.3")),
+            ],
+            || {
+                assert_eq!(ciphers(), Some(CipherSuite::TLS_V13_SET.to_vec()));
+            },
+        );
+        temp_env::with_vars(
+            [
+                ("ROCKET_TLS", Some("{certs=\"cert.pem\",key=\"key.pem\"}")),
+                (MIN_TLS_VERSION_STRING, Some("1.2")),
+            ],
+            || {
+                assert_eq!(ciphers(), None);
+            },
+        );
+
+        // Without TLS there is nothing to restrict
+        temp_env::with_vars(
+            [("ROCKET_TLS", None), (MIN_TLS_VERSION_STRING, Some("1.3"))],
+            || {
+                assert_eq!(ciphers(), None);
+            },
+        );
+    }
+
+    #[test]
+    fn health_ping() {
+        let (temp_dir, script) = write_script("echo 'hi' > file.temp");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (HEALTH_PING_STRING, Some("X-Health-Ping=uptime-robot")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let ping = |value: &str| {
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new("X-Health-Ping", value.to_owned()))
+                        .body("{}")
+                        .dispatch()
+                        .status()
+                };
+
+                assert_eq!(ping("uptime-robot"), Status::Ok);
+                assert_eq!(ping("someone-else"), Status::BadRequest);
+
+                // Unsigned deliveries are still rejected
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .header(ContentType::JSON)
+                    .header(Header::new(HEADER, "sha256=0123acd"))
+                    .body("{}")
+                    .dispatch();
+                assert_eq!(response.status(), Status::Unauthorized);
+            },
+        );
+
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(!temp_dir.path().join("file.temp").exists());
+    }
+
+    #[test]
+    fn statsd_metrics() {
+        let (_temp_dir, script) = write_script("");
+        let statsd = std::net::UdpSocket::bind("127.0.0.1:0").expect("Able to bind socket");
+        statsd
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let addr = statsd.local_addr().expect("Bound to address").to_string();
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (statsd::STATSD_ADDR_STRING, Some(addr.as_str())),
+                (statsd::STATSD_PREFIX_STRING, Some("deploys")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .header(ContentType::JSON)
+                    .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                    .body("{}")
+                    .dispatch();
+                assert_eq!(response.status(), Status::Ok);
+            },
+        );
+
+        let mut packet = [0; 512];
+        let length = statsd.recv(&mut packet).expect("Metrics were sent");
+        let packet = std::str::from_utf8(&packet[..length]).expect("Valid utf-8");
+        let lines: Vec<_> = packet.lines().collect();
+        assert_eq!(
+            lines[..2],
+            ["deploys.deliveries:1|c", "deploys.responses.200:1|c"]
+        );
+        assert!(lines[2].starts_with("deploys.delivery_time:") && lines[2].ends_with("|ms"));
+    }
+
+    #[test]
+    fn oversized_signature() {
+        let (_temp_dir, script) = write_script("");
+        let statsd = std::net::UdpSocket::bind("127.0.0.1:0").expect("Able to bind socket");
+        statsd
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let addr = statsd.local_addr().expect("Bound to address").to_string();
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (statsd::STATSD_ADDR_STRING, Some(addr.as_str())),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let signature = format!("sha256={}", "ab".repeat(512 * 1024));
+
+                let started = Instant::now();
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .header(ContentType::JSON)
+                    .header(Header::new(HEADER, signature))
+                    .body("{}")
+                    .dispatch();
+                assert_eq!(response.status(), Status::BadRequest);
+                assert!(started.elapsed() < Duration::from_secs(1));
+
+                let metrics = client.get(uri!(metrics::metrics)).dispatch();
+                let metrics = metrics.into_string().unwrap();
+                assert!(metrics.contains("\nwebhook_abuse_total{kind=\"oversized_signature\"} "));
+            },
+        );
+
+        let mut packet = [0; 512];
+        let length = statsd.recv(&mut packet).expect("Metrics were sent");
+        assert_eq!(&packet[..length], b"webhook.abuse.oversized_signature:1|c");
+    }
+
+    #[test]
+    fn response_budget_override() {
+        let (_temp_dir, script) = write_script("sleep 0.5");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (RESPONSE_BUDGET_STRING, Some("0.1")),
+                (MAX_BUDGET_STRING, Some("2")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |budget: Option<&str>| {
+                    let request = client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                        .body("{}");
+                    match budget {
+                        Some(budget) => {
+                            request.header(Header::new(BUDGET_HEADER, budget.to_owned()))
+                        }
+                        None => request,
+                    }
+                    .dispatch()
+                    .status()
+                };
+
+                assert_eq!(deliver(None), Status::Accepted);
+                assert_eq!(deliver(Some("1.5")), Status::Ok);
+            },
+        );
+
+        // Requests beyond the maximum are clamped to it
+        let headers = |budget: &str| {
+            let mut headers = HeaderMap::new();
+            headers.add(Header::new(BUDGET_HEADER, budget.to_owned()));
+            headers
+        };
+        temp_env::with_vars(
+            [
+                (RESPONSE_BUDGET_STRING, Some("0.1")),
+                (MAX_BUDGET_STRING, Some("2")),
+            ],
+            || {
+                assert_eq!(
+                    super::response_budget(&headers("600")).unwrap(),
+                    Some(Duration::from_secs(2))
+                );
+                assert_eq!(
+                    super::response_budget(&headers("soon")).unwrap(),
+                    Some(Duration::from_millis(100))
+                );
+            },
+        );
+
+        // Overrides are ignored without a maximum
+        temp_env::with_vars(
+            [
+                (RESPONSE_BUDGET_STRING, None::<&str>),
+                (MAX_BUDGET_STRING, None),
+            ],
+            || {
+                assert_eq!(super::response_budget(&headers("600")).unwrap(), None);
+            },
+        );
+    }
+
+    #[test]
+    fn disable_landing() {
+        temp_env::with_var(DISABLE_LANDING_STRING, None::<&str>, || {
+            let client = Client::tracked(rocket()).expect("valid rocket instance");
+            let response = client.get(uri!(listen)).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            assert!(response
+                .into_string()
+                .expect("Response has body")
+                .starts_with("Urm, hi?"));
+        });
+
+        temp_env::with_vars(
+            [
+                (DISABLE_LANDING_STRING, Some("1")),
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some("script.sh")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let response = client.get(uri!(listen)).dispatch();
+                assert_eq!(response.status(), Status::NotFound);
+
+                // Deliveries are still handled
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .header(ContentType::JSON)
+                    .header(Header::new(HEADER, "sha256=0123acd"))
+                    .body("{}")
+                    .dispatch();
+                assert_eq!(response.status(), Status::Unauthorized);
+            },
+        );
+    }
+
+    #[rocket::async_test]
+    async fn max_request_duration() {
+        use rocket::tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpStream,
+        };
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("Able to find a free port")
+            .port();
+
+        // The variable is read per request, so must stay set while the server runs
+        temp_env::async_with_vars([(auth::MAX_REQUEST_STRING, Some("0.3"))], async {
+            let rocket = rocket();
+            let figment = rocket
+                .figment()
+                .clone()
+                .merge(("address", "127.0.0.1"))
+                .merge(("port", port));
+            let rocket = rocket
+                .configure(figment)
+                .ignite()
+                .await
+                .expect("Valid rocket instance");
+            let shutdown = rocket.shutdown();
+            rocket::tokio::spawn(rocket.launch());
+
+            let mut stream = loop {
+                match TcpStream::connect(("127.0.0.1", port)).await {
+                    Ok(stream) => break stream,
+                    Err(_) => rocket::tokio::time::sleep(Duration::from_millis(20)).await,
+                }
+            };
+
+            // Promise a payload that never fully arrives
+            let started = Instant::now();
+            stream
+                .write_all(
+                    b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+                      Content-Length: 100\r\n\r\n{\"slow\": \"still sending",
+                )
+                .await
+                .unwrap();
+
+            let mut response = Vec::new();
+            rocket::tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+                .await
+                .expect("Connection was dropped")
+                .unwrap();
+            assert!(started.elapsed() >= Duration::from_millis(300));
+            assert!(response.starts_with(b"HTTP/1.1 408"));
+
+            shutdown.notify();
+        })
+        .await;
+    }
+
+    #[rocket::async_test]
+    async fn max_total_body_memory() {
+        use rocket::tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpStream,
+        };
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("Able to find a free port")
+            .port();
+
+        /// Sends the start of a delivery promising a body of the given length.
+        async fn start_delivery(port: u16, length: usize, body: &str) -> TcpStream {
+            let mut stream = loop {
+                match TcpStream::connect(("127.0.0.1", port)).await {
+                    Ok(stream) => break stream,
+                    Err(_) => rocket::tokio::time::sleep(Duration::from_millis(20)).await,
+                }
+            };
+            let head = format!(
+                "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+                 Connection: close\r\nContent-Length: {length}\r\n\r\n{body}"
+            );
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream
+        }
+
+        /// Sends a whole delivery of the given length, returning the response.
+        async fn deliver(port: u16, length: usize) -> Vec<u8> {
+            let body = format!("\"{}\"", "a".repeat(length - 2));
+            let mut stream = start_delivery(port, length, &body).await;
+            let mut response = Vec::new();
+            rocket::tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+                .await
+                .expect("Response was sent")
+                .unwrap();
+            response
+        }
+
+        // The variable is read per request, so must stay set while the server runs
+        temp_env::async_with_vars([(memory::MAX_TOTAL_BODY_STRING, Some("1"))], async {
+            let rocket = rocket();
+            let figment = rocket
+                .figment()
+                .clone()
+                .merge(("address", "127.0.0.1"))
+                .merge(("port", port));
+            let rocket = rocket
+                .configure(figment)
+                .ignite()
+                .await
+                .expect("Valid rocket instance");
+            let shutdown = rocket.shutdown();
+            rocket::tokio::spawn(rocket.launch());
+
+            // A slow client holds on to most of the budget while it sends
+            let slow = start_delivery(port, 800, "{\"slow\": \"still sending").await;
+            rocket::tokio::time::sleep(Duration::from_millis(200)).await;
+
+            let response = deliver(port, 800).await;
+            assert!(response.starts_with(b"HTTP/1.1 503"));
+
+            // Smaller bodies still fit within what is left
+            let response = deliver(port, 100).await;
+            assert!(!response.starts_with(b"HTTP/1.1 503"));
+
+            // The budget is given back once the slow client goes away
+            drop(slow);
+            let mut response = deliver(port, 800).await;
+            for _ in 0..50 {
+                if !response.starts_with(b"HTTP/1.1 503") {
+                    break;
+                }
+                rocket::tokio::time::sleep(Duration::from_millis(20)).await;
+                response = deliver(port, 800).await;
+            }
+            assert!(!response.starts_with(b"HTTP/1.1 503"));
+
+            shutdown.notify();
+        })
+        .await;
+    }
+
+    #[test]
+    fn detached_signature() {
+        let (temp_dir, script) = write_script("echo \"$1\" >> runs.temp");
+        let runs = temp_dir.path().join("runs.temp");
+        let payload = "{\"test\": 1}";
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (detached::DETACHED_TIMEOUT_STRING, Some("0.3")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let hold = |token: &str| {
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(detached::TOKEN_HEADER, token.to_owned()))
+                        .body(payload)
+                        .dispatch()
+                        .status()
+                };
+                let sign_with = |token: &str, secret: &str| {
+                    client
+                        .post(uri!(sign_delivery(token)))
+                        .header(Header::new(HEADER, sign(secret, payload)))
+                        .dispatch()
+                        .status()
+                };
+
+                // The payload is held until it is signed
+                assert_eq!(hold("first"), Status::Accepted);
+                assert_eq!(hold("first"), Status::Conflict);
+                assert!(!runs.exists());
+                assert_eq!(sign_with("first", "VerySecure"), Status::Ok);
+                assert!(wait_for_file(&runs));
+
+                // Each held delivery can only be signed once
+                assert_eq!(sign_with("first", "VerySecure"), Status::NotFound);
+
+                assert_eq!(hold("forged"), Status::Accepted);
+                assert_eq!(sign_with("forged", "NotTheSecret"), Status::Unauthorized);
+                assert_eq!(sign_with("forged", "VerySecure"), Status::NotFound);
+
+                // Signatures arriving after the timeout don't release the delivery
+                assert_eq!(hold("late"), Status::Accepted);
+                std::thread::sleep(Duration::from_millis(400));
+                assert_eq!(sign_with("late", "VerySecure"), Status::Gone);
+
+                std::thread::sleep(Duration::from_millis(100));
+                let runs = std::fs::read_to_string(&runs).expect("Script was run");
+                assert_eq!(runs.lines().count(), 1);
+            },
+        );
+
+        // Signatures are checked like any other, by the configured provider
+        let rotation = temp_dir.path().join("rotation");
+        std::fs::write(&rotation, "New\nOld\n").expect("Able to write rotation file");
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (detached::DETACHED_TIMEOUT_STRING, Some("5")),
+                (
+                    auth::ROTATION_FILE_STRING,
+                    Some(rotation.to_str().expect("Valid Path")),
+                ),
+                (auth::DUPLICATE_SIGNATURES_STRING, Some("strict")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let hold = |token: &str| {
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(detached::TOKEN_HEADER, token.to_owned()))
+                        .body(payload)
+                        .dispatch()
+                        .status()
+                };
+
+                assert_eq!(hold("global"), Status::Accepted);
+                let response = client
+                    .post(uri!(sign_delivery("global")))
+                    .header(Header::new(HEADER, sign("VerySecure", payload)))
+                    .dispatch();
+                assert_eq!(response.status(), Status::Unauthorized);
+
+                assert_eq!(hold("duplicated"), Status::Accepted);
+                let response = client
+                    .post(uri!(sign_delivery("duplicated")))
+                    .header(Header::new(HEADER, sign("Old", payload)))
+                    .header(Header::new(HEADER, sign("Old", payload)))
+                    .dispatch();
+                assert_eq!(response.status(), Status::BadRequest);
+
+                assert_eq!(hold("rotated"), Status::Accepted);
+                let response = client
+                    .post(uri!(sign_delivery("rotated")))
+                    .header(Header::new(HEADER, sign("Old", payload)))
+                    .dispatch();
+                assert_eq!(response.status(), Status::Ok);
+            },
+        );
+
+        // Deliveries are never held unless configured to be
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .header(ContentType::JSON)
+                    .header(Header::new(detached::TOKEN_HEADER, "first"))
+                    .body(payload)
+                    .dispatch();
+                assert_eq!(response.status(), Status::BadRequest);
+            },
+        );
+    }
+
+    #[test]
+    fn lenient_path() {
+        let (temp_dir, script) = write_script("echo 'hi' >> runs.temp");
+        let runs = temp_dir.path().join("runs.temp");
+
+        let deliver = |client: &Client, path: &str| {
+            client
+                .post(path.to_owned())
+                .header(ContentType::JSON)
+                .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                .body("{}")
+                .dispatch()
+                .status()
+        };
+
+        let strict = [
+            (WEBHOOK_STRING, Some("VerySecure")),
+            (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+        ];
+        temp_env::with_vars(strict, || {
+            let client = Client::tracked(rocket()).expect("valid rocket instance");
+            assert_eq!(deliver(&client, "/webhook"), Status::NotFound);
+        });
+
+        let mut lenient = strict.to_vec();
+        lenient.push((LENIENT_PATH_STRING, Some("1")));
+        temp_env::with_vars(lenient, || {
+            let client = Client::tracked(rocket()).expect("valid rocket instance");
+            for path in [
+                "/webhook",
+                "/webhook/",
+                "/Webhook",
+                "/WEBHOOK/?from=github",
+                "/",
+            ] {
+                assert_eq!(deliver(&client, path), Status::Ok, "{path}");
+            }
+            assert_eq!(deliver(&client, "/webhooks"), Status::NotFound);
+            assert_eq!(deliver(&client, "/other/webhook"), Status::NotFound);
+
+            // Only deliveries are rerouted
+            let response = client.get("/Webhook").dispatch();
+            assert_eq!(response.status(), Status::NotFound);
+        });
+
+        // The scripts are left running in the background
+        let count = || std::fs::read_to_string(&runs).map_or(0, |runs| runs.lines().count());
+        for _ in 0..50 {
+            if count() >= 5 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(count(), 5);
+    }
+
+    #[rocket::async_test]
+    async fn heartbeat() {
+        temp_env::async_with_vars([(HEARTBEAT_STRING, Some("0.05"))], async {
+            let client = rocket::local::asynchronous::Client::tracked(rocket())
+                .await
+                .expect("valid rocket instance");
+
+            let mut attempts = 0;
+            while metrics::LAST_HEARTBEAT.load(Ordering::Relaxed) == 0 {
+                attempts += 1;
+                assert!(attempts < 50, "No heartbeat was emitted");
+                rocket::tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+
+            let metrics = client.get("/metrics").dispatch().await;
+            let metrics = metrics.into_string().await.expect("Metrics were sent");
+            assert!(metrics.contains("webhook_last_heartbeat_timestamp_seconds "));
+            assert!(!metrics.contains("webhook_last_heartbeat_timestamp_seconds 0\n"));
+        })
+        .await;
+    }
+
+    #[test]
+    fn redact_fields() {
+        let (temp_dir, script) = write_script("printf '%s' \"$PAYLOAD\" > payload.temp");
+        let output = temp_dir.path().join("payload.temp");
+        let payload =
+            r#"{"pusher":{"email":"dev@example.com","name":"dev"},"token":"ghp_x","n":1}"#;
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (PAYLOAD_ENV_STRING, Some("PAYLOAD")),
+                (REDACT_FIELDS_STRING, Some("/pusher/email, /token,/missing")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+
+                // The signature is checked against the payload as it was sent
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .header(ContentType::JSON)
+                    .header(Header::new(HEADER, sign("VerySecure", payload)))
+                    .body(payload)
+                    .dispatch();
+                assert_eq!(response.status(), Status::Ok);
+            },
+        );
+
+        assert!(wait_for_file(&output));
+        let received: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(output).unwrap()).unwrap();
+        assert_eq!(
+            received,
+            serde_json::json!({
+                "pusher": { "email": "***", "name": "dev" },
+                "token": "***",
+                "n": 1,
+            })
+        );
+    }
+
+    #[test]
+    fn result_cache() {
+        let (temp_dir, script) = write_script("echo 'hi' >> runs.temp");
+        let runs = temp_dir.path().join("runs.temp");
+        let count = || std::fs::read_to_string(&runs).map_or(0, |runs| runs.lines().count());
+        let wait_for_runs = |expected: usize| {
+            for _ in 0..50 {
+                if count() >= expected {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            // Give any unexpected run the chance to show up
+            std::thread::sleep(Duration::from_millis(50));
+            count()
+        };
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (RESULT_CACHE_STRING, Some("0.5")),
+                (PAYLOAD_DIGEST_STRING, Some("1")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |payload: &str, secret: &str| {
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign(secret, payload)))
+                        .body(payload)
+                        .dispatch()
+                };
+
+                let first = deliver("{\"n\": 1}", "VerySecure");
+                assert_eq!(first.status(), Status::Ok);
+                assert_eq!(wait_for_runs(1), 1);
+
+                // The repeat gets the same response without running the script
+                let repeat = deliver("{\"n\": 1}", "VerySecure");
+                assert_eq!(repeat.status(), Status::Ok);
+                assert_eq!(
+                    repeat.headers().get_one(PAYLOAD_DIGEST_HEADER),
+                    first.headers().get_one(PAYLOAD_DIGEST_HEADER)
+                );
+                assert_eq!(wait_for_runs(2), 1);
+
+                // Unauthenticated repeats are never handed the result
+                let forged = deliver("{\"n\": 1}", "NotTheSecret");
+                assert_eq!(forged.status(), Status::Unauthorized);
+
+                assert_eq!(deliver("{\"n\": 2}", "VerySecure").status(), Status::Ok);
+                assert_eq!(wait_for_runs(2), 2);
+
+                // Results are only reused until they expire
+                std::thread::sleep(Duration::from_millis(600));
+                assert_eq!(deliver("{\"n\": 1}", "VerySecure").status(), Status::Ok);
+                assert_eq!(wait_for_runs(3), 3);
+            },
+        );
+    }
+
+    #[test]
+    fn result_cache_checks_repeats() {
+        let (_temp_dir, script) = write_script("exit 0");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (RESULT_CACHE_STRING, Some("30")),
+                (REQUIRE_UA_PREFIX_STRING, Some("GitHub-Hookshot/")),
+                (REQUIRE_ALL_SIGS_STRING, Some("X-Other-Signature=Other")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |user_agent: &str, other_signature: bool| {
+                    let mut request = client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                        .header(Header::new("User-Agent", user_agent.to_owned()))
+                        .body("{}");
+                    if other_signature {
+                        request =
+                            request.header(Header::new("X-Other-Signature", sign("Other", "{}")));
+                    }
+                    request.dispatch().status()
+                };
+
+                assert_eq!(deliver("GitHub-Hookshot/1", true), Status::Ok);
+                assert_eq!(deliver("GitHub-Hookshot/1", true), Status::Ok);
+
+                // Repeats that would have been rejected aren't handed the cached result
+                assert_eq!(deliver("curl/8", true), Status::Forbidden);
+                assert_eq!(deliver("GitHub-Hookshot/1", false), Status::Unauthorized);
+            },
+        );
+    }
+
+    /// Stands in for a bug in handling a delivery.
+    fn buggy_delivery() -> WebhookResponse {
+        panic!("Asked to panic by the test");
+    }
+
+    /// A delivery route that panics whilst handling every delivery.
+    #[post("/panic")]
+    async fn panicking() -> WebhookResponse {
+        catch_panics(async { buggy_delivery() }).await
+    }
+
+    #[test]
+    fn panic_caught() {
+        let (temp_dir, script) = write_script("echo 'hi' > file.temp");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (RESPONSE_BUDGET_STRING, Some("5")),
+            ],
+            || {
+                let rocket = rocket().mount("/", routes![panicking]);
+                let client = Client::tracked(rocket).expect("valid rocket instance");
+
+                let response = client.post(uri!(panicking)).dispatch();
+                assert_eq!(response.status(), Status::InternalServerError);
+
+                // Later deliveries are unaffected
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .header(ContentType::JSON)
+                    .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                    .body("{}")
+                    .dispatch();
+                assert_eq!(response.status(), Status::Ok);
+                assert!(temp_dir.path().join("file.temp").exists());
+            },
+        );
+    }
+
+    #[test]
+    fn config_logged() {
+        let captured = logging::tests::Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (
+                    REQUIRE_ALL_SIGS_STRING,
+                    Some("X-Other-Signature=AlsoSecure"),
+                ),
+                (SCRIPT_STRING, Some("/srv/deploy.sh")),
+                ("WEBHOOK_UNKNOWN_SECRET", Some("StillSecure")),
+            ],
+            || tracing::subscriber::with_default(subscriber, log_config),
+        );
+
+        let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("WEBHOOK_SCRIPT=/srv/deploy.sh"));
+        assert!(logs.contains("WEBHOOK_SECRET=[redacted]"));
+        assert!(logs.contains("WEBHOOK_REQUIRE_ALL_SIGS=[redacted]"));
+        assert!(logs.contains("WEBHOOK_UNKNOWN_SECRET=[redacted]"));
+        assert!(!logs.contains("Secure"));
+    }
+
+    #[test]
+    fn require_https() {
+        let (_temp_dir, script) = write_script("true");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (REQUIRE_HTTPS_STRING, Some("1")),
+                (TRUSTED_PROXIES_STRING, Some("10.0.0.1, 10.0.0.2")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |remote: &str, proto: Option<&'static str>| {
+                    let request = client
+                        .post(uri!(webhook_listen))
+                        .remote(remote.parse().unwrap())
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                        .body("{}");
+                    match proto {
+                        Some(proto) => request.header(Header::new(FORWARDED_PROTO_HEADER, proto)),
+                        None => request,
+                    }
+                    .dispatch()
+                    .status()
+                };
+
+                assert_eq!(deliver("10.0.0.2:4000", Some("https")), Status::Ok);
+                assert_eq!(deliver("10.0.0.2:4000", Some("http")), Status::BadRequest);
+                assert_eq!(deliver("10.0.0.2:4000", None), Status::BadRequest);
+
+                // Only trusted proxies are believed
+                assert_eq!(deliver("10.0.0.9:4000", Some("https")), Status::BadRequest);
+            },
+        );
+    }
+
+    #[test]
+    fn cleanup_script() {
+        let (temp_dir, script) = write_script("exit \"$(cat exit_code.temp)\"");
+        let cleanup = temp_dir.path().join("cleanup.sh");
+        std::fs::write(
+            &cleanup,
+            format!(
+                "cd {}; echo \"$WEBHOOK_OUTCOME $WEBHOOK_EXIT_CODE\" >> cleanup.temp; exit 1",
+                temp_dir.path().to_str().expect("Valid Path")
+            ),
+        )
+        .expect("Able to write cleanup script");
+        let exit_with = |code: &str| {
+            std::fs::write(temp_dir.path().join("exit_code.temp"), code).unwrap();
+        };
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (
+                    CLEANUP_SCRIPT_STRING,
+                    Some(cleanup.to_str().expect("Valid Path")),
+                ),
+                (RESPONSE_BUDGET_STRING, Some("5")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = || {
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                        .body("{}")
+                        .dispatch()
+                        .status()
+                };
+
+                // The failing cleanup script doesn't change the response
+                exit_with("0");
+                assert_eq!(deliver(), Status::Ok);
+                exit_with("3");
+                assert_eq!(deliver(), Status::InternalServerError);
+            },
+        );
+
+        let cleanups = std::fs::read_to_string(temp_dir.path().join("cleanup.temp")).unwrap();
+        assert_eq!(cleanups, "success 0\nfailure 3\n");
+    }
+
+    #[test]
+    fn headers_env() {
+        let (temp_dir, script) = write_script("printf '%s' \"$REQUEST_HEADERS\" > headers.temp");
+        let output = temp_dir.path().join("headers.temp");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (HEADERS_ENV_STRING, Some("REQUEST_HEADERS")),
+                (REDACT_HEADERS_STRING, Some("1")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .header(ContentType::JSON)
+                    .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                    .header(Header::new(EVENT_HEADER, "push"))
+                    .header(Header::new("X-Custom", "one"))
+                    .header(Header::new("X-Custom", "two"))
+                    .body("{}")
+                    .dispatch();
+                assert_eq!(response.status(), Status::Ok);
+            },
+        );
+
+        assert!(wait_for_file(&output));
+        let headers: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(output).expect("Valid file"))
+                .expect("Valid json");
+        assert_eq!(headers["x-github-event"], "push");
+        assert_eq!(headers["x-custom"], "one, two");
+        assert_eq!(headers["x-hub-signature-256"], "[redacted]");
+    }
+
+    #[test]
+    fn warmup_script() {
+        let (_temp_dir, script) = write_script("sleep 0.2");
+        let ready = |client: &Client| client.get(uri!(ready)).dispatch().status();
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (
+                    WARMUP_SCRIPT_STRING,
+                    Some(script.to_str().expect("Valid Path")),
+                ),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                assert_eq!(ready(&client), Status::ServiceUnavailable);
+
+                let mut attempts = 0;
+                while ready(&client) != Status::Ok {
+                    attempts += 1;
+                    assert!(attempts < 50, "Never became ready");
+                    std::thread::sleep(Duration::from_millis(20));
+                }
+            },
+        );
+
+        std::fs::write(&script, "exit 1").expect("Able to write test script");
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (
+                    WARMUP_SCRIPT_STRING,
+                    Some(script.to_str().expect("Valid Path")),
+                ),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                std::thread::sleep(Duration::from_millis(300));
+                assert_eq!(ready(&client), Status::ServiceUnavailable);
+
+                // Deliveries are refused until ready
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .header(ContentType::JSON)
+                    .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                    .body("{}")
+                    .dispatch();
+                assert_eq!(response.status(), Status::ServiceUnavailable);
+            },
+        );
+
+        // Handlers without a warmup script are ready straight away
+        temp_env::with_var(WARMUP_SCRIPT_STRING, None::<&str>, || {
+            let client = Client::tracked(rocket()).expect("valid rocket instance");
+            assert_eq!(ready(&client), Status::Ok);
+        });
+    }
+
+    #[test]
+    fn ready_after_successes() {
+        let (_temp_dir, script) = write_script("exit 0");
+        let ready = |client: &Client| client.get(uri!(ready)).dispatch().status();
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (READY_AFTER_SUCCESSES_STRING, Some("2")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |secret: &str| {
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign(secret, "{}")))
+                        .body("{}")
+                        .dispatch()
+                        .status()
+                };
+                assert_eq!(ready(&client), Status::ServiceUnavailable);
+
+                // Failed deliveries don't count
+                assert_eq!(deliver("WrongSecret"), Status::Unauthorized);
+                assert_eq!(deliver("VerySecure"), Status::Ok);
+                assert_eq!(ready(&client), Status::ServiceUnavailable);
+
+                assert_eq!(deliver("VerySecure"), Status::Ok);
+                assert_eq!(ready(&client), Status::Ok);
+            },
+        );
+    }
+
+    #[test]
+    fn head_status() {
+        let (temp_dir, script) = write_script("echo 'hi' > file.temp");
+
+        temp_env::with_vars(
+            [
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (HEAD_STATUS_STRING, Some("204")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let response = client.head(uri!(webhook_listen)).dispatch();
+                assert_eq!(response.status(), Status::NoContent);
+            },
+        );
+
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(!temp_dir.path().join("file.temp").exists());
+
+        // Otherwise answered as the landing page would be
+        temp_env::with_vars(
+            [
+                (HEAD_STATUS_STRING, None),
+                (DISABLE_LANDING_STRING, Some("1")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let response = client.head(uri!(webhook_listen)).dispatch();
+                assert_eq!(response.status(), Status::NotFound);
+            },
+        );
+    }
+
+    #[test]
+    fn retry_exit_codes() {
+        let (temp_dir, script) = write_script("");
+        let count = temp_dir.path().join("count.temp");
+        let deliver = |client: &Client, fail_with: i32| {
+            let _ = std::fs::remove_file(&count);
+            std::fs::write(
+                &script,
+                format!(
+                    "n=$(cat {count} 2>/dev/null || echo 0); n=$((n+1)); echo $n > {count}; \
+                     [ $n -ge 2 ] || exit {fail_with}",
+                    count = count.display()
+                ),
+            )
+            .expect("Able to write test script");
+
+            let status = client
+                .post(uri!(webhook_listen))
+                .header(ContentType::JSON)
+                .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                .body("{}")
+                .dispatch()
+                .status();
+            let runs = std::fs::read_to_string(&count).expect("Script ran");
+            (status, runs.trim().parse::<u32>().expect("Valid count"))
+        };
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (RESPONSE_BUDGET_STRING, Some("5")),
+                (SCRIPT_RETRIES_STRING, Some("3")),
+                (RETRY_EXIT_CODES_STRING, Some("75")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                assert_eq!(deliver(&client, 75), (Status::Ok, 2));
+                assert_eq!(deliver(&client, 1), (Status::InternalServerError, 1));
+            },
+        );
+    }
+
+    #[rocket::async_test]
+    async fn max_deliveries_per_connection() {
+        use rocket::tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpStream,
+        };
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("Able to find a free port")
+            .port();
+
+        temp_env::async_with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some("script.sh")),
+                (connections::MAX_DELIVERIES_STRING, Some("3")),
+            ],
+            async {
+                let rocket = rocket();
+                let figment = rocket
+                    .figment()
+                    .clone()
+                    .merge(("address", "127.0.0.1"))
+                    .merge(("port", port));
+                let rocket = rocket
+                    .configure(figment)
+                    .ignite()
+                    .await
+                    .expect("Valid rocket instance");
+                let shutdown = rocket.shutdown();
+                rocket::tokio::spawn(rocket.launch());
+
+                let mut stream = loop {
+                    match TcpStream::connect(("127.0.0.1", port)).await {
+                        Ok(stream) => break stream,
+                        Err(_) => rocket::tokio::time::sleep(Duration::from_millis(20)).await,
+                    }
+                };
+
+                // Pipeline more deliveries than allowed on the one connection
+                let delivery =
+                    "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+                                X-Hub-Signature-256: sha256=0123acd\r\nContent-Length: 2\r\n\r\n{}";
+                stream
+                    .write_all(delivery.repeat(5).as_bytes())
+                    .await
+                    .unwrap();
+
+                let mut responses = Vec::new();
+                rocket::tokio::time::timeout(
+                    Duration::from_secs(5),
+                    stream.read_to_end(&mut responses),
+                )
+                .await
+                .expect("Connection was closed")
+                .unwrap();
+                let responses = String::from_utf8_lossy(&responses);
+                assert_eq!(responses.matches("HTTP/1.1 401").count(), 3);
+
+                shutdown.notify();
+            },
+        )
+        .await;
+    }
+
+    #[test]
+    fn routed_scripts_limited() {
+        let (temp_dir, workflow) =
+            write_script("ulimit -n > workflow.partial && mv workflow.partial workflow.temp");
+        let parallel = temp_dir.path().join("parallel.sh");
+        std::fs::write(
+            &parallel,
+            format!(
+                "ulimit -n > {}",
+                temp_dir.path().join("parallel.temp").display()
+            ),
+        )
+        .expect("Able to write test script");
+        let mapping = format!("push={}", parallel.to_str().expect("Valid Path"));
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (
+                    WORKFLOW_SCRIPT_STRING,
+                    Some(workflow.to_str().expect("Valid Path")),
+                ),
+                (PARALLEL_SCRIPTS_STRING, Some(mapping.as_str())),
+                (NOFILE_LIMIT_STRING, Some("64")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |event: &str| {
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                        .header(Header::new(EVENT_HEADER, event.to_owned()))
+                        .body("{}")
+                        .dispatch()
+                        .status()
+                };
+
+                assert_eq!(deliver("workflow_job"), Status::Ok);
+                let output = temp_dir.path().join("workflow.temp");
+                assert!(wait_for_file(&output));
+                assert_eq!(std::fs::read_to_string(output).unwrap().trim(), "64");
+
+                assert_eq!(deliver("push"), Status::Ok);
+                let output = temp_dir.path().join("parallel.temp");
+                assert_eq!(std::fs::read_to_string(output).unwrap().trim(), "64");
+            },
+        );
+    }
+
+    #[test]
+    fn workflow_script() {
+        let (temp_dir, deploy) = write_script("echo 'deploy' > deploy.temp");
+        let workflow = temp_dir.path().join("workflow.sh");
+        let output = temp_dir.path().join("workflow.temp");
+        std::fs::write(
+            &workflow,
+            format!(
+                "printf '%s|%s|%s|%s' \"$WEBHOOK_ACTION\" \"$WEBHOOK_WORKFLOW_NAME\" \
+                 \"$WEBHOOK_WORKFLOW_STATUS\" \"$WEBHOOK_WORKFLOW_CONCLUSION\" > {}",
+                output.display()
+            ),
+        )
+        .expect("Able to write test script");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(deploy.to_str().expect("Valid Path"))),
+                (
+                    WORKFLOW_SCRIPT_STRING,
+                    Some(workflow.to_str().expect("Valid Path")),
+                ),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |event: &str, payload: serde_json::Value| {
+                    let _ = std::fs::remove_file(&output);
+                    let payload = payload.to_string();
+                    let status = client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", &payload)))
+                        .header(Header::new(EVENT_HEADER, event.to_owned()))
+                        .body(payload)
+                        .dispatch()
+                        .status();
+                    assert_eq!(status, Status::Ok);
+                    assert!(wait_for_file(&output));
+                    std::fs::read_to_string(&output).expect("Valid file")
+                };
+
+                let job = serde_json::json!({
+                    "action": "completed",
+                    "workflow_job": { "name": "build", "status": "completed", "conclusion": "failure" },
+                });
+                assert_eq!(
+                    deliver("workflow_job", job),
+                    "completed|build|completed|failure"
+                );
+
+                let run = serde_json::json!({
+                    "action": "in_progress",
+                    "workflow_run": { "name": "CI", "status": "in_progress", "conclusion": null },
+                });
+                assert_eq!(deliver("workflow_run", run), "in_progress|CI|in_progress|");
+            },
+        );
+
+        // Deploy script never ran for the workflow events
+        assert!(!temp_dir.path().join("deploy.temp").exists());
+    }
+
+    #[test]
+    fn ref_scripts() {
+        let (temp_dir, deploy) = write_script("echo 'deploy' > deploy.temp");
+        let output = temp_dir.path().join("ref.temp");
+        let ref_script = |kind: &str| {
+            let path = temp_dir.path().join(format!("{kind}.sh"));
+            std::fs::write(
+                &path,
+                format!(
+                    "printf '{kind}|%s|%s' \"$WEBHOOK_REF_TYPE\" \"$WEBHOOK_REF\" > {}",
+                    output.display()
+                ),
+            )
+            .expect("Able to write test script");
+            path
+        };
+        let (create, delete) = (ref_script("create"), ref_script("delete"));
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(deploy.to_str().expect("Valid Path"))),
+                (
+                    CREATE_SCRIPT_STRING,
+                    Some(create.to_str().expect("Valid Path")),
+                ),
+                (
+                    DELETE_SCRIPT_STRING,
+                    Some(delete.to_str().expect("Valid Path")),
+                ),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |event: &str, payload: serde_json::Value| {
+                    let _ = std::fs::remove_file(&output);
+                    let payload = payload.to_string();
+                    let status = client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", &payload)))
+                        .header(Header::new(EVENT_HEADER, event.to_owned()))
+                        .body(payload)
+                        .dispatch()
+                        .status();
+                    assert_eq!(status, Status::Ok);
+                    assert!(wait_for_file(&output));
+                    std::fs::read_to_string(&output).expect("Valid file")
+                };
+
+                let created = serde_json::json!({
+                    "ref": "feature/preview",
+                    "ref_type": "branch",
+                    "master_branch": "main",
+                });
+                assert_eq!(deliver("create", created), "create|branch|feature/preview");
+
+                let deleted = serde_json::json!({ "ref": "v1.0.0", "ref_type": "tag" });
+                assert_eq!(deliver("delete", deleted), "delete|tag|v1.0.0");
+            },
+        );
+
+        // Deploy script never ran for the ref events
+        assert!(!temp_dir.path().join("deploy.temp").exists());
+    }
+
+    #[test]
+    fn require_user_agent_prefix() {
+        let (_temp_dir, script) = write_script("");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (REQUIRE_UA_PREFIX_STRING, Some("GitHub-Hookshot/")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |user_agent: &str, signature: &str| {
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new("User-Agent", user_agent.to_owned()))
+                        .header(Header::new(HEADER, signature.to_owned()))
+                        .body("{}")
+                        .dispatch()
+                        .status()
+                };
+
+                assert_eq!(
+                    deliver("GitHub-Hookshot/4a1b2c3", &sign("VerySecure", "{}")),
+                    Status::Ok
+                );
+                // Rejected before the signature is even looked at
+                assert_eq!(deliver("curl/8.5.0", "sha256=0123acd"), Status::Forbidden);
+            },
+        );
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let keep_alive = || {
+            rocket()
+                .figment()
+                .extract::<Config>()
+                .expect("Valid config")
+                .keep_alive
+        };
+
+        temp_env::with_var(IDLE_TIMEOUT_STRING, Some("2"), || {
+            assert_eq!(keep_alive(), 2);
+        });
+        temp_env::with_var(IDLE_TIMEOUT_STRING, Some("forever"), || {
+            assert_eq!(keep_alive(), Config::default().keep_alive);
+        });
+    }
+
+    #[test]
+    fn dashboard() {
+        let (_temp_dir, script) = write_script("exit 0");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (dashboard::ADMIN_TOKEN_STRING, Some("AdminToken")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                for event in ["push", "<script>"] {
+                    let response = client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                        .header(Header::new(EVENT_HEADER, event))
+                        .body("{}")
+                        .dispatch();
+                    assert_eq!(response.status(), Status::Ok);
+                }
+
+                let response = client
+                    .get(uri!(dashboard::dashboard))
+                    .header(Header::new("Authorization", "Bearer AdminToken"))
+                    .dispatch();
+                assert_eq!(response.status(), Status::Ok);
+                assert_eq!(response.content_type(), Some(ContentType::HTML));
+                let html = response.into_string().unwrap();
+                assert_eq!(html.matches("<tr class=\"delivery\">").count(), 2);
+                assert!(html.contains("<td>push</td><td>200 OK</td>"));
+                // Event names come from the client, so must never be rendered as markup
+                assert!(html.contains("<td>&lt;script&gt;</td>"));
+                assert!(!html.contains("<script>"));
+
+                let response = client
+                    .get(uri!(dashboard::dashboard))
+                    .header(Header::new("Authorization", "Bearer NotTheToken"))
+                    .dispatch();
+                assert_eq!(response.status(), Status::Unauthorized);
+                let response = client.get(uri!(dashboard::dashboard)).dispatch();
+                assert_eq!(response.status(), Status::Unauthorized);
+            },
+        );
+
+        // Without a token the dashboard doesn't exist
+        for token in [None, Some("")] {
+            temp_env::with_var(dashboard::ADMIN_TOKEN_STRING, token, || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let response = client.get(uri!(dashboard::dashboard)).dispatch();
+                assert_eq!(response.status(), Status::NotFound);
+                let response = client
+                    .get(uri!(dashboard::dashboard))
+                    .header(Header::new("Authorization", "Bearer "))
+                    .dispatch();
+                assert_eq!(response.status(), Status::NotFound);
+            });
+        }
+    }
+
+    #[test]
+    fn sniff_gzip() {
+        use std::io::Write;
+
+        let (temp_dir, script) = write_script("printf '%s' \"$PAYLOAD\" >> payloads.temp");
+        let output = temp_dir.path().join("payloads.temp");
+        let payload = "{\"n\":1}";
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(payload.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let deliver = |signed: &[u8]| {
+            let client = Client::tracked(rocket()).expect("valid rocket instance");
+            // Sent without a Content-Encoding header
+            let response = client
+                .post(uri!(webhook_listen))
+                .header(ContentType::JSON)
+                .header(Header::new(HEADER, sign("VerySecure", signed)))
+                .body(&compressed)
+                .dispatch();
+            response.status()
+        };
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (PAYLOAD_ENV_STRING, Some("PAYLOAD")),
+                (auth::SNIFF_GZIP_STRING, Some("1")),
+                (auth::SIGN_COMPRESSED_STRING, None),
+            ],
+            || {
+                // Signatures are of the bytes as sent by default
+                assert_eq!(deliver(payload.as_bytes()), Status::Unauthorized);
+                assert_eq!(deliver(&compressed), Status::Ok);
+                assert!(wait_for_file(&output));
+
+                temp_env::with_var(auth::SIGN_COMPRESSED_STRING, Some("0"), || {
+                    assert_eq!(deliver(&compressed), Status::Unauthorized);
+                    assert_eq!(deliver(payload.as_bytes()), Status::Ok);
+                });
+            },
+        );
+
+        // Both deliveries were handed to the script decompressed
+        for _ in 0..50 {
+            if std::fs::read_to_string(&output).unwrap() == payload.repeat(2) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        panic!("Script did not receive both decompressed payloads");
+    }
+
+    #[test]
+    fn environment() {
+        let (temp_dir, script) = write_script("printf '%s' \"$DEPLOY_ENVIRONMENT\" > env.temp");
+        let output = temp_dir.path().join("env.temp");
+        let parallel = temp_dir.path().join("parallel.sh");
+        let parallel_output = temp_dir.path().join("parallel.temp");
+        std::fs::write(
+            &parallel,
+            format!(
+                "printf '%s' \"$DEPLOY_ENVIRONMENT\" > {}",
+                parallel_output.display()
+            ),
+        )
+        .expect("Able to write test script");
+        let mapping = format!("release={}", parallel.to_str().expect("Valid Path"));
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (PARALLEL_SCRIPTS_STRING, Some(mapping.as_str())),
+                (ENVIRONMENT_STRING, Some("prod \"eu\"")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .header(ContentType::JSON)
+                    .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                    .body("{}")
+                    .dispatch();
+                assert_eq!(response.status(), Status::Ok);
+
+                let metrics = client.get(uri!(metrics::metrics)).dispatch();
+                let metrics = metrics.into_string().unwrap();
+                assert!(metrics
+                    .contains("\nwebhook_deliveries_total{environment=\"prod \\\"eu\\\"\"} "));
+                assert!(metrics.contains(
+                    "\nwebhook_scripts_finished_total{environment=\"prod \\\"eu\\\"\"} "
+                ));
+
+                // Scripts are told the environment however they are dispatched
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .header(ContentType::JSON)
+                    .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                    .header(Header::new(EVENT_HEADER, "release"))
+                    .body("{}")
+                    .dispatch();
+                assert_eq!(response.status(), Status::Ok);
+                assert_eq!(
+                    std::fs::read_to_string(&parallel_output).unwrap(),
+                    "prod \"eu\""
+                );
+            },
+        );
+
+        assert!(wait_for_file(&output));
+        assert_eq!(std::fs::read_to_string(output).unwrap(), "prod \"eu\"");
+    }
+
+    #[test]
+    fn validate_delivery_uuid() {
+        let (_temp_dir, script) = write_script("exit 0");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (VALIDATE_DELIVERY_UUID_STRING, Some("1")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |id: Option<&'static str>| {
+                    let request = client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                        .body("{}");
+                    match id {
+                        Some(id) => request.header(Header::new(DELIVERY_HEADER, id)),
+                        None => request,
+                    }
+                    .dispatch()
+                    .status()
+                };
+
+                let valid = Some("72d3162e-cc78-11e3-81ab-4c9367dc0958");
+                assert_eq!(deliver(valid), Status::Ok);
+                assert_eq!(deliver(Some("not-a-uuid")), Status::BadRequest);
+                assert_eq!(
+                    deliver(Some("72d3162e-cc78-11e3-81ab-4c9367dc095g")),
+                    Status::BadRequest
+                );
+                assert_eq!(deliver(None), Status::BadRequest);
+            },
+        );
+    }
+
+    #[test]
+    fn debug_env() {
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some("/srv/deploy.sh")),
+                (dashboard::ADMIN_TOKEN_STRING, Some("AdminToken")),
+                ("WEBHOOK_UNKNOWN_SECRET", Some("StillSecure")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let response = client.get(uri!(debug_env)).dispatch();
+                assert_eq!(response.status(), Status::Unauthorized);
+
+                let response = client
+                    .get(uri!(debug_env))
+                    .header(Header::new("Authorization", "Bearer AdminToken"))
+                    .dispatch();
+                assert_eq!(response.status(), Status::Ok);
+                let listed = response.into_string().unwrap();
+                assert!(listed.contains("\nWEBHOOK_SCRIPT=/srv/deploy.sh\n"));
+                assert!(listed.contains("\nWEBHOOK_SECRET=[redacted]\n"));
+                assert!(listed.contains("WEBHOOK_ADMIN_TOKEN=[redacted]\n"));
+                assert!(listed.contains("\nWEBHOOK_UNKNOWN_SECRET=[redacted]\n"));
+                assert!(!listed.contains("Secure"));
+                assert!(!listed.contains("AdminToken"));
+            },
+        );
+    }
+
+    #[test]
+    fn event_response_budgets() {
+        let (_temp_dir, script) = write_script("sleep 0.5");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (RESPONSE_BUDGET_STRING, Some("2")),
+                (EVENT_BUDGETS_STRING, Some("ping=0.1, push=1.5")),
+                (MAX_BUDGET_STRING, None),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |event: &'static str| {
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                        .header(Header::new(EVENT_HEADER, event))
+                        .body("{}")
+                        .dispatch()
+                };
+
+                // The slow script outlasts only the short budget
+                let response = deliver("ping");
+                assert_eq!(response.status(), Status::Accepted);
+                let status_url = response
+                    .headers()
+                    .get_one("Location")
+                    .expect("Has status url")
+                    .to_owned();
+                assert_eq!(deliver("push").status(), Status::Ok);
+                // Events without a budget of their own fall back to the global one
+                assert_eq!(deliver("release").status(), Status::Ok);
+
+                // Budgets only limit how long the response waits, the script isn't stopped
+                let status = client.get(status_url.as_str()).dispatch();
+                assert_eq!(
+                    status.into_json::<serde_json::Value>().expect("Valid json")["status"],
+                    "finished"
+                );
+            },
+        );
+
+        temp_env::with_vars(
+            [
+                (RESPONSE_BUDGET_STRING, Some("2")),
+                (EVENT_BUDGETS_STRING, Some("ping=soon")),
+                (MAX_BUDGET_STRING, None),
+            ],
+            || {
+                let mut headers = HeaderMap::new();
+                headers.add(Header::new(EVENT_HEADER, "ping"));
+                assert!(matches!(
+                    super::response_budget(&headers),
+                    Err(BudgetError::Seconds(_))
+                ));
+            },
+        );
+    }
+
+    #[test]
+    fn require_event_header() {
+        let (_temp_dir, script) = write_script("exit 0");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (REQUIRE_EVENT_HEADER_STRING, Some("1")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |event: Option<&'static str>| {
+                    let request = client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                        .body("{}");
+                    match event {
+                        Some(event) => request.header(Header::new(EVENT_HEADER, event)),
+                        None => request,
+                    }
+                    .dispatch()
+                    .status()
+                };
+
+                assert_eq!(deliver(Some("push")), Status::Ok);
+                assert_eq!(deliver(None), Status::BadRequest);
+            },
+        );
+    }
+
+    #[test]
+    fn log_script_env() {
+        let (_temp_dir, script) = write_script("exit 0");
+        let captured = logging::tests::Captured::default();
+        let writer = captured.clone();
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (LOG_SCRIPT_ENV_STRING, Some("1")),
+                (logging::BUFFER_DELIVERIES_STRING, None),
+                (ENVIRONMENT_STRING, Some("prod")),
+                (HEADERS_ENV_STRING, Some("HEADERS")),
+                (REDACT_HEADERS_STRING, None),
+            ],
+            || {
+                // Logged through the same subscriber the handler installs
+                let subscriber = logging::subscriber(move || writer.clone(), false);
+                tracing::subscriber::with_default(subscriber, || {
+                    let client = Client::tracked(rocket()).expect("valid rocket instance");
+                    let response = client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                        .header(Header::new(auth::GITLAB_TOKEN_HEADER, "AlsoSecure"))
+                        .body("{}")
+                        .dispatch();
+                    assert_eq!(response.status(), Status::Ok);
+                })
+            },
+        );
+
+        let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("Script environment DEPLOY_ENVIRONMENT=prod"));
+        assert!(logs.contains("Script environment HEADERS={"));
+        assert!(logs.contains("\"x-gitlab-token\":\"[redacted]\""));
+        assert!(!logs.contains("AlsoSecure"));
+        // Variables inherited from the handler aren't logged
+        assert!(!logs.contains("Script environment WEBHOOK_"));
+    }
+
+    #[test]
+    fn batch_deliveries() {
+        let (temp_dir, script) = write_script("cat >> batches.temp; echo >> batches.temp");
+        let output = temp_dir.path().join("batches.temp");
+        let batches = || -> Vec<serde_json::Value> {
+            std::fs::read_to_string(&output)
+                .unwrap_or_default()
+                .lines()
+                .map(|batch| serde_json::from_str(batch).unwrap())
+                .collect()
+        };
+        let wait_for_batches = |expected: usize| {
+            for _ in 0..100 {
+                if batches().len() >= expected {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            batches()
+        };
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (batch::BATCH_WINDOW_STRING, Some("0.5")),
+                (batch::BATCH_MAX_STRING, Some("4")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |n: usize| {
+                    let payload = format!("{{\"n\":{n}}}");
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", &payload)))
+                        .body(payload)
+                        .dispatch()
+                        .status()
+                };
+
+                // Deliveries within the window are all run at once when it passes
+                for n in 1..=3 {
+                    assert_eq!(deliver(n), Status::Accepted);
+                }
+                assert!(batches().is_empty());
+                assert_eq!(
+                    wait_for_batches(1),
+                    [serde_json::json!([{ "n": 1 }, { "n": 2 }, { "n": 3 }])]
+                );
+
+                // Full batches are run without waiting for the window
+                for n in 4..=7 {
+                    assert_eq!(deliver(n), Status::Accepted);
+                }
+                assert_eq!(
+                    wait_for_batches(2)[1],
+                    serde_json::json!([{ "n": 4 }, { "n": 5 }, { "n": 6 }, { "n": 7 }])
+                );
+            },
+        );
+    }
+
+    #[rocket::async_test]
+    async fn strict_startup() {
+        let scripts = [
+            (BRANCH_SCRIPTS_STRING, None),
+            (MANAGEMENT_SCRIPT_STRING, None),
+            (WORKFLOW_SCRIPT_STRING, None),
+            (CREATE_SCRIPT_STRING, None),
+            (DELETE_SCRIPT_STRING, None),
+            (COMMENT_SCRIPT_STRING, None),
+            (auth::PROVIDER_STRING, None),
+            (auth::ROTATION_FILE_STRING, None),
+            (STRICT_STARTUP_STRING, Some("1")),
+        ];
+        // Rocket panics if its launch errors are dropped without being looked at
+        let refused = || async {
+            rocket()
+                .ignite()
+                .await
+                .is_err_and(|err| matches!(err.kind(), rocket::error::ErrorKind::FailedFairings(_)))
+        };
+        let with = |vars: [(&'static str, Option<&'static str>); 2]| {
+            scripts.iter().copied().chain(vars).collect::<Vec<_>>()
+        };
+
+        temp_env::async_with_vars(
+            with([
+                (WEBHOOK_STRING, None),
+                (SCRIPT_STRING, Some("/srv/deploy.sh")),
+            ]),
+            async { assert!(refused().await) },
+        )
+        .await;
+        temp_env::async_with_vars(
+            with([(WEBHOOK_STRING, Some("VerySecure")), (SCRIPT_STRING, None)]),
+            async { assert!(refused().await) },
+        )
+        .await;
+        temp_env::async_with_vars(
+            with([
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some("/srv/deploy.sh")),
+            ]),
+            async { assert!(!refused().await) },
+        )
+        .await;
+
+        // Otherwise missing configuration only fails deliveries
+        temp_env::async_with_vars(
+            [
+                (STRICT_STARTUP_STRING, None::<&str>),
+                (WEBHOOK_STRING, None),
+                (SCRIPT_STRING, None),
+            ],
+            async { assert!(!refused().await) },
+        )
+        .await;
+    }
+
+    #[test]
+    fn comment_commands() {
+        let (temp_dir, deploy) = write_script("echo 'deploy' > deploy.temp");
+        let output = temp_dir.path().join("comment.temp");
+        let comment_script = temp_dir.path().join("comment.sh");
+        std::fs::write(
+            &comment_script,
+            format!(
+                "printf '%s|%s' \"$WEBHOOK_COMMAND\" \"$WEBHOOK_PR_NUMBER\" >> {}",
+                output.display()
+            ),
+        )
+        .expect("Able to write test script");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(deploy.to_str().expect("Valid Path"))),
+                (
+                    COMMENT_SCRIPT_STRING,
+                    Some(comment_script.to_str().expect("Valid Path")),
+                ),
+                (COMMENT_COMMAND_REGEX_STRING, Some(r"^/(deploy|rollback)\b")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |event: &str, payload: serde_json::Value| {
+                    let payload = payload.to_string();
+                    let status = client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", &payload)))
+                        .header(Header::new(EVENT_HEADER, event.to_owned()))
+                        .body(payload)
+                        .dispatch()
+                        .status();
+                    assert_eq!(status, Status::Ok);
+                };
+                let comment = |body: &str| {
+                    serde_json::json!({
+                        "action": "created",
+                        "issue": { "number": 42, "pull_request": {} },
+                        "comment": { "body": body },
+                    })
+                };
+
+                deliver("issue_comment", comment("Looks good to me"));
+                deliver("issue_comment", comment("/deploy staging please"));
+                deliver(
+                    "pull_request_review",
+                    serde_json::json!({
+                        "action": "submitted",
+                        "pull_request": { "number": 7 },
+                        "review": { "body": "/rollback" },
+                    }),
+                );
+
+                assert!(wait_for_file(&output));
+                for _ in 0..50 {
+                    if std::fs::read_to_string(&output).unwrap().len()
+                        >= "deploy|42rollback|7".len()
+                    {
+                        break;
+                    }
+                    std::thread::sleep(Duration::from_millis(20));
+                }
+            },
+        );
+
+        // Only the matching comments ran the comment script, and never the deploy script
+        let ran = std::fs::read_to_string(&output).unwrap();
+        assert!(
+            ran == "deploy|42rollback|7" || ran == "rollback|7deploy|42",
+            "{ran}"
+        );
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!temp_dir.path().join("deploy.temp").exists());
+    }
+
+    #[test]
+    fn compress_responses() {
+        use std::io::Read;
+
+        temp_env::with_var(COMPRESS_RESPONSES_STRING, Some("1"), || {
+            let client = Client::tracked(rocket()).expect("valid rocket instance");
+
+            let response = client
+                .get(uri!(metrics::metrics))
+                .header(Header::new("Accept-Encoding", "br;q=1.0, gzip;q=0.8"))
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(response.headers().get_one("Content-Encoding"), Some("gzip"));
+            let compressed = response.into_bytes().unwrap();
+            let mut metrics = String::new();
+            flate2::read::GzDecoder::new(&compressed[..])
+                .read_to_string(&mut metrics)
+                .expect("Valid gzip");
+            assert!(metrics.contains("\nwebhook_deliveries_total"));
+
+            // Clients refusing gzip get the response as is
+            let response = client
+                .get(uri!(metrics::metrics))
+                .header(Header::new("Accept-Encoding", "gzip;q=0"))
+                .dispatch();
+            assert_eq!(response.headers().get_one("Content-Encoding"), None);
+            assert!(response
+                .into_string()
+                .unwrap()
+                .contains("webhook_deliveries_total"));
+
+            // Deliveries are never compressed
+            let response = client
+                .post(uri!(webhook_listen))
+                .header(ContentType::JSON)
+                .header(Header::new("Accept-Encoding", "gzip"))
+                .body("{}")
+                .dispatch();
+            assert_eq!(response.headers().get_one("Content-Encoding"), None);
+        });
+    }
+
+    #[test]
+    fn payload_transform() {
+        let (temp_dir, script) = write_script("printf '%s' \"$PAYLOAD\" > payload.temp");
+        let output = temp_dir.path().join("payload.temp");
+        let payload = serde_json::json!({
+            "ref": "refs/heads/main",
+            "repository": { "full_name": "tye-exe/website", "private": false },
+            "pusher": { "name": "dev" },
+        })
+        .to_string();
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (PAYLOAD_ENV_STRING, Some("PAYLOAD")),
+                (
+                    PAYLOAD_TRANSFORM_STRING,
+                    Some("repo=/repository/full_name, branch=/ref,by=/pusher/name,missing=/nope"),
+                ),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .header(ContentType::JSON)
+                    .header(Header::new(HEADER, sign("VerySecure", &payload)))
+                    .body(&payload)
+                    .dispatch();
+                assert_eq!(response.status(), Status::Ok);
+            },
+        );
+
+        assert!(wait_for_file(&output));
+        let received: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(output).unwrap()).unwrap();
+        assert_eq!(
+            received,
+            serde_json::json!({
+                "repo": "tye-exe/website",
+                "branch": "refs/heads/main",
+                "by": "dev",
+                "missing": null,
+            })
+        );
+    }
+
+    #[test]
+    fn last_error() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let (_temp_dir, script) = write_script("exit 3");
+        let subscriber = tracing_subscriber::registry().with(last_error::Recorder);
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (RESPONSE_BUDGET_STRING, Some("5")),
+                (dashboard::ADMIN_TOKEN_STRING, Some("AdminToken")),
+            ],
+            || {
+                tracing::subscriber::with_default(subscriber, || {
+                    let client = Client::tracked(rocket()).expect("valid rocket instance");
+                    let response = client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                        .body("{}")
+                        .dispatch();
+                    assert_eq!(response.status(), Status::InternalServerError);
+
+                    let response = client.get(uri!(last_error::last_error)).dispatch();
+                    assert_eq!(response.status(), Status::Unauthorized);
+
+                    let response = client
+                        .get(uri!(last_error::last_error))
+                        .header(Header::new("Authorization", "Bearer AdminToken"))
+                        .dispatch();
+                    assert_eq!(response.status(), Status::Ok);
+                    let error: serde_json::Value = response.into_json().unwrap();
+                    assert_eq!(error["subsystem"], "handler");
+                    assert_eq!(error["message"], "Bash script failed: exit status: 3");
+                    assert!(error["time"].as_str().is_some_and(|time| !time.is_empty()));
+                })
+            },
+        );
+    }
+
+    #[test]
+    fn signed_deliveries_recorded() {
+        let (temp_dir, script) = write_script("exit 0");
+        let database = temp_dir.path().join("history.db");
+        let payload = "{}";
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (dashboard::ADMIN_TOKEN_STRING, Some("AdminToken")),
+                (detached::DETACHED_TIMEOUT_STRING, Some("30")),
+                (
+                    history::HISTORY_DB_STRING,
+                    Some(database.to_str().expect("Valid Path")),
+                ),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .header(ContentType::JSON)
+                    .header(Header::new(detached::TOKEN_HEADER, "token"))
+                    .header(Header::new(EVENT_HEADER, "push"))
+                    .header(Header::new(DELIVERY_HEADER, "held"))
+                    .body(payload)
+                    .dispatch();
+                assert_eq!(response.status(), Status::Accepted);
+                let response = client
+                    .post(uri!(sign_delivery("token")))
+                    .header(Header::new(HEADER, sign("VerySecure", payload)))
+                    .dispatch();
+                assert_eq!(response.status(), Status::Ok);
+
+                // Held deliveries are only recorded once they have been signed & handled
+                let response = client
+                    .get("/history")
+                    .header(Header::new("Authorization", "Bearer AdminToken"))
+                    .dispatch();
+                let history: serde_json::Value = response.into_json().unwrap();
+                assert_eq!(history.as_array().map(Vec::len), Some(1));
+                assert_eq!(history[0]["id"], "held");
+                assert_eq!(history[0]["event"], "push");
+                assert_eq!(history[0]["outcome"], 200);
+
+                let response = client
+                    .get(uri!(dashboard::dashboard))
+                    .header(Header::new("Authorization", "Bearer AdminToken"))
+                    .dispatch();
+                let html = response.into_string().unwrap();
+                assert_eq!(html.matches("<tr class=\"delivery\">").count(), 1);
+                assert!(html.contains("<td>push</td><td>200 OK</td>"));
+            },
+        );
+    }
+
+    #[test]
+    fn history() {
+        let (temp_dir, script) = write_script("exit 0");
+        let database = temp_dir.path().join("history.db");
+        let payload = r#"{"repository": {"full_name": "octo/app"}}"#;
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (dashboard::ADMIN_TOKEN_STRING, Some("AdminToken")),
+                (
+                    history::HISTORY_DB_STRING,
+                    Some(database.to_str().expect("Valid Path")),
+                ),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .header(ContentType::JSON)
+                    .header(Header::new(HEADER, sign("VerySecure", payload)))
+                    .header(Header::new(EVENT_HEADER, "push"))
+                    .header(Header::new(DELIVERY_HEADER, "first"))
+                    .body(payload)
+                    .dispatch();
+                assert_eq!(response.status(), Status::Ok);
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .header(ContentType::JSON)
+                    .header(Header::new(HEADER, sign("NotTheSecret", "{}")))
+                    .body("{}")
+                    .dispatch();
+                assert_eq!(response.status(), Status::Unauthorized);
+
+                let response = client.get("/history").dispatch();
+                assert_eq!(response.status(), Status::Unauthorized);
+
+                let response = client
+                    .get("/history")
+                    .header(Header::new("Authorization", "Bearer AdminToken"))
+                    .dispatch();
+                assert_eq!(response.status(), Status::Ok);
+                let history: serde_json::Value = response.into_json().unwrap();
+                assert_eq!(history[0]["outcome"], 401);
+                assert_eq!(history[0]["id"], serde_json::Value::Null);
+                assert_eq!(history[1]["id"], "first");
+                assert_eq!(history[1]["event"], "push");
+                assert_eq!(history[1]["repo"], "octo/app");
+                assert_eq!(history[1]["outcome"], 200);
+
+                let response = client
+                    .get("/history?limit=1")
+                    .header(Header::new("Authorization", "Bearer AdminToken"))
+                    .dispatch();
+                let history: serde_json::Value = response.into_json().unwrap();
+                assert_eq!(history.as_array().map(Vec::len), Some(1));
+            },
+        );
+
+        // Without a database there's no history to serve
+        temp_env::with_vars(
+            [
+                (dashboard::ADMIN_TOKEN_STRING, Some("AdminToken")),
+                (history::HISTORY_DB_STRING, None),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let response = client
+                    .get("/history")
+                    .header(Header::new("Authorization", "Bearer AdminToken"))
+                    .dispatch();
+                assert_eq!(response.status(), Status::NotFound);
+            },
+        );
+    }
+
+    #[test]
+    fn tenants() {
+        let (acme_dir, acme_script) = write_script("printf '%s' \"$WEBHOOK_TENANT\" > tenant.temp");
+        let (globex_dir, globex_script) =
+            write_script("printf '%s' \"$WEBHOOK_TENANT\" > tenant.temp");
+        let scripts = format!(
+            "acme={},globex={}",
+            acme_script.to_str().expect("Valid Path"),
+            globex_script.to_str().expect("Valid Path")
+        );
 
-    // Execute script
-    match Command::new("bash").arg(path).spawn() {
-        Ok(_) => {}
-        Err(err) => {
-            eprintln!("Could not execute bash script: {err}");
-            return Status::InternalServerError;
-        }
-    };
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("Global")),
+                (tenant::TENANT_SCRIPTS_STRING, Some(scripts.as_str())),
+                (
+                    tenant::TENANT_SECRETS_STRING,
+                    Some("acme=AcmeSecret,globex=GlobexSecret"),
+                ),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |path: &str, secret: &str| {
+                    client
+                        .post(path.to_owned())
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign(secret, "{}")))
+                        .body("{}")
+                        .dispatch()
+                        .status()
+                };
 
-    Status::Ok
-}
+                // Tenants can't sign as each other, or with the global secret
+                assert_eq!(
+                    deliver("/t/acme/hook", "GlobexSecret"),
+                    Status::Unauthorized
+                );
+                assert_eq!(deliver("/t/acme/hook", "Global"), Status::Unauthorized);
+                assert_eq!(deliver("/t/unknown/hook", "Global"), Status::NotFound);
+                assert_eq!(deliver("/t/acme/hook", "AcmeSecret"), Status::Ok);
 
-/// The possible errors when checking that the received signature is correct.
-#[derive(thiserror::Error, Debug)]
-enum SignatureError {
-    #[error("The received signature contained non-ascii chars.")]
-    NotASCII,
-    #[error("The received signature is not valid hexadecimal: {0}")]
-    BadHex(#[from] hex::FromHexError),
-    #[error("Error when validating signature: {0}")]
-    ValidationError(#[from] MacError),
-}
-
-/// Check if the payload signature is generated from the given secret
-fn signature_matches<'a>(
-    secret: &str,
-    payload: &str,
-    signature: XHubSignature,
-) -> Result<(), SignatureError> {
-    let XHubSignature { signature } = signature;
-
-    // Remove the "sha256=" from start of signature
-    let hex_signature = signature
-        .split_at_checked(7)
-        .ok_or_else(|| SignatureError::NotASCII)?
-        .1;
-
-    // Using let binding to create longer lived value
-    let binding = hex::decode(hex_signature)?;
-    let raw_signature = binding.as_slice();
-
-    Ok(
-        hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes())
-            .unwrap()
-            .chain_update(payload)
-            .verify_slice(raw_signature)?,
-    )
-}
+                let metrics = client.get(uri!(metrics::metrics)).dispatch();
+                let metrics = metrics.into_string().unwrap();
+                assert!(metrics.contains("\nwebhook_tenant_deliveries_total{tenant=\"acme\"} "));
+                assert!(!metrics.contains("tenant=\"unknown\""));
 
-/// The GitHub webhook payload signature
-struct XHubSignature<'a> {
-    signature: &'a str,
-}
+                assert!(wait_for_file(&acme_dir.path().join("tenant.temp")));
+                assert!(!globex_dir.path().join("tenant.temp").exists());
 
-#[rocket::async_trait]
-impl<'a> FromRequest<'a> for XHubSignature<'a> {
-    type Error = ();
+                assert_eq!(deliver("/t/globex/hook", "GlobexSecret"), Status::Ok);
+            },
+        );
 
-    async fn from_request(request: &'a Request<'_>) -> Outcome<Self, ()> {
-        match request.headers().get_one(HEADER) {
-            Some(signature) => Outcome::Success(Self { signature }),
-            None => Outcome::Error((Status::BadRequest, ())),
-        }
+        let globex = globex_dir.path().join("tenant.temp");
+        assert!(wait_for_file(&globex));
+        assert_eq!(std::fs::read_to_string(globex).unwrap(), "globex");
+        let acme = acme_dir.path().join("tenant.temp");
+        assert_eq!(std::fs::read_to_string(acme).unwrap(), "acme");
     }
-}
 
-#[launch]
-fn launch() -> _ {
-    // This way still allows for customistion via ENV.
-    let config = Config::figment().merge((
-        Config::LIMITS,
-        Limits::new().limit("string", 32.kibibytes()),
-    ));
+    #[test]
+    fn tenant_rate_limit() {
+        let (_acme_dir, acme_script) = write_script("");
+        let (_globex_dir, globex_script) = write_script("");
+        let scripts = format!(
+            "acme={},globex={}",
+            acme_script.to_str().expect("Valid Path"),
+            globex_script.to_str().expect("Valid Path")
+        );
 
-    rocket::build()
-        .configure(config)
-        .mount("/", routes![listen, webhook_listen])
-}
+        temp_env::with_vars(
+            [
+                (tenant::TENANT_SCRIPTS_STRING, Some(scripts.as_str())),
+                (
+                    tenant::TENANT_SECRETS_STRING,
+                    Some("acme=AcmeSecret,globex=GlobexSecret"),
+                ),
+                (tenant::TENANT_RATE_LIMIT_STRING, Some("3")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |path: &str, secret: &str| {
+                    client
+                        .post(path.to_owned())
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign(secret, "{}")))
+                        .body("{}")
+                        .dispatch()
+                        .status()
+                };
 
-#[cfg(test)]
-mod tests {
-    use rocket::{http::Header, local::blocking::Client, uri};
+                // Forged deliveries don't use up the limit
+                assert_eq!(deliver("/t/acme/hook", "Forged"), Status::Unauthorized);
+                for _ in 0..3 {
+                    assert_eq!(deliver("/t/acme/hook", "AcmeSecret"), Status::Ok);
+                }
+                assert_eq!(
+                    deliver("/t/acme/hook", "AcmeSecret"),
+                    Status::TooManyRequests
+                );
 
-    use super::*;
+                // A busy tenant doesn't throttle the others
+                assert_eq!(deliver("/t/globex/hook", "GlobexSecret"), Status::Ok);
+            },
+        );
+    }
 
     #[test]
-    fn no_env() {
-        temp_env::with_vars_unset([WEBHOOK_STRING, SCRIPT_STRING], || {
-            let client = Client::tracked(launch()).expect("valid rocket instance");
-            let response = client
-                .post(uri!(webhook_listen))
-                .json(&"{}")
-                .header(Header::new(HEADER, "N/A"))
-                .dispatch();
+    fn tenants_skip_global_overrides() {
+        let (tenant_dir, tenant_script) = write_script("echo 'tenant' >> tenant.temp");
+        let (global_dir, global_script) = write_script("echo 'global' > global.temp");
+        let tenant_scripts = format!("acme={}", tenant_script.to_str().expect("Valid Path"));
+        let global_script = global_script.to_str().expect("Valid Path");
+        let branch_scripts = format!("main={global_script}");
+        let parallel_scripts = format!("push={global_script}");
+        let payload = r#"{"ref": "refs/heads/main", "ref_type": "branch", "action": "created"}"#;
 
-            assert_eq!(response.status(), Status::InternalServerError);
-        });
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("Global")),
+                (tenant::TENANT_SCRIPTS_STRING, Some(tenant_scripts.as_str())),
+                (tenant::TENANT_SECRETS_STRING, Some("acme=AcmeSecret")),
+                (SCRIPT_STRING, Some(global_script)),
+                (BRANCH_SCRIPTS_STRING, Some(branch_scripts.as_str())),
+                (MANAGEMENT_SCRIPT_STRING, Some(global_script)),
+                (WORKFLOW_SCRIPT_STRING, Some(global_script)),
+                (CREATE_SCRIPT_STRING, Some(global_script)),
+                (DELETE_SCRIPT_STRING, Some(global_script)),
+                (COMMENT_SCRIPT_STRING, Some(global_script)),
+                (PARALLEL_SCRIPTS_STRING, Some(parallel_scripts.as_str())),
+                (REQUIRE_ALL_SIGS_STRING, Some("X-Other-Signature=Other")),
+                (batch::BATCH_WINDOW_STRING, Some("30")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                for event in ["push", "create", "workflow_run", "installation"] {
+                    let response = client
+                        .post("/t/acme/hook")
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("AcmeSecret", payload)))
+                        .header(Header::new(EVENT_HEADER, event))
+                        .body(payload)
+                        .dispatch();
+                    assert_eq!(response.status(), Status::Ok, "{event} was not run");
+                }
+            },
+        );
+
+        assert!(wait_for_file(&tenant_dir.path().join("tenant.temp")));
+        assert!(!global_dir.path().join("global.temp").exists());
     }
 
     #[test]
-    fn no_signature() {
+    fn tenant_detached_signature() {
+        let (temp_dir, script) = write_script("printf '%s' \"$WEBHOOK_TENANT\" > tenant.temp");
+        let scripts = format!("acme={}", script.to_str().expect("Valid Path"));
+
         temp_env::with_vars(
-            [(WEBHOOK_STRING, None), (SCRIPT_STRING, Some("script.sh"))],
+            [
+                (WEBHOOK_STRING, Some("Global")),
+                (SCRIPT_STRING, None),
+                (tenant::TENANT_SCRIPTS_STRING, Some(scripts.as_str())),
+                (tenant::TENANT_SECRETS_STRING, Some("acme=AcmeSecret")),
+                (detached::DETACHED_TIMEOUT_STRING, Some("5")),
+            ],
             || {
-                let client = Client::tracked(launch()).expect("valid rocket instance");
-                let response = client
-                    .post(uri!(webhook_listen))
-                    .json(&"{}")
-                    .header(Header::new(HEADER, "Not_A_Match"))
-                    .dispatch();
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let hold = |token: &str| {
+                    client
+                        .post("/t/acme/hook")
+                        .header(ContentType::JSON)
+                        .header(Header::new(detached::TOKEN_HEADER, token.to_owned()))
+                        .body("{}")
+                        .dispatch()
+                        .status()
+                };
+                let sign_with = |token: &str, secret: &str| {
+                    client
+                        .post(uri!(sign_delivery(token)))
+                        .header(Header::new(HEADER, sign(secret, "{}")))
+                        .dispatch()
+                        .status()
+                };
 
-                assert_eq!(response.status(), Status::InternalServerError);
+                // Held deliveries are still signed with their tenant's secret
+                assert_eq!(hold("global"), Status::Accepted);
+                assert_eq!(sign_with("global", "Global"), Status::Unauthorized);
+                assert_eq!(hold("acme"), Status::Accepted);
+                assert_eq!(sign_with("acme", "AcmeSecret"), Status::Ok);
             },
         );
+
+        let tenant = temp_dir.path().join("tenant.temp");
+        assert!(wait_for_file(&tenant));
+        assert_eq!(std::fs::read_to_string(tenant).unwrap(), "acme");
     }
 
     #[test]
-    fn no_script() {
+    fn release_assets() {
+        let (temp_dir, script) =
+            write_script("cat \"$WEBHOOK_RELEASE_ASSETS/app.tar.gz\" > deployed.temp");
+        let assets_dir = temp_dir.path().join("assets");
+        let mut server = mockito::Server::new();
+        let asset = server
+            .mock("GET", "/repos/octo/app/releases/assets/1")
+            .match_header("Accept", "application/octet-stream")
+            .match_header("Authorization", "Bearer ghp_test")
+            .with_body("release contents")
+            .expect(1)
+            .create();
+        let payload = serde_json::json!({
+            "action": "published",
+            "release": {
+                "id": 7,
+                "assets": [{
+                    "name": "app.tar.gz",
+                    "url": format!("{}/repos/octo/app/releases/assets/1", server.url()),
+                }],
+            },
+        })
+        .to_string();
+
         temp_env::with_vars(
             [
-                (WEBHOOK_STRING, Some("Very Secure!")),
-                (SCRIPT_STRING, None),
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (
+                    release::ASSETS_DIR_STRING,
+                    Some(assets_dir.to_str().expect("Valid Path")),
+                ),
+                (release::ASSETS_TOKEN_STRING, Some("ghp_test")),
             ],
             || {
-                let client = Client::tracked(launch()).expect("valid rocket instance");
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
                 let response = client
                     .post(uri!(webhook_listen))
-                    .json(&"{}")
-                    .header(Header::new(HEADER, "Not_A_Match"))
+                    .header(ContentType::JSON)
+                    .header(Header::new(HEADER, sign("VerySecure", &payload)))
+                    .header(Header::new(EVENT_HEADER, "release"))
+                    .body(&payload)
                     .dispatch();
-
-                assert_eq!(response.status(), Status::InternalServerError);
+                assert_eq!(response.status(), Status::Ok);
             },
         );
+
+        asset.assert();
+        let deployed = temp_dir.path().join("deployed.temp");
+        assert!(wait_for_file(&deployed));
+        assert_eq!(
+            std::fs::read_to_string(deployed).unwrap(),
+            "release contents"
+        );
+        assert!(assets_dir.join("7").join("app.tar.gz").is_file());
     }
 
     #[test]
-    fn signature_generation() {
-        signature_matches(
-            "It's a Secret to Everybody",
-            "Hello, World!",
-            XHubSignature {
-                signature:
-                    "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17",
-            },
+    fn parallel_scripts() {
+        // Each only succeeds if the other starts while it's still running
+        let (temp_dir, notify) = write_script(
+            "cat > notify.temp; touch notify.started; \
+             for _ in $(seq 100); do [ -e deploy.started ] && exit 0; sleep 0.02; done; exit 1",
+        );
+        let deploy = temp_dir.path().join("deploy.sh");
+        std::fs::write(
+            &deploy,
+            format!(
+                "cd {}; touch deploy.started; \
+                 for _ in $(seq 100); do [ -e notify.started ] && exit 0; sleep 0.02; done; exit 1",
+                temp_dir.path().to_str().expect("Valid Path")
+            ),
         )
-        .unwrap();
+        .expect("Able to write test script");
+        let failing = temp_dir.path().join("failing.sh");
+        std::fs::write(&failing, "exit 3").expect("Able to write test script");
+
+        let (notify, deploy, failing) = (
+            notify.to_str().expect("Valid Path"),
+            deploy.to_str().expect("Valid Path"),
+            failing.to_str().expect("Valid Path"),
+        );
+        let mapping = format!("push={notify},push={deploy},release={notify},release={failing}");
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (PARALLEL_SCRIPTS_STRING, Some(mapping.as_str())),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |event: &str| {
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", "{\"n\":1}")))
+                        .header(Header::new(EVENT_HEADER, event.to_owned()))
+                        .body("{\"n\":1}")
+                        .dispatch()
+                        .status()
+                };
+
+                assert_eq!(deliver("push"), Status::Ok);
+                let sent = std::fs::read_to_string(temp_dir.path().join("notify.temp")).unwrap();
+                assert_eq!(sent, "{\"n\":1}");
+
+                // Every script has to succeed
+                assert_eq!(deliver("release"), Status::InternalServerError);
+            },
+        );
     }
 
     #[test]
-    fn invalid_signature() {
+    fn sequential_scripts() {
+        // Each step records when it finished, in milliseconds
+        let (temp_dir, first) = write_script("cat > first.temp; date +%s%3N > first.done");
+        let second = temp_dir.path().join("second.sh");
+        std::fs::write(
+            &second,
+            format!(
+                "cd {}; [ -e first.done ] || exit 1; date +%s%3N > second.done",
+                temp_dir.path().to_str().expect("Valid Path")
+            ),
+        )
+        .expect("Able to write test script");
+        let failing = temp_dir.path().join("failing.sh");
+        std::fs::write(&failing, "exit 3").expect("Able to write test script");
+
+        let (first, second, failing) = (
+            first.to_str().expect("Valid Path"),
+            second.to_str().expect("Valid Path"),
+            failing.to_str().expect("Valid Path"),
+        );
+        let mapping = format!("push={first},push={second},release={failing},release={first}");
+
         temp_env::with_vars(
             [
-                (WEBHOOK_STRING, Some("Very Secure!")),
-                (SCRIPT_STRING, Some("script.sh")),
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SEQUENTIAL_SCRIPTS_STRING, Some(mapping.as_str())),
+                (SCRIPT_STEP_DELAY_STRING, Some("0.3")),
             ],
             || {
-                let client = Client::tracked(launch()).expect("valid rocket instance");
-                let response = client
-                    .post(uri!(webhook_listen))
-                    .json(&"{}")
-                    .header(Header::new(HEADER, "sha256=0123acd"))
-                    .dispatch();
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |event: &str| {
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", "{\"n\":1}")))
+                        .header(Header::new(EVENT_HEADER, event.to_owned()))
+                        .body("{\"n\":1}")
+                        .dispatch()
+                        .status()
+                };
+                let timestamp = |name: &str| -> u64 {
+                    std::fs::read_to_string(temp_dir.path().join(name))
+                        .unwrap()
+                        .trim()
+                        .parse()
+                        .unwrap()
+                };
 
-                assert_eq!(response.status(), Status::Unauthorized);
+                assert_eq!(deliver("push"), Status::Ok);
+                let sent = std::fs::read_to_string(temp_dir.path().join("first.temp")).unwrap();
+                assert_eq!(sent, "{\"n\":1}");
+                assert!(timestamp("second.done") - timestamp("first.done") >= 300);
+
+                // The steps after a failing one aren't run
+                std::fs::remove_file(temp_dir.path().join("first.done")).unwrap();
+                assert_eq!(deliver("release"), Status::InternalServerError);
+                assert!(!temp_dir.path().join("first.done").exists());
             },
         );
     }
 
     #[test]
-    fn valid_request() {
-        // Temp dir for bash script
-        let temp_dir =
-            tempdir::TempDir::new("webhook_handler-temp").expect("Able to create temp dir");
-
-        // Write script
-        let mut path = temp_dir.path().to_path_buf();
-        path.push("test.sh");
+    fn multiscript_continue() {
+        let (temp_dir, failing) = write_script("exit 3");
+        let second = temp_dir.path().join("second.sh");
         std::fs::write(
-            &path,
+            &second,
             format!(
-                // Switch to correct dir
-                "cd {}; echo 'hi' > file.temp",
+                "cd {}; touch second.temp",
                 temp_dir.path().to_str().expect("Valid Path")
             ),
         )
         .expect("Able to write test script");
 
-        // Valid signature with valid bash script
+        let (failing, second) = (
+            failing.to_str().expect("Valid Path"),
+            second.to_str().expect("Valid Path"),
+        );
+        let mapping = format!("push={failing},push={second}");
+
         temp_env::with_vars(
             [
                 (WEBHOOK_STRING, Some("VerySecure")),
-                (SCRIPT_STRING, Some(path.to_str().expect("Valid Path"))),
+                (SEQUENTIAL_SCRIPTS_STRING, Some(mapping.as_str())),
+                (MULTISCRIPT_MODE_STRING, Some("continue")),
             ],
             || {
-                let client = Client::tracked(launch()).expect("valid rocket instance");
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
                 let response = client
                     .post(uri!(webhook_listen))
-                    .json(&"{\"test\": 1}")
-                    .header(Header::new(
-                        HEADER,
-                        "sha256=f5cf34a2c036452fd80ced7508e5c231b1afa5c05713eaf87610499ee23f471a",
-                    ))
+                    .header(ContentType::JSON)
+                    .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                    .header(Header::new(EVENT_HEADER, "push"))
+                    .body("{}")
                     .dispatch();
 
-                assert_eq!(response.status(), Status::Ok);
+                assert_eq!(response.status(), Status::MultiStatus);
+                assert!(temp_dir.path().join("second.temp").exists());
+                let summary: serde_json::Value = response.into_json().unwrap();
+                assert_eq!(
+                    summary,
+                    serde_json::json!({ "scripts": [
+                        { "script": failing, "exit_code": 3 },
+                        { "script": second, "exit_code": 0 },
+                    ]})
+                );
             },
         );
+    }
 
-        let mut path = temp_dir.path().to_path_buf();
-        path.push("file.temp");
-        // Check file exists
-        assert!(std::fs::exists(&path).expect("Exists"));
+    #[test]
+    fn json_limits() {
+        let (_temp_dir, script) = write_script("exit 0");
 
-        // Check correct content
-        let content = std::fs::read_to_string(path).expect("Valid file");
-        assert_eq!(content, "hi\n");
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+                (json_limits::MAX_DEPTH_STRING, Some("32")),
+                (json_limits::MAX_ELEMENTS_STRING, Some("1000")),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let deliver = |payload: &str| {
+                    client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", payload)))
+                        .body(payload)
+                        .dispatch()
+                        .status()
+                };
+
+                let nested = format!("{}{}", "[".repeat(10_000), "]".repeat(10_000));
+                assert_eq!(deliver(&nested), Status::UnprocessableEntity);
+                let wide = format!("[{}0]", "0,".repeat(5_000));
+                assert_eq!(deliver(&wide), Status::PayloadTooLarge);
+                assert_eq!(deliver(r#"{"commits": [{"id": "abc"}]}"#), Status::Ok);
+            },
+        );
+    }
+
+    #[test]
+    fn min_free_space() {
+        let temp_dir = tempdir::TempDir::new("webhook_handler-temp").unwrap();
+        let ready_with = |path: &str, minimum: &str| {
+            temp_env::with_vars(
+                [
+                    (MIN_FREE_MB_STRING, Some(minimum)),
+                    (FREE_SPACE_PATH_STRING, Some(path)),
+                ],
+                || {
+                    let client = Client::tracked(rocket()).expect("valid rocket instance");
+                    let status = client.get(uri!(ready)).dispatch().status();
+                    status
+                },
+            )
+        };
+
+        let path = temp_dir.path().to_str().expect("Valid Path");
+        assert_eq!(ready_with(path, "0"), Status::Ok);
+        // No disk has this much free
+        let unreachable = u64::MAX.to_string();
+        assert_eq!(ready_with(path, &unreachable), Status::ServiceUnavailable);
+        assert_eq!(
+            ready_with("/does/not/exist", "0"),
+            Status::ServiceUnavailable
+        );
+    }
+
+    #[test]
+    fn secret_command() {
+        let (temp_dir, script) = write_script("exit 0");
+        let runs = temp_dir.path().join("runs.temp");
+        let command = format!(
+            "echo run >> {}; echo CommandSecret",
+            runs.to_str().expect("Valid Path")
+        );
+        let count = || std::fs::read_to_string(&runs).map_or(0, |runs| runs.lines().count());
+        let deliver = |client: &Client, secret: &str| {
+            client
+                .post(uri!(webhook_listen))
+                .header(ContentType::JSON)
+                .header(Header::new(HEADER, sign(secret, "{}")))
+                .body("{}")
+                .dispatch()
+                .status()
+        };
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("FromEnvironment")),
+                (auth::SECRET_COMMAND_STRING, Some(command.as_str())),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                assert_eq!(deliver(&client, "CommandSecret"), Status::Ok);
+                assert_eq!(deliver(&client, "CommandSecret"), Status::Ok);
+                assert_eq!(deliver(&client, "FromEnvironment"), Status::Unauthorized);
+                // The secret is reused until it expires
+                assert_eq!(count(), 1);
+            },
+        );
+
+        temp_env::with_vars(
+            [
+                (auth::SECRET_COMMAND_STRING, Some(command.as_str())),
+                (auth::SECRET_COMMAND_TTL_STRING, Some("0")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let before = count();
+                assert_eq!(deliver(&client, "CommandSecret"), Status::Ok);
+                assert_eq!(deliver(&client, "CommandSecret"), Status::Ok);
+                assert_eq!(count(), before + 2);
+            },
+        );
+
+        temp_env::with_vars(
+            [
+                (auth::SECRET_COMMAND_STRING, Some("exit 1")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                assert_eq!(
+                    deliver(&client, "CommandSecret"),
+                    Status::InternalServerError
+                );
+            },
+        );
+
+        // Commands that hang are killed rather than holding up the delivery
+        temp_env::with_vars(
+            [
+                (
+                    auth::SECRET_COMMAND_STRING,
+                    Some("sleep 10; echo 'CommandSecret'"),
+                ),
+                (auth::SECRET_COMMAND_TIMEOUT_STRING, Some("0.2")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                let started = Instant::now();
+                assert_eq!(
+                    deliver(&client, "CommandSecret"),
+                    Status::InternalServerError
+                );
+                assert!(started.elapsed() < Duration::from_secs(5));
+            },
+        );
+    }
+
+    #[rocket::async_test]
+    async fn script_is_directory() {
+        let temp_dir = tempdir::TempDir::new("webhook_handler-temp").unwrap();
+        let dir = temp_dir.path().to_str().expect("Valid Path").to_owned();
+
+        temp_env::async_with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(dir.as_str())),
+                (STRICT_STARTUP_STRING, None),
+            ],
+            async {
+                let client = rocket::local::asynchronous::Client::tracked(rocket())
+                    .await
+                    .expect("valid rocket instance");
+                let response = client
+                    .post(uri!(webhook_listen))
+                    .header(ContentType::JSON)
+                    .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                    .body("{}")
+                    .dispatch()
+                    .await;
+                assert_eq!(response.status(), Status::InternalServerError);
+                let body: serde_json::Value =
+                    serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+                assert_eq!(body["error"], SCRIPT_IS_DIRECTORY);
+            },
+        )
+        .await;
+
+        temp_env::async_with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(dir.as_str())),
+                (STRICT_STARTUP_STRING, Some("1")),
+            ],
+            async {
+                assert!(missing_config().is_some_and(|err| err.contains("is a directory")));
+                // Rocket panics if its launch errors are dropped without being looked at
+                let ignited = rocket().ignite().await;
+                assert!(
+                    ignited.is_err_and(|err| matches!(err.kind(), ErrorKind::FailedFairings(_)))
+                );
+            },
+        )
+        .await;
+    }
+
+    #[rocket::async_test]
+    async fn unpoolable_config() {
+        for key in [pty::USE_PTY_STRING, PARSE_STDERR_STRING] {
+            temp_env::async_with_vars(
+                [
+                    (WEBHOOK_STRING, Some("VerySecure")),
+                    (shell_pool::POOL_SIZE_STRING, Some("2")),
+                    (pty::USE_PTY_STRING, None),
+                    (PARSE_STDERR_STRING, None),
+                    (key, Some("1")),
+                ],
+                async {
+                    assert_eq!(super::unpoolable_config(), Some(key));
+                    // Rocket panics if its launch errors are dropped without being looked at
+                    let ignited = rocket().ignite().await;
+                    assert!(ignited
+                        .is_err_and(|err| matches!(err.kind(), ErrorKind::FailedFairings(_))));
+                },
+            )
+            .await;
+        }
+
+        temp_env::async_with_vars(
+            [
+                (shell_pool::POOL_SIZE_STRING, None),
+                (pty::USE_PTY_STRING, Some("1")),
+                (PARSE_STDERR_STRING, Some("1")),
+            ],
+            async {
+                assert_eq!(super::unpoolable_config(), None);
+            },
+        )
+        .await;
+    }
+
+    #[test]
+    fn script_is_directory_logged() {
+        let temp_dir = tempdir::TempDir::new("webhook_handler-temp").unwrap();
+        let captured = logging::tests::Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, temp_dir.path().to_str()),
+                (STRICT_STARTUP_STRING, None),
+            ],
+            || {
+                tracing::subscriber::with_default(subscriber, || {
+                    Client::tracked(rocket()).expect("valid rocket instance");
+                })
+            },
+        );
+
+        let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(logs
+            .lines()
+            .any(|line| line.contains("ERROR") && line.contains("is a directory, not a script")));
+    }
+
+    #[test]
+    fn running_scripts() {
+        let (temp_dir, script) = write_script("sleep 0.5; echo done >> done.temp");
+        let done = temp_dir.path().join("done.temp");
+        let running = |client: &Client| -> u64 {
+            let metrics = client.get(uri!(metrics::metrics)).dispatch();
+            let metrics = metrics.into_string().unwrap();
+            metrics
+                .lines()
+                .find_map(|line| line.strip_prefix("webhook_scripts_running "))
+                .expect("Gauge is exposed")
+                .parse()
+                .unwrap()
+        };
+
+        temp_env::with_vars(
+            [
+                (WEBHOOK_STRING, Some("VerySecure")),
+                (SCRIPT_STRING, Some(script.to_str().expect("Valid Path"))),
+            ],
+            || {
+                let client = Client::tracked(rocket()).expect("valid rocket instance");
+                for _ in 0..2 {
+                    let response = client
+                        .post(uri!(webhook_listen))
+                        .header(ContentType::JSON)
+                        .header(Header::new(HEADER, sign("VerySecure", "{}")))
+                        .body("{}")
+                        .dispatch();
+                    assert_eq!(response.status(), Status::Ok);
+                }
+
+                // Other tests may be running scripts at the same time
+                let during = running(&client);
+                assert!(during >= 2);
+
+                let mut attempts = 0;
+                while std::fs::read_to_string(&done).map_or(0, |done| done.lines().count()) < 2
+                    || running(&client) > during - 2
+                {
+                    attempts += 1;
+                    assert!(attempts < 250, "Scripts were never counted as finished");
+                    std::thread::sleep(Duration::from_millis(20));
+                }
+            },
+        );
     }
 }
@@ -0,0 +1,64 @@
+//! Bounding the memory used to buffer the bodies of all in-flight requests.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// The string for the environment variable containing the most KiB that may be buffered across
+/// all in-flight request bodies.
+pub const MAX_TOTAL_BODY_STRING: &str = "WEBHOOK_MAX_TOTAL_BODY_MEM_KIB";
+
+/// The bytes currently set aside for buffering request bodies.
+#[derive(Default)]
+pub struct BodyMemory {
+    in_use: Arc<AtomicU64>,
+}
+
+impl BodyMemory {
+    /// Sets aside the bytes for a body, unless doing so would take the total over the maximum.
+    pub fn reserve(&self, bytes: u64, max: u64) -> Option<Reservation> {
+        self.in_use
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |in_use| {
+                in_use.checked_add(bytes).filter(|total| *total <= max)
+            })
+            .ok()?;
+
+        Some(Reservation {
+            in_use: self.in_use.clone(),
+            bytes,
+        })
+    }
+}
+
+/// Bytes set aside for a body, given back once dropped.
+pub struct Reservation {
+    in_use: Arc<AtomicU64>,
+    bytes: u64,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.in_use.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservations() {
+        let memory = BodyMemory::default();
+
+        let first = memory.reserve(600, 1024).expect("Within the maximum");
+        assert!(memory.reserve(600, 1024).is_none());
+
+        let second = memory.reserve(424, 1024).expect("Exactly at the maximum");
+        assert!(memory.reserve(1, 1024).is_none());
+
+        drop(first);
+        drop(second);
+        assert!(memory.reserve(1024, 1024).is_some());
+    }
+}
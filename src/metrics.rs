@@ -0,0 +1,332 @@
+//! Keeping count of what the handler has done, exposed for Prometheus to scrape.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Write,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rocket::{get, Shutdown};
+
+/// The string for the environment variable containing the path to the file the counters are
+/// saved to, so that they carry on from where they were after a restart.
+pub const STATE_FILE_STRING: &str = "WEBHOOK_METRICS_STATE_FILE";
+
+/// How often the counters are saved to the state file while running.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The resources used by the scripts that have finished, across every kind of script.
+pub static SCRIPT_USAGE: ScriptUsage = ScriptUsage::new();
+/// The deliveries received, whatever came of them.
+pub static DELIVERIES: AtomicU64 = AtomicU64::new(0);
+/// The unix time of the last heartbeat, or 0 if there hasn't been one.
+pub static LAST_HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+/// The scripts running right now, across every kind of script.
+pub static RUNNING_SCRIPTS: AtomicU64 = AtomicU64::new(0);
+/// The deliveries received for each tenant, by the tenant's name.
+static TENANT_DELIVERIES: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+/// The deliveries that looked like abuse of the handler, by the kind of abuse.
+static ABUSE: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+/// Records a delivery received for the tenant.
+pub fn tenant_delivery(tenant: &str) {
+    *TENANT_DELIVERIES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .entry(tenant.to_owned())
+        .or_default() += 1;
+}
+
+/// Records a delivery that looked like abuse of the handler, of the given kind.
+pub fn abuse(kind: &str) {
+    *ABUSE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .entry(kind.to_owned())
+        .or_default() += 1;
+}
+
+/// Records that the handler is still alive.
+pub fn heartbeat() {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    LAST_HEARTBEAT.store(now, Ordering::Relaxed);
+}
+
+/// Counts a script as running for as long as it's held, however the script ends.
+pub struct RunningScript(());
+
+impl RunningScript {
+    pub fn start() -> Self {
+        RUNNING_SCRIPTS.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+}
+
+impl Drop for RunningScript {
+    fn drop(&mut self) {
+        RUNNING_SCRIPTS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counters, by the name they are saved to the state file under.
+pub type Counters<'a> = [(&'static str, &'a AtomicU64)];
+
+/// The counters that carry on across restarts.
+pub fn counters() -> [(&'static str, &'static AtomicU64); 3] {
+    [
+        ("deliveries", &DELIVERIES),
+        ("scripts_finished", &SCRIPT_USAGE.finished),
+        ("script_cpu_micros", &SCRIPT_USAGE.cpu_micros),
+    ]
+}
+
+/// Saves the counters to the state file, one `name value` pair per line.
+///
+/// The file is replaced all at once, so it is never left half written.
+pub fn save(path: &Path, counters: &Counters<'_>) -> io::Result<()> {
+    let mut contents = String::new();
+    for (name, counter) in counters {
+        // Writing to a string never fails
+        let _ = writeln!(contents, "{name} {}", counter.load(Ordering::Relaxed));
+    }
+
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, contents)?;
+    fs::rename(temp, path)
+}
+
+/// Adds the counts saved in the state file to the counters, if it exists.
+///
+/// Unknown or malformed lines are ignored.
+pub fn restore(path: &Path, counters: &Counters<'_>) -> io::Result<()> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    for line in contents.lines() {
+        let Some((name, Ok(count))) = line
+            .split_once(' ')
+            .map(|(name, count)| (name, count.trim().parse::<u64>()))
+        else {
+            continue;
+        };
+        if let Some((_, counter)) = counters.iter().find(|(known, _)| *known == name) {
+            counter.fetch_add(count, Ordering::Relaxed);
+        }
+    }
+    Ok(())
+}
+
+/// Saves the counters every [`SAVE_INTERVAL`] until shut down.
+pub async fn save_periodically(path: PathBuf, shutdown: Shutdown) {
+    let mut saves = rocket::tokio::time::interval(SAVE_INTERVAL);
+    // The first tick completes immediately
+    saves.tick().await;
+
+    loop {
+        rocket::tokio::select! {
+            _ = saves.tick() => {}
+            _ = shutdown.clone() => return,
+        }
+
+        if let Err(err) = save(&path, &counters()) {
+            tracing::warn!("Could not save the metrics state: {err}");
+        }
+    }
+}
+
+/// The resources used by a single script, as reported by the OS once it exited.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ResourceUsage {
+    pub user_cpu: Duration,
+    pub system_cpu: Duration,
+    pub max_rss_bytes: u64,
+}
+
+/// The totals of the resources used by scripts.
+pub struct ScriptUsage {
+    finished: AtomicU64,
+    cpu_micros: AtomicU64,
+    max_rss_bytes: AtomicU64,
+}
+
+impl ScriptUsage {
+    const fn new() -> Self {
+        Self {
+            finished: AtomicU64::new(0),
+            cpu_micros: AtomicU64::new(0),
+            max_rss_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Adds the usage of a script that has just finished.
+    pub fn record(&self, usage: ResourceUsage) {
+        let cpu = usage.user_cpu + usage.system_cpu;
+        self.finished.fetch_add(1, Ordering::Relaxed);
+        self.cpu_micros
+            .fetch_add(cpu.as_micros() as u64, Ordering::Relaxed);
+        self.max_rss_bytes
+            .fetch_max(usage.max_rss_bytes, Ordering::Relaxed);
+    }
+
+    /// The number of scripts that have finished.
+    pub fn finished(&self) -> u64 {
+        self.finished.load(Ordering::Relaxed)
+    }
+
+    /// The CPU time used by all of the finished scripts.
+    pub fn cpu(&self) -> Duration {
+        Duration::from_micros(self.cpu_micros.load(Ordering::Relaxed))
+    }
+}
+
+/// Metrics in the Prometheus text format.
+#[get("/metrics")]
+pub fn metrics() -> String {
+    // Lets the metrics of each environment be told apart once scraped together
+    let environment = env::var(crate::ENVIRONMENT_STRING)
+        .map(|environment| format!("environment=\"{}\"", escape_label(&environment)));
+    let labels = match &environment {
+        Ok(environment) => format!("{{{environment}}}"),
+        Err(_) => String::new(),
+    };
+
+    let mut metrics = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: &dyn std::fmt::Display| {
+        // Writing to a string never fails
+        let _ = write!(
+            metrics,
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name}{labels} {value}\n"
+        );
+    };
+
+    metric(
+        "webhook_deliveries_total",
+        "counter",
+        "Deliveries received, whatever came of them.",
+        &DELIVERIES.load(Ordering::Relaxed),
+    );
+    metric(
+        "webhook_last_heartbeat_timestamp_seconds",
+        "gauge",
+        "The unix time of the last heartbeat, or 0 if heartbeats are disabled.",
+        &LAST_HEARTBEAT.load(Ordering::Relaxed),
+    );
+    metric(
+        "webhook_scripts_running",
+        "gauge",
+        "Scripts running right now.",
+        &RUNNING_SCRIPTS.load(Ordering::Relaxed),
+    );
+    metric(
+        "webhook_scripts_finished_total",
+        "counter",
+        "Scripts that finished with their resource usage recorded.",
+        &SCRIPT_USAGE.finished(),
+    );
+    metric(
+        "webhook_script_cpu_seconds_total",
+        "counter",
+        "CPU time used by finished scripts.",
+        &SCRIPT_USAGE.cpu().as_secs_f64(),
+    );
+    metric(
+        "webhook_script_max_rss_bytes",
+        "gauge",
+        "The largest resident set size reached by any finished script.",
+        &SCRIPT_USAGE.max_rss_bytes.load(Ordering::Relaxed),
+    );
+
+    let mut labelled =
+        |name: &str, help: &str, label: &str, counts: &Mutex<BTreeMap<String, u64>>| {
+            let counts = counts.lock().unwrap_or_else(PoisonError::into_inner);
+            if counts.is_empty() {
+                return;
+            }
+            let _ = write!(metrics, "# HELP {name} {help}\n# TYPE {name} counter\n");
+            for (value, count) in counts.iter() {
+                let _ = write!(metrics, "{name}{{{label}=\"{}\"", escape_label(value));
+                if let Ok(environment) = &environment {
+                    let _ = write!(metrics, ",{environment}");
+                }
+                let _ = writeln!(metrics, "}} {count}");
+            }
+        };
+    labelled(
+        "webhook_tenant_deliveries_total",
+        "Deliveries received for each tenant.",
+        "tenant",
+        &TENANT_DELIVERIES,
+    );
+    labelled(
+        "webhook_abuse_total",
+        "Deliveries that looked like abuse, by the kind of abuse.",
+        "kind",
+        &ABUSE,
+    );
+
+    metrics
+}
+
+/// Escapes the value of a label in the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_persisted() {
+        let temp_dir = tempdir::TempDir::new("webhook_handler-temp").unwrap();
+        let path = temp_dir.path().join("metrics.state");
+
+        // Nothing has been saved before the first run
+        let deliveries = AtomicU64::new(0);
+        let finished = AtomicU64::new(0);
+        let counters = [("deliveries", &deliveries), ("scripts_finished", &finished)];
+        restore(&path, &counters).unwrap();
+        assert_eq!(deliveries.load(Ordering::Relaxed), 0);
+
+        deliveries.fetch_add(5, Ordering::Relaxed);
+        finished.fetch_add(2, Ordering::Relaxed);
+        save(&path, &counters).unwrap();
+
+        // Restarting carries on from the saved totals
+        let deliveries = AtomicU64::new(0);
+        let finished = AtomicU64::new(0);
+        let counters = [("deliveries", &deliveries), ("scripts_finished", &finished)];
+        restore(&path, &counters).unwrap();
+        deliveries.fetch_add(1, Ordering::Relaxed);
+        save(&path, &counters).unwrap();
+
+        let deliveries = AtomicU64::new(0);
+        let finished = AtomicU64::new(0);
+        let counters = [("deliveries", &deliveries), ("scripts_finished", &finished)];
+        restore(&path, &counters).unwrap();
+        assert_eq!(deliveries.load(Ordering::Relaxed), 6);
+        assert_eq!(finished.load(Ordering::Relaxed), 2);
+
+        // Counters that weren't saved start from nothing
+        let unknown = AtomicU64::new(0);
+        restore(&path, &[("unknown", &unknown)]).unwrap();
+        assert_eq!(unknown.load(Ordering::Relaxed), 0);
+    }
+}
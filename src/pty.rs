@@ -0,0 +1,89 @@
+//! Running scripts attached to a pseudo-terminal, for tools that behave differently without one.
+
+use std::{io, process::Command};
+
+/// The string for the environment variable enabling running scripts with their output written to
+/// a pseudo-terminal.
+pub const USE_PTY_STRING: &str = "WEBHOOK_USE_PTY";
+
+/// Writes the command's stdout & stderr to a new pseudo-terminal, whose combined output is passed
+/// on to the handler's stdout.
+///
+/// The terminal is closed once the command is dropped & every process it started has exited.
+#[cfg(unix)]
+pub fn attach(command: &mut Command) -> io::Result<()> {
+    use std::{
+        fs::File,
+        io::{Read, Write},
+        os::fd::{FromRawFd, OwnedFd},
+        thread,
+    };
+
+    let (mut controller, terminal) = {
+        let (mut controller, mut terminal) = (-1, -1);
+        // Safety: both fds are written before returning, and the other arguments may be null
+        let opened = unsafe {
+            libc::openpty(
+                &mut controller,
+                &mut terminal,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        };
+        if opened != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Safety: openpty succeeded, so both are newly opened fds owned by nothing else
+        unsafe {
+            (
+                File::from(OwnedFd::from_raw_fd(controller)),
+                File::from(OwnedFd::from_raw_fd(terminal)),
+            )
+        }
+    };
+
+    command.stdout(terminal.try_clone()?).stderr(terminal);
+
+    thread::spawn(move || {
+        let mut stdout = io::stdout();
+        let mut buffer = [0; 4096];
+        // Reading fails once nothing has the terminal open anymore
+        while let Ok(read @ 1..) = controller.read(&mut buffer) {
+            let _ = stdout.write_all(&buffer[..read]);
+        }
+    });
+
+    Ok(())
+}
+
+/// Pseudo-terminals are only supported on unix.
+#[cfg(not(unix))]
+pub fn attach(_command: &mut Command) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Pseudo-terminals are only supported on unix",
+    ))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sees_terminal() {
+        let temp_dir = tempdir::TempDir::new("webhook_handler-temp").unwrap();
+        let dir = temp_dir.path();
+        let script = "for fd in 1 2; do [ -t $fd ] && seen+=tty || seen+=none; done; \
+                      echo \"$seen\" > tty.temp";
+
+        let mut command = Command::new("bash");
+        command.args(["-c", script]).current_dir(dir);
+        attach(&mut command).expect("Able to open a pseudo-terminal");
+        assert!(command.status().unwrap().success());
+
+        let seen = std::fs::read_to_string(dir.join("tty.temp")).unwrap();
+        assert_eq!(seen.trim(), "ttytty");
+    }
+}
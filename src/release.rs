@@ -0,0 +1,87 @@
+//! Downloading the assets of a published release, ready for the script to deploy.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The string for the environment variable containing the directory the assets of releases are
+/// downloaded to before their script is run.
+pub const ASSETS_DIR_STRING: &str = "WEBHOOK_RELEASE_ASSETS_DIR";
+/// The string for the environment variable containing the API token assets are downloaded with.
+pub const ASSETS_TOKEN_STRING: &str = "WEBHOOK_RELEASE_ASSETS_TOKEN";
+/// The environment variable the script is passed the directory of the downloaded assets in.
+pub const ASSETS_ENV: &str = "WEBHOOK_RELEASE_ASSETS";
+
+/// The possible errors when downloading the assets of a release.
+#[derive(thiserror::Error, Debug)]
+pub enum ReleaseError {
+    #[error("The release has no id.")]
+    MissingId,
+    #[error("Release asset {0} has no name or url.")]
+    MalformedAsset(usize),
+    #[error("Release asset name \"{0}\" is not a plain file name.")]
+    UnsafeName(String),
+    #[error("Could not download release asset: {0}")]
+    Request(#[from] Box<ureq::Error>),
+    #[error("Could not save release asset: {0}")]
+    Save(#[from] io::Error),
+}
+
+/// Downloads every asset of the release in the payload to its own directory within `dir`,
+/// returning that directory.
+///
+/// Assets are fetched from their API url, so the token is only needed for private repositories.
+pub fn download_assets(
+    payload: &serde_json::Value,
+    dir: &Path,
+    token: Option<&str>,
+) -> Result<PathBuf, ReleaseError> {
+    let release = &payload["release"];
+    let id = release["id"].as_u64().ok_or(ReleaseError::MissingId)?;
+    let dir = dir.join(id.to_string());
+    fs::create_dir_all(&dir)?;
+
+    let assets = release["assets"].as_array().map_or(&[][..], Vec::as_slice);
+    for (index, asset) in assets.iter().enumerate() {
+        let (Some(name), Some(url)) = (asset["name"].as_str(), asset["url"].as_str()) else {
+            return Err(ReleaseError::MalformedAsset(index));
+        };
+        // Names come from the payload, so mustn't be able to escape the directory
+        if Path::new(name).file_name().and_then(|file| file.to_str()) != Some(name) {
+            return Err(ReleaseError::UnsafeName(name.to_owned()));
+        }
+
+        let mut request = ureq::get(url).set("Accept", "application/octet-stream");
+        if let Some(token) = token {
+            request = request.set("Authorization", &format!("Bearer {token}"));
+        }
+        let response = request.call().map_err(Box::new)?;
+
+        tracing::info!("Downloading release asset \"{name}\"");
+        let mut file = fs::File::create(dir.join(name))?;
+        io::copy(&mut response.into_reader(), &mut file)?;
+    }
+
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsafe_names() {
+        let temp_dir = tempdir::TempDir::new("webhook_handler-temp").unwrap();
+        for name in ["../escaped", "/etc/passwd", "nested/asset", ".."] {
+            let payload = serde_json::json!({
+                "release": { "id": 1, "assets": [{ "name": name, "url": "http://unused" }] },
+            });
+            let result = download_assets(&payload, temp_dir.path(), None);
+            assert!(
+                matches!(result, Err(ReleaseError::UnsafeName(_))),
+                "{name} was accepted"
+            );
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Reusing the result of a delivery for identical repeats of it.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use hmac::digest::Digest;
+
+/// The string for the environment variable containing the seconds the result of a delivery is
+/// reused for identical repeats of it.
+pub const RESULT_CACHE_STRING: &str = "WEBHOOK_RESULT_CACHE_SECS";
+
+/// The results of recent deliveries, keyed by the SHA-256 digest of the tenant & event they were
+/// for along with their payloads.
+pub struct ResultCache<T> {
+    results: Mutex<HashMap<[u8; 32], (T, Instant)>>,
+}
+
+impl<T> Default for ResultCache<T> {
+    fn default() -> Self {
+        Self {
+            results: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Clone> ResultCache<T> {
+    /// The result of an earlier delivery of the payload for the same tenant & event, if it hasn't
+    /// expired.
+    pub fn get(&self, tenant: Option<&str>, event: Option<&str>, payload: &[u8]) -> Option<T> {
+        let results = self.results.lock().unwrap_or_else(PoisonError::into_inner);
+        results
+            .get(&digest(tenant, event, payload))
+            .filter(|(_, expires)| Instant::now() <= *expires)
+            .map(|(result, _)| result.clone())
+    }
+
+    /// Reuses the result for identical deliveries of the payload for the same tenant & event until
+    /// the ttl passes.
+    pub fn insert(
+        &self,
+        tenant: Option<&str>,
+        event: Option<&str>,
+        payload: &[u8],
+        result: T,
+        ttl: Duration,
+    ) {
+        let mut results = self.results.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // Results that will never be reused would otherwise pile up
+        let now = Instant::now();
+        results.retain(|_, (_, expires)| now <= *expires);
+        results.insert(digest(tenant, event, payload), (result, now + ttl));
+    }
+}
+
+fn digest(tenant: Option<&str>, event: Option<&str>, payload: &[u8]) -> [u8; 32] {
+    let mut hasher = sha2::Sha256::new();
+    // Lengths are included so the parts can't run into each other
+    for part in [tenant, event] {
+        match part {
+            Some(part) => {
+                hasher.update([1]);
+                hasher.update((part.len() as u64).to_be_bytes());
+                hasher.update(part);
+            }
+            None => hasher.update([0]),
+        }
+    }
+    hasher.update(payload);
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyed_by_tenant_and_event() {
+        let cache = ResultCache::default();
+        let ttl = Duration::from_secs(60);
+        cache.insert(Some("acme"), Some("push"), b"{}", 1, ttl);
+        cache.insert(None, Some("push"), b"{}", 2, ttl);
+
+        assert_eq!(cache.get(Some("acme"), Some("push"), b"{}"), Some(1));
+        assert_eq!(cache.get(None, Some("push"), b"{}"), Some(2));
+        assert_eq!(cache.get(Some("globex"), Some("push"), b"{}"), None);
+        assert_eq!(cache.get(Some("acme"), Some("release"), b"{}"), None);
+        assert_eq!(cache.get(Some("acme"), None, b"{}"), None);
+        assert_eq!(cache.get(Some("acmepush"), None, b"{}"), None);
+    }
+}
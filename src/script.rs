@@ -0,0 +1,641 @@
+//! Running the webhook script & keeping track of its progress.
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Write},
+    process::{Child, ChildStderr, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rocket::tokio::sync::oneshot;
+use serde::Serialize;
+use tracing::{dispatcher, Dispatch, Span};
+
+use crate::metrics::{ResourceUsage, RunningScript, SCRIPT_USAGE};
+
+/// Receives the exit status of a spawned script once it finishes.
+pub type Finished = oneshot::Receiver<io::Result<ExitStatus>>;
+
+/// When a failed script should be run again.
+#[derive(Clone, Debug, Default)]
+pub struct RetryPolicy {
+    /// How many more times the script may be run after failing.
+    pub retries: u32,
+    /// The exit codes worth retrying, or every failure if unset.
+    pub exit_codes: Option<Vec<i32>>,
+}
+
+impl RetryPolicy {
+    /// Whether to retry after the given attempt exited with the status.
+    fn should_retry(&self, status: ExitStatus, attempt: u32) -> bool {
+        !status.success()
+            && attempt <= self.retries
+            && self
+                .exit_codes
+                .as_ref()
+                .is_none_or(|codes| status.code().is_some_and(|code| codes.contains(&code)))
+    }
+}
+
+/// Spawns the command, waiting for it to exit on a separate thread.
+pub fn spawn(command: Command) -> io::Result<Finished> {
+    spawn_retrying(command, RetryPolicy::default())
+}
+
+/// Spawns the command with the input written to its stdin, waiting for it to exit on a separate
+/// thread.
+pub fn spawn_with_input(mut command: Command, input: Vec<u8>) -> io::Result<Finished> {
+    let mut child = command.stdin(Stdio::piped()).spawn()?;
+    let running = RunningScript::start();
+    let stdin = child.stdin.take();
+    let (sender, receiver) = oneshot::channel();
+
+    thread::spawn(move || {
+        let _running = running;
+        // Scripts that exit without reading all of their input close the pipe early
+        if let Some(mut stdin) = stdin {
+            let _ = stdin.write_all(&input);
+        }
+        let _ = sender.send(wait_recorded(child));
+    });
+
+    Ok(receiver)
+}
+
+/// Spawns the command, waiting for it to exit on a separate thread & running it again for as long
+/// as the policy allows.
+///
+/// The resources used by each run are logged & recorded once it exits. If the command's stderr is
+/// piped, it is logged as part of the current span.
+pub fn spawn_retrying(mut command: Command, policy: RetryPolicy) -> io::Result<Finished> {
+    let span = Span::current();
+    let dispatch = dispatcher::get_default(Dispatch::clone);
+    let child = command.spawn()?;
+    // Held across retries, as the script is still being run
+    let running = RunningScript::start();
+    let (sender, receiver) = oneshot::channel();
+
+    thread::spawn(move || {
+        let _running = running;
+        let wait = |child| wait_logged(child, &span, &dispatch);
+        let result = retry(&policy, wait(child), || command.spawn().and_then(wait));
+
+        // The receiver is dropped if nobody is interested in the result
+        let _ = sender.send(result);
+    });
+
+    Ok(receiver)
+}
+
+/// Runs the command made from the script's result once it finishes, before passing the result on.
+///
+/// The command is run however the script finished, and failing to run it doesn't change the
+/// result.
+pub fn then_run<F>(finished: Finished, command: F) -> Finished
+where
+    F: FnOnce(&io::Result<ExitStatus>) -> Command + Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+
+    rocket::tokio::spawn(async move {
+        let result = finished
+            .await
+            .unwrap_or_else(|_| Err(io::Error::other("Script was never waited on")));
+
+        let cleanup = match spawn(command(&result)) {
+            Ok(cleanup) => cleanup
+                .await
+                .unwrap_or_else(|_| Err(io::Error::other("Script was never waited on"))),
+            Err(err) => Err(err),
+        };
+        match cleanup {
+            Ok(status) if status.success() => {}
+            Ok(status) => tracing::warn!("Cleanup script failed: {status}"),
+            Err(err) => tracing::warn!("Could not execute cleanup script: {err}"),
+        }
+
+        // The receiver is dropped if nobody is interested in the result
+        let _ = sender.send(result);
+    });
+
+    receiver
+}
+
+/// Runs the script again for as long as the policy allows, returning the result of the last run.
+pub fn retry(
+    policy: &RetryPolicy,
+    first: io::Result<ExitStatus>,
+    mut again: impl FnMut() -> io::Result<ExitStatus>,
+) -> io::Result<ExitStatus> {
+    let mut result = first;
+    let mut attempt = 1;
+    while let Ok(status) = result {
+        if !policy.should_retry(status, attempt) {
+            break;
+        }
+
+        attempt += 1;
+        tracing::warn!(
+            "Script failed with {status}, retrying (attempt {attempt}/{})",
+            policy.retries + 1
+        );
+        result = again();
+    }
+    result
+}
+
+/// Waits for the child to exit, logging its piped stderr as part of the span until it does.
+fn wait_logged(mut child: Child, span: &Span, dispatch: &Dispatch) -> io::Result<ExitStatus> {
+    let stderr = child
+        .stderr
+        .take()
+        .map(|stderr| log_stderr(stderr, span.clone(), dispatch.clone()));
+    let result = wait_recorded(child);
+
+    // Make sure everything the script wrote is logged before reporting it finished
+    if let Some(stderr) = stderr {
+        let _ = stderr.join();
+    }
+    result
+}
+
+/// Logs each line the script writes to stderr as part of the span, with `key=value` lines logged
+/// as the `key` & `value` fields.
+///
+/// Invalid UTF-8 is logged as replacement characters, rather than losing the rest of the output.
+fn log_stderr(stderr: ChildStderr, span: Span, dispatch: Dispatch) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        dispatcher::with_default(&dispatch, || {
+            for line in BufReader::new(stderr).split(b'\n').map_while(Result::ok) {
+                let line = String::from_utf8_lossy(&line);
+                let line = line.strip_suffix('\r').unwrap_or(&line);
+                match parse_field(line) {
+                    Some((key, value)) => {
+                        tracing::info!(parent: &span, key, value, "Script reported a field")
+                    }
+                    None => tracing::info!(parent: &span, "Script: {line}"),
+                }
+            }
+        })
+    })
+}
+
+/// Splits a `key=value` line, where the key is made up of alphanumerics, `_`, `-` or `.`.
+fn parse_field(line: &str) -> Option<(&str, &str)> {
+    let (key, value) = line.trim_end().split_once('=')?;
+    let valid_key = !key.is_empty()
+        && key
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || matches!(char, '_' | '-' | '.'));
+    valid_key.then_some((key, value))
+}
+
+/// Waits for the child to exit, logging & recording the resources it used.
+fn wait_recorded(child: Child) -> io::Result<ExitStatus> {
+    wait(child).map(|(status, usage)| {
+        if let Some(usage) = usage {
+            tracing::info!(
+                "Script used {:?} user & {:?} system CPU time, and at most {} bytes of memory",
+                usage.user_cpu,
+                usage.system_cpu,
+                usage.max_rss_bytes
+            );
+            SCRIPT_USAGE.record(usage);
+        }
+        status
+    })
+}
+
+/// Waits for the child to exit, along with the resources it used.
+#[cfg(unix)]
+fn wait(child: Child) -> io::Result<(ExitStatus, Option<ResourceUsage>)> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let pid = child.id() as libc::pid_t;
+    let mut status = 0;
+    // SAFETY: rusage is plain old data, for which all zeroes is valid
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+
+    // SAFETY: the child is only ever waited on here, so the pid still refers to it
+    while unsafe { libc::wait4(pid, &mut status, 0, &mut usage) } == -1 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+
+    let time = |time: libc::timeval| {
+        Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+    };
+    // Reported in bytes on macOS, but kilobytes elsewhere
+    let max_rss_bytes = match cfg!(target_os = "macos") {
+        true => usage.ru_maxrss as u64,
+        false => usage.ru_maxrss as u64 * 1024,
+    };
+
+    Ok((
+        ExitStatus::from_raw(status),
+        Some(ResourceUsage {
+            user_cpu: time(usage.ru_utime),
+            system_cpu: time(usage.ru_stime),
+            max_rss_bytes,
+        }),
+    ))
+}
+
+/// Waits for the child to exit, the resources it used are unknown off Unix.
+#[cfg(not(unix))]
+fn wait(mut child: Child) -> io::Result<(ExitStatus, Option<ResourceUsage>)> {
+    child.wait().map(|status| (status, None))
+}
+
+/// Limits the number of files the command may have open at once, by setting its `RLIMIT_NOFILE`.
+///
+/// Fails if the limit is above the handler's own hard limit, as it could only be raised with
+/// privileges scripts shouldn't be run with.
+#[cfg(unix)]
+pub fn limit_open_files(command: &mut Command, limit: u64) -> io::Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let mut current = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // Safety: the pointer is to a valid rlimit for the duration of the call
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut current) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if limit > current.rlim_max {
+        return Err(io::Error::other(format!(
+            "The open file limit of {limit} is above the hard limit of {}",
+            current.rlim_max
+        )));
+    }
+
+    let limit = libc::rlimit {
+        rlim_cur: limit,
+        rlim_max: limit,
+    };
+    // Safety: setrlimit is async-signal-safe, and nothing is allocated between fork & exec
+    unsafe {
+        command.pre_exec(move || match libc::setrlimit(libc::RLIMIT_NOFILE, &limit) {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        });
+    }
+    Ok(())
+}
+
+/// Open file limits are only supported on unix.
+#[cfg(not(unix))]
+pub fn limit_open_files(_command: &mut Command, _limit: u64) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Open file limits are only supported on unix",
+    ))
+}
+
+/// The progress of a script that was still running when its delivery was responded to.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    Finished {
+        success: bool,
+        exit_code: Option<i32>,
+    },
+    Failed,
+}
+
+/// How long the status of a finished script is kept for.
+const FINISHED_JOB_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The status of a job, along with when it finished if it has.
+type TrackedJob = (JobStatus, Option<Instant>);
+
+/// The scripts that outlived the response to their delivery.
+pub struct Jobs {
+    next_id: AtomicU64,
+    statuses: Arc<Mutex<HashMap<u64, TrackedJob>>>,
+    /// How long the statuses of finished scripts are kept for.
+    ttl: Duration,
+    /// The current time, which tests stand in for.
+    now: Arc<dyn Fn() -> Instant + Send + Sync>,
+}
+
+impl Default for Jobs {
+    fn default() -> Self {
+        Self {
+            next_id: AtomicU64::new(0),
+            statuses: Arc::default(),
+            ttl: FINISHED_JOB_TTL,
+            now: Arc::new(Instant::now),
+        }
+    }
+}
+
+impl Jobs {
+    /// Keeps track of the script until it finishes, returning the id to look it up by.
+    ///
+    /// The script's status is forgotten once it has been finished for longer than the ttl.
+    pub fn track(&self, finished: Finished) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (statuses, now) = (self.statuses.clone(), self.now.clone());
+        {
+            let mut statuses = statuses.lock().unwrap_or_else(PoisonError::into_inner);
+            // Statuses that nobody checked on would otherwise pile up
+            let now = now();
+            statuses.retain(|_, (_, finished)| {
+                !finished.is_some_and(|finished| now.duration_since(finished) > self.ttl)
+            });
+            statuses.insert(id, (JobStatus::Running, None));
+        }
+
+        rocket::tokio::spawn(async move {
+            let status = match finished.await {
+                Ok(Ok(status)) => JobStatus::Finished {
+                    success: status.success(),
+                    exit_code: status.code(),
+                },
+                Ok(Err(err)) => {
+                    tracing::error!("Could not wait for bash script: {err}");
+                    JobStatus::Failed
+                }
+                Err(_) => JobStatus::Failed,
+            };
+
+            statuses
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(id, (status, Some(now())));
+        });
+
+        id
+    }
+
+    /// The status of the job with the given id.
+    pub fn status(&self, id: u64) -> Option<JobStatus> {
+        self.statuses
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&id)
+            .map(|(status, _)| *status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The name of an event's parent span, along with the event's fields.
+    type CapturedEvent = (Option<String>, Vec<(String, String)>);
+
+    /// Captures every event.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<CapturedEvent>>>);
+
+    impl<S> tracing_subscriber::Layer<S> for Captured
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            struct Fields(Vec<(String, String)>);
+            impl tracing::field::Visit for Fields {
+                fn record_debug(
+                    &mut self,
+                    field: &tracing::field::Field,
+                    value: &dyn std::fmt::Debug,
+                ) {
+                    self.0.push((field.name().to_owned(), format!("{value:?}")));
+                }
+
+                fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+                    self.0.push((field.name().to_owned(), value.to_owned()));
+                }
+            }
+
+            let mut fields = Fields(Vec::new());
+            event.record(&mut fields);
+            let parent = ctx.event_span(event).map(|span| span.name().to_owned());
+            self.0.lock().unwrap().push((parent, fields.0));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn finished_jobs_evicted() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let clock = Arc::new(Mutex::new(Instant::now()));
+        let jobs = Jobs {
+            ttl: Duration::from_secs(60),
+            now: {
+                let clock = clock.clone();
+                Arc::new(move || *clock.lock().unwrap())
+            },
+            ..Jobs::default()
+        };
+        let runtime = rocket::tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let (sender, finished) = oneshot::channel();
+            let done = jobs.track(finished);
+            let (_running_sender, running) = oneshot::channel();
+            let running = jobs.track(running);
+
+            sender.send(Ok(ExitStatus::from_raw(0))).unwrap();
+            // Lets the job's task record that it finished
+            while jobs.status(done) == Some(JobStatus::Running) {
+                rocket::tokio::task::yield_now().await;
+            }
+            let finished = JobStatus::Finished {
+                success: true,
+                exit_code: Some(0),
+            };
+            assert_eq!(jobs.status(done), Some(finished));
+
+            // Finished jobs are kept until the ttl passes
+            *clock.lock().unwrap() += Duration::from_secs(60);
+            let (_sender, another) = oneshot::channel();
+            jobs.track(another);
+            assert_eq!(jobs.status(done), Some(finished));
+
+            // Tracking another job clears out those finished for longer than the ttl
+            *clock.lock().unwrap() += Duration::from_secs(1);
+            let (_sender, another) = oneshot::channel();
+            jobs.track(another);
+            assert_eq!(jobs.status(done), None);
+            assert_eq!(jobs.status(running), Some(JobStatus::Running));
+        });
+    }
+
+    #[test]
+    fn stderr_fields() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::registry().with(captured.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            let mut command = Command::new("bash");
+            command
+                .arg("-c")
+                .arg("echo 'commit=abc123' >&2; echo 'Deploying now' >&2")
+                .stderr(std::process::Stdio::piped());
+
+            let span = tracing::info_span!("delivery");
+            let finished = span.in_scope(|| spawn(command).unwrap());
+            rocket::tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap()
+                .block_on(finished)
+                .unwrap()
+                .unwrap();
+        });
+
+        let events = captured.0.lock().unwrap().clone();
+        let field = |name: &str, event: &CapturedEvent| {
+            event
+                .1
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone())
+        };
+
+        let reported = events
+            .iter()
+            .find(|event| field("key", event).is_some())
+            .expect("Field was logged");
+        assert_eq!(reported.0.as_deref(), Some("delivery"));
+        assert_eq!(field("key", reported).as_deref(), Some("commit"));
+        assert_eq!(field("value", reported).as_deref(), Some("abc123"));
+
+        assert!(events.iter().any(|event| {
+            event.0.as_deref() == Some("delivery")
+                && field("message", event).is_some_and(|message| message.contains("Deploying now"))
+        }));
+
+        assert_eq!(parse_field("commit=abc=123"), Some(("commit", "abc=123")));
+        assert_eq!(parse_field("not a field = really"), None);
+    }
+
+    #[test]
+    fn invalid_utf8_stderr() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::registry().with(captured.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            let mut command = Command::new("bash");
+            command
+                .arg("-c")
+                .arg("printf 'binary \\xff\\xfe\\n' >&2; echo 'still logged' >&2")
+                .stderr(std::process::Stdio::piped());
+
+            let finished = spawn(command).unwrap();
+            rocket::tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap()
+                .block_on(finished)
+                .unwrap()
+                .unwrap();
+        });
+
+        let messages: Vec<_> = captured
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|(_, fields)| fields.clone())
+            .filter(|(key, _)| key == "message")
+            .map(|(_, message)| message)
+            .collect();
+        assert!(messages
+            .iter()
+            .any(|message| message.contains("Script: binary \u{FFFD}\u{FFFD}")));
+        assert!(messages
+            .iter()
+            .any(|message| message.contains("Script: still logged")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn retry_policy() {
+        use std::os::unix::process::ExitStatusExt;
+
+        // Exit codes are in the high byte of the raw wait status
+        let exited = |code: i32| ExitStatus::from_raw(code << 8);
+        let policy = RetryPolicy {
+            retries: 2,
+            exit_codes: Some(vec![75]),
+        };
+
+        assert!(policy.should_retry(exited(75), 1));
+        assert!(policy.should_retry(exited(75), 2));
+        assert!(!policy.should_retry(exited(75), 3));
+        assert!(!policy.should_retry(exited(1), 1));
+        assert!(!policy.should_retry(exited(0), 1));
+
+        let any_failure = RetryPolicy {
+            retries: 1,
+            exit_codes: None,
+        };
+        assert!(any_failure.should_retry(exited(1), 1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn open_file_limit() {
+        let command = || {
+            let mut command = Command::new("bash");
+            command
+                .arg("-c")
+                .arg("for i in $(seq 64); do exec {fd}</dev/null || exit 3; done");
+            command
+        };
+
+        assert!(command().status().unwrap().success());
+
+        let mut limited = command();
+        limit_open_files(&mut limited, 16).unwrap();
+        assert!(!limited.status().unwrap().success());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resource_usage() {
+        let mut command = Command::new("bash");
+        command
+            .arg("-c")
+            .arg("i=0; while [ $i -lt 200000 ]; do i=$((i+1)); done");
+
+        let (status, usage) = wait(command.spawn().unwrap()).unwrap();
+        let usage = usage.expect("Usage recorded on Unix");
+        assert!(status.success());
+        assert!(usage.user_cpu + usage.system_cpu > Duration::ZERO);
+        assert!(usage.max_rss_bytes > 0);
+
+        // Recorded for scripts spawned as normal too
+        let before = SCRIPT_USAGE.cpu();
+        rocket::tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(spawn(command).unwrap())
+            .unwrap()
+            .unwrap();
+        assert!(SCRIPT_USAGE.cpu() > before);
+        assert!(crate::metrics::metrics().contains("webhook_script_cpu_seconds_total"));
+    }
+}
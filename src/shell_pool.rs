@@ -0,0 +1,504 @@
+//! Running scripts in a pool of long-lived shells, rather than starting a new shell for each.
+
+use std::{
+    env,
+    ffi::OsStr,
+    io::{self, BufRead, BufReader, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, ExitStatus, Stdio},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex, OnceLock, PoisonError,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rocket::tokio::sync::oneshot;
+
+use crate::{
+    metrics::RunningScript,
+    script::{self, Finished, RetryPolicy},
+};
+
+/// The string for the environment variable containing the number of shells to keep running
+/// scripts in.
+pub const POOL_SIZE_STRING: &str = "WEBHOOK_SHELL_POOL_SIZE";
+/// The string for the environment variable containing how scripts are handled when the queue of
+/// scripts waiting for a free shell is full, either `reject` or `oldest`.
+pub const QUEUE_SHED_STRING: &str = "WEBHOOK_QUEUE_SHED";
+
+/// The pool configured in the environment, which lives for as long as the handler.
+static POOL: OnceLock<Option<Arc<ShellPool>>> = OnceLock::new();
+
+/// The pool configured in the environment, if any.
+///
+/// The size is only read the first time this is called, as the shells outlive any one delivery.
+pub fn from_env() -> Option<Arc<ShellPool>> {
+    POOL.get_or_init(|| {
+        let size = env::var(POOL_SIZE_STRING).ok()?;
+        match size.parse() {
+            Ok(0) => None,
+            Ok(size) => Some(Arc::new(ShellPool::new(size, MAX_QUEUED))),
+            Err(err) => {
+                tracing::warn!("Invalid value for {POOL_SIZE_STRING}, ignoring it: {err}");
+                None
+            }
+        }
+    })
+    .clone()
+}
+
+/// The most scripts that may be waiting for a free shell at once.
+const MAX_QUEUED: usize = 1000;
+
+/// Long-lived shells that scripts are run in, one at a time each.
+///
+/// Each shell has a worker thread of its own, taking the scripts to run from a bounded queue.
+/// Each script is run in a subshell, so any variables, functions or working directory it changes
+/// are thrown away once it exits. Shells are started as they are first needed, and replaced if
+/// they stop responding.
+pub struct ShellPool {
+    queue: SyncSender<Job>,
+    /// The scripts waiting for a free shell, which the oldest are taken from to be shed.
+    jobs: Arc<Mutex<Receiver<Job>>>,
+    /// The most scripts that may wait in the queue.
+    queued: usize,
+}
+
+/// A script waiting for a free shell, along with where to send its result.
+struct Job {
+    command: Command,
+    open_files: Option<u64>,
+    policy: RetryPolicy,
+    finished: oneshot::Sender<io::Result<ExitStatus>>,
+}
+
+/// The error when the queue of scripts waiting for a free shell is full.
+#[derive(thiserror::Error, Debug)]
+#[error("{0} scripts are already waiting for a free shell")]
+pub struct QueueFull(usize);
+
+impl ShellPool {
+    /// A pool of `size` shells, with room for `queued` scripts to wait for one to be free.
+    pub fn new(size: usize, queued: usize) -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let marker: Arc<str> =
+            format!("__webhook_handler_{}_{nanos}_exited__", std::process::id()).into();
+
+        let (queue, jobs) = mpsc::sync_channel(queued);
+        let jobs = Arc::new(Mutex::new(jobs));
+        for _ in 0..size {
+            let (jobs, marker) = (jobs.clone(), marker.clone());
+            thread::spawn(move || work(&jobs, &marker));
+        }
+        Self {
+            queue,
+            jobs,
+            queued,
+        }
+    }
+
+    /// Queues the `bash <script> [args]` command to run in a shell from the pool, running it
+    /// again for as long as the policy allows.
+    ///
+    /// The command's environment & working directory are applied within the subshell it runs in,
+    /// along with the limit on its open files, though how its stdio is configured is not.
+    pub fn spawn(
+        &self,
+        command: Command,
+        open_files: Option<u64>,
+        policy: RetryPolicy,
+    ) -> Result<Finished, QueueFull> {
+        let (sender, receiver) = oneshot::channel();
+        let job = Job {
+            command,
+            open_files,
+            policy,
+            finished: sender,
+        };
+
+        let job = match self.queue.try_send(job) {
+            Ok(()) => return Ok(receiver),
+            Err(TrySendError::Full(job)) => job,
+            // The receiver is dropped along with the job, so the script is reported as never run
+            Err(TrySendError::Disconnected(_)) => return Ok(receiver),
+        };
+
+        match env::var(QUEUE_SHED_STRING).as_deref() {
+            Err(_) | Ok("reject") => return Err(QueueFull(self.queued)),
+            Ok("oldest") => {}
+            Ok(mode) => {
+                tracing::warn!("Unknown queue shedding \"{mode}\", using \"reject\"");
+                return Err(QueueFull(self.queued));
+            }
+        }
+
+        // Makes room for the newest script, which is likely the freshest deploy
+        let oldest = self
+            .jobs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .try_recv();
+        if let Ok(oldest) = oldest {
+            tracing::warn!("Shedding the oldest script waiting for a free shell");
+            let shed = io::Error::other("Shed to make room for a newer script");
+            let _ = oldest.finished.send(Err(shed));
+        }
+
+        match self.queue.try_send(job) {
+            Ok(()) | Err(TrySendError::Disconnected(_)) => Ok(receiver),
+            Err(TrySendError::Full(_)) => Err(QueueFull(self.queued)),
+        }
+    }
+}
+
+/// Runs the scripts queued for the pool one at a time, in a shell of the worker's own.
+fn work(jobs: &Mutex<Receiver<Job>>, marker: &str) {
+    let mut shell = None;
+    loop {
+        // Held only while waiting, so the other workers can take jobs while this one runs
+        let job = jobs.lock().unwrap_or_else(PoisonError::into_inner).recv();
+        let Ok(job) = job else {
+            return;
+        };
+
+        let line = command_line(&job.command, job.open_files, marker);
+        let mut run = || run_in(&mut shell, &line, marker);
+        let result = script::retry(&job.policy, run(), run);
+        // The receiver is dropped if nobody is interested in the result
+        let _ = job.finished.send(result);
+    }
+}
+
+/// Runs the command line in the shell, starting one if there isn't one yet.
+fn run_in(shell: &mut Option<Shell>, line: &str, marker: &str) -> io::Result<ExitStatus> {
+    let running = match shell {
+        Some(running) => running,
+        None => shell.insert(Shell::start()?),
+    };
+
+    let _running = RunningScript::start();
+    let result = running.run(line, marker);
+    // Shells that stopped responding are replaced by the next script
+    if result.is_err() {
+        *shell = None;
+    }
+    result
+}
+
+/// A shell reading the scripts to run from its stdin.
+struct Shell {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Shell {
+    fn start() -> io::Result<Self> {
+        let mut child = Command::new("bash")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(io::Error::other("Shell stdio was not piped"));
+        };
+
+        Ok(Self {
+            child,
+            stdin,
+            stdout: BufReader::new(stdout),
+        })
+    }
+
+    /// Runs the command line, passing on the script's output until the marker reports its exit
+    /// code.
+    fn run(&mut self, line: &str, marker: &str) -> io::Result<ExitStatus> {
+        writeln!(self.stdin, "{line}")?;
+        self.stdin.flush()?;
+
+        let mut stdout = io::stdout();
+        // Read as bytes, as scripts may output anything & it's passed on untouched
+        let mut output = Vec::new();
+        loop {
+            output.clear();
+            if self.stdout.read_until(b'\n', &mut output)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Shell exited while running the script",
+                ));
+            }
+
+            // Scripts whose output doesn't end in a newline leave the marker mid line
+            let found = output
+                .windows(marker.len())
+                .position(|window| window == marker.as_bytes());
+            match found {
+                Some(start) => {
+                    let _ = stdout.write_all(&output[..start]);
+                    let code = String::from_utf8_lossy(&output[start + marker.len()..])
+                        .trim()
+                        .parse()
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                    return Ok(exit_status(code));
+                }
+                None => {
+                    let _ = stdout.write_all(&output);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Shell {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// The line a shell runs the command with, sourcing the script within a subshell & then printing
+/// the marker followed by its exit code.
+fn command_line(command: &Command, open_files: Option<u64>, marker: &str) -> String {
+    let mut setup = Vec::new();
+    // Sets both the soft & hard limits, so the script can't raise it again
+    if let Some(limit) = open_files {
+        setup.push(format!("ulimit -n {limit} || exit"));
+    }
+    if let Some(dir) = command.get_current_dir() {
+        setup.push(format!("cd {} || exit", quote(dir.as_os_str())));
+    }
+    for (key, value) in command.get_envs() {
+        setup.push(match value {
+            Some(value) => format!("export {}={}", quote(key), quote(value)),
+            None => format!("unset {}", quote(key)),
+        });
+    }
+
+    let script: Vec<_> = command.get_args().map(quote).collect();
+    setup.push(format!(". {}", script.join(" ")));
+
+    // The script mustn't read the following commands from the shell's stdin
+    format!(
+        "( {} ) </dev/null; printf '%s%d\\n' {} \"$?\"",
+        setup.join("; "),
+        quote(OsStr::new(marker))
+    )
+}
+
+/// Quotes the value for bash, so that it is never expanded.
+fn quote(value: &OsStr) -> String {
+    format!("'{}'", value.to_string_lossy().replace('\'', r"'\''"))
+}
+
+/// The exit status of a process that exited with the code.
+#[cfg(unix)]
+fn exit_status(code: i32) -> ExitStatus {
+    std::os::unix::process::ExitStatusExt::from_raw(code << 8)
+}
+
+/// The exit status of a process that exited with the code.
+#[cfg(windows)]
+fn exit_status(code: i32) -> ExitStatus {
+    std::os::windows::process::ExitStatusExt::from_raw(code as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `bash <script>` with the environment variable from within the dir.
+    fn run(pool: &Arc<ShellPool>, dir: &std::path::Path, script: &str) -> ExitStatus {
+        run_limited(pool, dir, script, None)
+    }
+
+    /// Runs `bash <script>` like [`run`], limited to the number of open files.
+    fn run_limited(
+        pool: &Arc<ShellPool>,
+        dir: &std::path::Path,
+        script: &str,
+        open_files: Option<u64>,
+    ) -> ExitStatus {
+        let path = dir.join("test.sh");
+        std::fs::write(&path, script).expect("Able to write test script");
+
+        let mut command = Command::new("bash");
+        command
+            .arg(path)
+            .current_dir(dir)
+            .env("DELIVERY_VAR", "it's quoted");
+
+        rocket::tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(
+                pool.spawn(command, open_files, RetryPolicy::default())
+                    .unwrap(),
+            )
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn shells_reused() {
+        let temp_dir = tempdir::TempDir::new("webhook_handler-temp").unwrap();
+        let dir = temp_dir.path();
+        let pool = Arc::new(ShellPool::new(1, MAX_QUEUED));
+
+        // $$ is the pid of the shell the subshell was started from
+        let status = run(
+            &pool,
+            dir,
+            "echo $$ > first.temp; echo \"$DELIVERY_VAR\" > var.temp",
+        );
+        assert!(status.success());
+        let status = run(&pool, dir, "echo $$ > second.temp; exit 3");
+        assert_eq!(status.code(), Some(3));
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("var.temp").trim(), "it's quoted");
+        assert_eq!(read("first.temp"), read("second.temp"));
+    }
+
+    #[test]
+    fn invalid_utf8_output() {
+        let temp_dir = tempdir::TempDir::new("webhook_handler-temp").unwrap();
+        let dir = temp_dir.path();
+        let pool = Arc::new(ShellPool::new(1, MAX_QUEUED));
+
+        let status = run(
+            &pool,
+            dir,
+            "echo $$ > first.temp; printf 'binary \\xff\\xfe\\n'; exit 4",
+        );
+        assert_eq!(status.code(), Some(4));
+        // The shell is still in step with the scripts it's running, so isn't replaced
+        assert!(run(&pool, dir, "echo $$ > second.temp").success());
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("first.temp"), read("second.temp"));
+    }
+
+    #[test]
+    fn runs_isolated() {
+        let temp_dir = tempdir::TempDir::new("webhook_handler-temp").unwrap();
+        let dir = temp_dir.path();
+        let pool = Arc::new(ShellPool::new(1, MAX_QUEUED));
+
+        let status = run(
+            &pool,
+            dir,
+            "export LEAKED=1; UNEXPORTED=1; leaked() { :; }; cd /; printf 'no newline'",
+        );
+        assert!(status.success());
+
+        let status = run(
+            &pool,
+            dir,
+            "echo \"${LEAKED:-unset} ${UNEXPORTED:-unset} $(type -t leaked || echo unset) \
+             $(pwd)\" > state.temp",
+        );
+        assert!(status.success());
+
+        let state = std::fs::read_to_string(dir.join("state.temp")).unwrap();
+        assert_eq!(
+            state.trim(),
+            format!("unset unset unset {}", dir.to_str().unwrap())
+        );
+    }
+
+    #[test]
+    fn open_files_limited() {
+        let temp_dir = tempdir::TempDir::new("webhook_handler-temp").unwrap();
+        let dir = temp_dir.path();
+        let pool = Arc::new(ShellPool::new(1, MAX_QUEUED));
+
+        let status = run_limited(&pool, dir, "ulimit -n > limited.temp", Some(64));
+        assert!(status.success());
+        let status = run(&pool, dir, "ulimit -n > unlimited.temp");
+        assert!(status.success());
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("limited.temp").trim(), "64");
+        // The limit only applies within the script's subshell
+        assert_ne!(read("unlimited.temp").trim(), "64");
+    }
+
+    #[test]
+    fn queue_bounded() {
+        let temp_dir = tempdir::TempDir::new("webhook_handler-temp").unwrap();
+        let dir = temp_dir.path();
+        let pool = ShellPool::new(1, 1);
+        let script = |name: &str, body: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, body).expect("Able to write test script");
+            let mut command = Command::new("bash");
+            command.arg(path).current_dir(dir);
+            command
+        };
+        let spawn = |command| pool.spawn(command, None, RetryPolicy::default());
+
+        // The only shell is kept busy until the test is done queueing
+        let busy = spawn(script(
+            "busy.sh",
+            "touch started.temp; while [ ! -e release.temp ]; do sleep 0.02; done",
+        ))
+        .unwrap();
+        for _ in 0..250 {
+            if dir.join("started.temp").exists() {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(20));
+        }
+        let queued = spawn(script("queued.sh", "exit 0")).unwrap();
+        assert!(matches!(
+            spawn(script("rejected.sh", "exit 0")),
+            Err(QueueFull(1))
+        ));
+
+        std::fs::write(dir.join("release.temp"), "").unwrap();
+        assert!(busy.blocking_recv().unwrap().unwrap().success());
+        assert!(queued.blocking_recv().unwrap().unwrap().success());
+    }
+
+    #[test]
+    fn queue_sheds_oldest() {
+        let temp_dir = tempdir::TempDir::new("webhook_handler-temp").unwrap();
+        let dir = temp_dir.path();
+        let pool = ShellPool::new(1, 2);
+        let script = |name: &str, body: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, body).expect("Able to write test script");
+            let mut command = Command::new("bash");
+            command.arg(path).current_dir(dir);
+            command
+        };
+        let spawn = |command| pool.spawn(command, None, RetryPolicy::default());
+
+        temp_env::with_var(QUEUE_SHED_STRING, Some("oldest"), || {
+            // The only shell is kept busy until the test is done queueing
+            let busy = spawn(script(
+                "busy.sh",
+                "touch started.temp; while [ ! -e release.temp ]; do sleep 0.02; done",
+            ))
+            .unwrap();
+            for _ in 0..250 {
+                if dir.join("started.temp").exists() {
+                    break;
+                }
+                thread::sleep(std::time::Duration::from_millis(20));
+            }
+            let earliest = spawn(script("earliest.sh", "touch earliest.temp")).unwrap();
+            let second = spawn(script("second.sh", "touch second.temp")).unwrap();
+            let newest = spawn(script("newest.sh", "touch newest.temp")).unwrap();
+
+            std::fs::write(dir.join("release.temp"), "").unwrap();
+            assert!(busy.blocking_recv().unwrap().unwrap().success());
+            assert!(earliest.blocking_recv().unwrap().is_err());
+            assert!(second.blocking_recv().unwrap().unwrap().success());
+            assert!(newest.blocking_recv().unwrap().unwrap().success());
+            assert!(!dir.join("earliest.temp").exists());
+        });
+    }
+}
@@ -0,0 +1,124 @@
+//! Pushing delivery metrics to a StatsD server.
+
+use std::{
+    env,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use rocket::http::Status;
+
+/// The string for the environment variable containing the address of the StatsD server.
+pub const STATSD_ADDR_STRING: &str = "WEBHOOK_STATSD_ADDR";
+/// The string for the environment variable containing the prefix of the metric names.
+pub const STATSD_PREFIX_STRING: &str = "WEBHOOK_STATSD_PREFIX";
+
+/// The prefix of the metric names unless another is configured.
+const DEFAULT_PREFIX: &str = "webhook";
+
+/// The address last resolved for the StatsD server, along with the address it was resolved from.
+static RESOLVED: Mutex<Option<Resolved>> = Mutex::new(None);
+
+/// A resolved StatsD server, with the socket metrics are sent to it from.
+struct Resolved {
+    from: String,
+    target: SocketAddr,
+    socket: Arc<UdpSocket>,
+}
+
+/// The StatsD lines describing a delivery that was responded to with the status after the elapsed
+/// time.
+fn lines(prefix: &str, status: Status, elapsed: Duration) -> String {
+    format!(
+        "{prefix}.deliveries:1|c\n{prefix}.responses.{}:1|c\n{prefix}.delivery_time:{}|ms",
+        status.code,
+        elapsed.as_millis()
+    )
+}
+
+/// Sends the metrics for the delivery to the StatsD server configured in the environment, if any.
+pub fn record(status: Status, elapsed: Duration) {
+    send(|prefix| lines(prefix, status, elapsed));
+}
+
+/// Counts a delivery that looked like abuse of the handler, of the given kind.
+pub fn abuse(kind: &str) {
+    send(|prefix| format!("{prefix}.abuse.{kind}:1|c"));
+}
+
+/// Sends the lines built from the metric prefix to the StatsD server configured in the
+/// environment, if any.
+///
+/// Metrics are best effort, so failures are only logged.
+fn send(lines: impl FnOnce(&str) -> String) {
+    let Ok(addr) = env::var(STATSD_ADDR_STRING) else {
+        return;
+    };
+    let prefix = env::var(STATSD_PREFIX_STRING).unwrap_or_else(|_| DEFAULT_PREFIX.to_owned());
+
+    let sent = resolve(&addr)
+        .and_then(|(socket, target)| socket.send_to(lines(&prefix).as_bytes(), target));
+
+    if let Err(err) = sent {
+        tracing::warn!("Could not send metrics to StatsD at \"{addr}\": {err}");
+    }
+}
+
+/// Resolves the address of the StatsD server, along with the socket to send to it from.
+///
+/// Resolving can block on DNS, so is only done again when the configured address changes. The
+/// socket is bound along with it, rather than for every metric.
+fn resolve(addr: &str) -> std::io::Result<(Arc<UdpSocket>, SocketAddr)> {
+    if let Some(resolved) = RESOLVED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+    {
+        if resolved.from == addr {
+            return Ok((resolved.socket.clone(), resolved.target));
+        }
+    }
+
+    // Not held while resolving, so a slow lookup doesn't hold up anything else
+    let target = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::other("address did not resolve"))?;
+    let local: SocketAddr = match target {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = Arc::new(UdpSocket::bind(local)?);
+
+    *RESOLVED.lock().unwrap_or_else(PoisonError::into_inner) = Some(Resolved {
+        from: addr.to_owned(),
+        target,
+        socket: socket.clone(),
+    });
+    Ok((socket, target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_reused() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+
+        temp_env::with_var(STATSD_ADDR_STRING, Some(&addr), || {
+            abuse("first");
+            abuse("second");
+        });
+
+        let mut buf = [0; 64];
+        let (_, first) = server.recv_from(&mut buf).unwrap();
+        let (_, second) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,144 @@
+//! Serving several tenants from one handler, each delivering to `/t/<tenant>/hook`.
+
+use std::{
+    collections::HashMap,
+    env,
+    path::PathBuf,
+    sync::{Mutex, PoisonError},
+    time::Instant,
+};
+
+use rocket::{
+    http::{Method, Status},
+    request::{FromRequest, Outcome},
+    Request,
+};
+
+use crate::parse_pairs;
+
+/// The string for the environment variable containing comma separated `tenant=script` pairs, of
+/// the tenants served & the script run for each.
+pub const TENANT_SCRIPTS_STRING: &str = "WEBHOOK_TENANT_SCRIPTS";
+/// The string for the environment variable containing comma separated `tenant=secret` pairs, of
+/// the secret each tenant's deliveries are signed with.
+pub const TENANT_SECRETS_STRING: &str = "WEBHOOK_TENANT_SECRETS";
+/// The string for the environment variable containing the deliveries each tenant may send a
+/// minute, so one busy tenant doesn't hold up the others.
+pub const TENANT_RATE_LIMIT_STRING: &str = "WEBHOOK_TENANT_RATE_LIMIT";
+/// The environment variable the script is passed the name of the tenant in.
+pub const TENANT_ENV: &str = "WEBHOOK_TENANT";
+
+/// A tenant that a delivery was sent for.
+#[derive(Clone, Debug)]
+pub struct Tenant {
+    pub name: String,
+    pub script: PathBuf,
+}
+
+/// Rewrites deliveries to `/t/<tenant>/hook` to the root, remembering the tenant they were for.
+///
+/// Deliveries for tenants that aren't configured are left alone, so are never routed.
+pub fn route(request: &mut Request<'_>) {
+    if request.method() != Method::Post {
+        return;
+    }
+    let Some(name) = request
+        .uri()
+        .path()
+        .as_str()
+        .strip_prefix("/t/")
+        .and_then(|path| path.strip_suffix("/hook"))
+        .filter(|name| !name.is_empty() && !name.contains('/'))
+    else {
+        return;
+    };
+    let Ok(scripts) = env::var(TENANT_SCRIPTS_STRING) else {
+        return;
+    };
+
+    let script = match parse_pairs(&scripts) {
+        Ok(pairs) => pairs
+            .into_iter()
+            .find(|(tenant, _)| *tenant == name)
+            .map(|(_, script)| PathBuf::from(script)),
+        Err(err) => {
+            tracing::error!("Invalid {TENANT_SCRIPTS_STRING}: {err}");
+            None
+        }
+    };
+    let Some(script) = script else {
+        tracing::warn!("Delivery sent for unknown tenant \"{name}\"");
+        return;
+    };
+
+    let tenant = Tenant {
+        name: name.to_owned(),
+        script,
+    };
+    request.local_cache(|| Some(tenant));
+    crate::route_to_root(request);
+}
+
+/// The tenant the request was routed for, if any.
+pub fn of<'r>(request: &'r Request<'_>) -> Option<&'r Tenant> {
+    request.local_cache(|| None::<Tenant>).as_ref()
+}
+
+/// The deliveries each tenant has left, as token buckets refilled at the tenant's rate limit.
+#[derive(Default)]
+pub struct RateLimits {
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl RateLimits {
+    /// Takes a delivery from the tenant's bucket, or returns false if it is empty.
+    ///
+    /// Buckets hold up to a minute's worth of deliveries, starting out full.
+    pub fn take(&self, tenant: &str, per_minute: f64) -> bool {
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        let (tokens, refilled) = buckets
+            .entry(tenant.to_owned())
+            .or_insert((per_minute, now));
+
+        let elapsed = now.duration_since(*refilled).as_secs_f64();
+        *tokens = (*tokens + elapsed * per_minute / 60.0).min(per_minute);
+        *refilled = now;
+
+        match *tokens >= 1.0 {
+            true => {
+                *tokens -= 1.0;
+                true
+            }
+            false => false,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r Tenant {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        match of(request) {
+            Some(tenant) => Outcome::Success(tenant),
+            None => Outcome::Forward(Status::NotFound),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limits_per_tenant() {
+        let limits = RateLimits::default();
+        assert!(limits.take("acme", 2.0));
+        assert!(limits.take("acme", 2.0));
+        assert!(!limits.take("acme", 2.0));
+
+        // Other tenants have buckets of their own
+        assert!(limits.take("globex", 2.0));
+    }
+}